@@ -1,6 +1,12 @@
-use egui::{Color32, Event, Frame, PointerButton, Pos2, Sense, TextureOptions, Vec2};
+use std::time::Duration;
+
+use egui::{Color32, Event, Frame, Key, Mesh, PointerButton, Pos2, Sense, Shape, Stroke, StrokeKind, Vec2};
 
 use super::AppComponentExt;
+use crate::app::components::utils::draw_tool::Pencil;
+use crate::app::components::utils::selection::SelectionMode;
+use crate::app::components::utils::shape::{constrain_end, shape_path};
+use crate::app::components::utils::transform::{TransformHandle, ROTATE_HANDLE_OFFSET};
 use crate::app::App;
 pub struct Canvas;
 
@@ -46,42 +52,118 @@ impl AppComponentExt for Canvas {
                 Pos2::new(raw_canvas_rect.min.x.max(canva_container_response.rect.min.x), raw_canvas_rect.min.y.max(canva_container_response.rect.min.y)),  
                 Pos2::new(raw_canvas_rect.max.x.min(canva_container_response.rect.max.x), raw_canvas_rect.max.y.min(canva_container_response.rect.max.y))
             );
-            let clamped_canvas_uv = egui::Rect::from_min_max(
-                Pos2::new(
-                    ((clamped_canvas_rect.min.x - raw_canvas_rect.min.x) / raw_canvas_rect.width()).max(0.), 
-                    ((clamped_canvas_rect.min.y - raw_canvas_rect.min.y) / raw_canvas_rect.height()).max(0.)
-                ), 
-                Pos2::new(
-                    ((clamped_canvas_rect.max.x - raw_canvas_rect.min.x) / raw_canvas_rect.width()).min(1.), 
-                    ((clamped_canvas_rect.max.y - raw_canvas_rect.min.y) / raw_canvas_rect.height()).min(1.)
-                )
-            );
             // canvas_container_painter.
             let clamped_canva_sense = ui.allocate_rect(clamped_canvas_rect, Sense::click_and_drag());
             canvas_container_painter.rect_filled(clamped_canvas_rect,0.0, Color32::from_rgb(200, 200, 200));
             
-            if  clamped_canva_sense.drag_started_by(PointerButton::Primary) {
-                ctx.app_state.is_dragging = true;
-            }
-           
-            if clamped_canva_sense.clicked_by(PointerButton::Primary) || (clamped_canva_sense.dragged_by(PointerButton::Primary) &&  ctx.app_state.is_dragging) {
-             
-                let pos = (cursor.get_pos() - raw_canvas_rect.min.to_vec2()) / ctx.app_state.layers_container.transform.scale;
-                let brush_size = ctx.app_state.current_stroke_width;
-                let color = ctx.app_state.current_color.clone().unwrap_or_default().color;
-                if let Some(layer_id) = ctx.app_state.current_layer {
-                    if let Some(layer) = ctx.app_state.layers_container.layers.iter_mut().find(|layer| layer.id == layer_id) {
-                        layer.texture.paint_at(
-                            pos, 
-                            ctx.app_state.current_draw_tool.clone().unwrap().pencil, 
-                            brush_size, 
-                            color
-                        );
+            let layer_size = [ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize];
+            let scale = ctx.app_state.layers_container.transform.scale;
+            let pencil = ctx.app_state.current_draw_tool.as_ref().map(|tool| tool.pencil);
+            let pointer_pos = (cursor.get_pos() - raw_canvas_rect.min.to_vec2()) / scale;
+            // Shift picks the selection mode for selection tools instead
+            let constrain = ui.input(|i| i.modifiers.shift) && pencil.is_some_and(|pencil| pencil.is_shape());
+            let shape_end = pencil.map(|pencil| constrain_end(pencil, &ctx.app_state.shape_anchors, pointer_pos, constrain)).unwrap_or(pointer_pos);
+            // Alt turns painting tools into the eyedropper, unless a stroke is already under way
+            let is_alt_picking = !ctx.app_state.is_dragging && ui.input(|i| i.modifiers.alt) && pencil.is_some_and(|pencil| pencil.picks_with_alt());
+            if pencil == Some(Pencil::Eyedropper) || is_alt_picking {
+                let settings = ctx.app_settings.eyedropper_settings;
+                let is_released = clamped_canva_sense.clicked_by(PointerButton::Primary) || clamped_canva_sense.drag_stopped_by(PointerButton::Primary);
+                if is_released || clamped_canva_sense.dragged_by(PointerButton::Primary) {
+                    // Only the color the pointer is released on joins the palette, not every one dragged over
+                    if let Some(color) = ctx.app_state.pick_color(pointer_pos, &settings, is_released && settings.add_to_palette, layer_size) {
+                        ctx.app_settings.color_picker.set_color(color);
                     }
                 }
-            }
-            if clamped_canva_sense.drag_stopped_by(PointerButton::Primary) {
-                ctx.app_state.is_dragging = false;
+            } else if pencil == Some(Pencil::Fill) {
+                if clamped_canva_sense.clicked_by(PointerButton::Primary) {
+                    ctx.app_state.fill_at(pointer_pos, &ctx.app_settings.fill_settings, layer_size);
+                }
+            } else if pencil == Some(Pencil::MagicWand) {
+                if clamped_canva_sense.clicked_by(PointerButton::Primary) {
+                    let mode = SelectionMode::from_modifiers(ui.input(|i| i.modifiers), ctx.app_settings.selection_settings.mode);
+                    ctx.app_state.magic_wand(pointer_pos, &ctx.app_settings.selection_settings, mode, layer_size);
+                }
+            } else if pencil == Some(Pencil::Move) {
+                let settings = ctx.app_settings.transform_settings;
+                if ui.input(|i| i.key_pressed(Key::Enter)) {
+                    ctx.app_state.apply_transform(settings.interpolation, layer_size);
+                } else if ui.input(|i| i.key_pressed(Key::Escape)) {
+                    ctx.app_state.cancel_transform();
+                }
+                if clamped_canva_sense.drag_started_by(PointerButton::Primary) {
+                    let origin = ui.input(|i| i.pointer.press_origin()).unwrap_or(cursor.get_pos());
+                    let origin = (origin - raw_canvas_rect.min.to_vec2()) / scale;
+                    // A drag with nothing floating yet lifts the content and moves it, wherever it starts
+                    let is_new = ctx.app_state.transform.is_none();
+                    if ctx.app_state.begin_transform(settings.target) && let Some(transform) = &mut ctx.app_state.transform {
+                        let handle = if is_new { TransformHandle::Move } else { transform.handle_at(origin, scale, ui.input(|i| i.modifiers.command)) };
+                        transform.begin_drag(handle, origin);
+                    }
+                }
+                if let Some(transform) = &mut ctx.app_state.transform {
+                    // Rotating often takes the pointer off the canvas, so follow it there too
+                    if clamped_canva_sense.dragged_by(PointerButton::Primary) && let Some(pos) = clamped_canva_sense.interact_pointer_pos() {
+                        transform.drag_to((pos - raw_canvas_rect.min.to_vec2()) / scale, ui.input(|i| i.modifiers.shift));
+                    }
+                    if clamped_canva_sense.drag_stopped_by(PointerButton::Primary) {
+                        transform.end_drag();
+                    }
+                } else if clamped_canva_sense.clicked_by(PointerButton::Primary) {
+                    ctx.app_state.begin_transform(settings.target);
+                }
+            } else if let Some(pencil) = pencil && (pencil.is_shape() || pencil.is_selection()) {
+                let mode = SelectionMode::from_modifiers(ui.input(|i| i.modifiers), ctx.app_settings.selection_settings.mode);
+                if ui.input(|i| i.key_pressed(Key::Escape)) {
+                    ctx.app_state.shape_anchors.clear();
+                }
+                if pencil == Pencil::Polygon || pencil == Pencil::PolygonLasso {
+                    // Double-clicking, Enter or clicking the first point again closes the polygon
+                    let anchors = &ctx.app_state.shape_anchors;
+                    let is_on_first = anchors.first().is_some_and(|first| first.distance(pointer_pos) * scale < 8.);
+                    let is_closing = anchors.len() >= 3 && (
+                        clamped_canva_sense.double_clicked_by(PointerButton::Primary)
+                        || (clamped_canva_sense.clicked_by(PointerButton::Primary) && is_on_first)
+                        || ui.input(|i| i.key_pressed(Key::Enter))
+                    );
+                    if is_closing && let Some(last) = ctx.app_state.shape_anchors.pop() {
+                        ctx.app_state.commit_outline(pencil, last, &ctx.app_settings.shape_settings, &ctx.app_settings.selection_settings, mode, layer_size);
+                    } else if clamped_canva_sense.clicked_by(PointerButton::Primary) && !clamped_canva_sense.double_clicked() {
+                        ctx.app_state.shape_anchors.push(shape_end);
+                    }
+                } else {
+                    if clamped_canva_sense.drag_started_by(PointerButton::Primary) {
+                        let origin = ui.input(|i| i.pointer.press_origin()).unwrap_or(cursor.get_pos());
+                        ctx.app_state.shape_anchors = vec![(origin - raw_canvas_rect.min.to_vec2()) / scale];
+                    }
+                    // The lasso follows the pointer, skipping samples too close to be visible
+                    if pencil == Pencil::Lasso && clamped_canva_sense.dragged_by(PointerButton::Primary)
+                        && ctx.app_state.shape_anchors.last().is_some_and(|last| last.distance(pointer_pos) * scale >= 2.) {
+                        ctx.app_state.shape_anchors.push(pointer_pos);
+                    }
+                    if clamped_canva_sense.drag_stopped_by(PointerButton::Primary) && !ctx.app_state.shape_anchors.is_empty() {
+                        ctx.app_state.commit_outline(pencil, shape_end, &ctx.app_settings.shape_settings, &ctx.app_settings.selection_settings, mode, layer_size);
+                    }
+                    if pencil.is_selection() && mode == SelectionMode::Replace && clamped_canva_sense.clicked_by(PointerButton::Primary) {
+                        ctx.app_state.selection.deselect();
+                    }
+                }
+            } else {
+                if  clamped_canva_sense.drag_started_by(PointerButton::Primary) {
+                    ctx.app_state.is_dragging = true;
+                }
+
+                if clamped_canva_sense.clicked_by(PointerButton::Primary) || (clamped_canva_sense.dragged_by(PointerButton::Primary) &&  ctx.app_state.is_dragging) {
+
+                    let pos = (cursor.get_pos() - raw_canvas_rect.min.to_vec2()) / ctx.app_state.layers_container.transform.scale;
+                    ctx.app_state.stroke_to(pos, &ctx.app_settings.stroke_settings);
+                }
+                if clamped_canva_sense.clicked_by(PointerButton::Primary) {
+                    ctx.app_state.end_stroke(&ctx.app_settings.stroke_settings);
+                }
+                if clamped_canva_sense.drag_stopped_by(PointerButton::Primary) {
+                    ctx.app_state.is_dragging = false;
+                    ctx.app_state.end_stroke(&ctx.app_settings.stroke_settings);
+                }
             }
 
             if  clamped_canva_sense.drag_started_by(PointerButton::Secondary) {
@@ -115,26 +197,87 @@ impl AppComponentExt for Canvas {
                 }
                
             }
-            for layer in ctx.app_state.layers_container.layers.clone().iter_mut().filter(|layer| layer.is_visible).rev(){
-                let texture_handle = &layer.texture.texture_handle;
-                if texture_handle.is_none() {
+            ctx.app_settings.layer_composites.paint(
+                ui.ctx(),
+                &canvas_container_painter,
+                raw_canvas_rect,
+                &mut ctx.app_state.layers_container,
+                ctx.app_state.current_layer,
+                layer_size
+            );
 
-                    layer.texture.texture_handle = Some(ui.ctx().load_texture(layer.name.clone(), layer.texture.image_data.clone(), TextureOptions::LINEAR))
-                } 
-                canvas_container_painter.image(
-                    layer.texture.texture_handle.as_ref().unwrap().id(), 
-                    clamped_canvas_rect, 
-                    clamped_canvas_uv, 
-                    Color32::from_white_alpha(255)
-                );
-                
+            let to_screen = |point: Pos2| raw_canvas_rect.min + point.to_vec2() * scale;
+            // The transform box stands in for the selection while it is being moved
+            if ctx.app_state.selection.is_active() && ctx.app_state.transform.is_none() {
+                // Marching ants; the phase comes from the position so dashes line up across segments
+                let time = ui.input(|i| i.time) as f32;
+                let mut ants = Vec::new();
+                for [start, end] in ctx.app_state.selection.outline() {
+                    let (start, end) = (to_screen(*start), to_screen(*end));
+                    ants.push(Shape::line_segment([start, end], Stroke::new(1., Color32::BLACK)));
+                    let offset = (time * 8. + start.x + start.y).rem_euclid(8.);
+                    Shape::dashed_line_many_with_offset(&[start, end], Stroke::new(1., Color32::WHITE), &[4.], &[4.], offset, &mut ants);
+                }
+                canvas_container_painter.extend(ants);
+                ui.ctx().request_repaint_after(Duration::from_millis(100));
+            }
+
+            if let Some(transform) = &mut ctx.app_state.transform {
+                let corners = transform.corners().map(to_screen);
+                if let Some(texture_id) = transform.texture(ui.ctx()) {
+                    // Two triangles map the lifted pixels exactly, since the box stays a parallelogram
+                    let mut mesh = Mesh::with_texture(texture_id);
+                    let uvs = [Pos2::new(0., 0.), Pos2::new(1., 0.), Pos2::new(1., 1.), Pos2::new(0., 1.)];
+                    for (corner, uv) in corners.iter().zip(uvs) {
+                        mesh.vertices.push(egui::epaint::Vertex { pos: *corner, uv, color: Color32::WHITE });
+                    }
+                    mesh.add_triangle(0, 1, 2);
+                    mesh.add_triangle(0, 2, 3);
+                    canvas_container_painter.add(Shape::mesh(mesh));
+                }
+                let mut outline = corners.to_vec();
+                outline.push(corners[0]);
+                canvas_container_painter.add(Shape::line(outline.clone(), Stroke::new(1., Color32::BLACK)));
+                canvas_container_painter.extend(Shape::dashed_line(&outline, Stroke::new(1., Color32::WHITE), 4., 4.));
+                let (top, rotate_handle) = transform.rotate_handle(ROTATE_HANDLE_OFFSET / scale);
+                canvas_container_painter.line_segment([to_screen(top), to_screen(rotate_handle)], Stroke::new(1., Color32::BLACK));
+                canvas_container_painter.circle(to_screen(rotate_handle), 4., Color32::WHITE, Stroke::new(1., Color32::BLACK));
+                for (_, handle) in transform.handles() {
+                    let rect = egui::Rect::from_center_size(to_screen(handle), Vec2::splat(7.));
+                    canvas_container_painter.rect(rect, 0., Color32::WHITE, Stroke::new(1., Color32::BLACK), StrokeKind::Middle);
+                }
+            }
+
+            // Rubber band of the selection being drawn
+            if let Some(pencil) = pencil && pencil.is_selection() && !ctx.app_state.shape_anchors.is_empty() {
+                let (mut path, _) = shape_path(pencil.outline_shape(), &ctx.app_state.shape_anchors, shape_end);
+                path.extend(path.first().copied());
+                let points = path.into_iter().map(to_screen).collect::<Vec<Pos2>>();
+                canvas_container_painter.add(Shape::line(points.clone(), Stroke::new(1., Color32::BLACK)));
+                canvas_container_painter.extend(Shape::dashed_line(&points, Stroke::new(1., Color32::WHITE), 4., 4.));
+            }
+
+            // Rubber band of the shape being drawn, until it is committed to the layer
+            if let Some(pencil) = pencil && pencil.is_shape() && !ctx.app_state.shape_anchors.is_empty() {
+                let (path, closed) = shape_path(pencil, &ctx.app_state.shape_anchors, shape_end);
+                let points = path.into_iter().map(to_screen).collect::<Vec<Pos2>>();
+                let color = ctx.app_state.current_color.clone().unwrap_or_default().color;
+                let settings = &ctx.app_settings.shape_settings;
+                let preview = if settings.filled && closed && pencil != Pencil::Polygon {
+                    Shape::convex_polygon(points, color, Stroke::NONE)
+                } else {
+                    // Concave polygons can't be previewed filled, so they show their outline
+                    let stroke = Stroke::new(if settings.filled { 1. } else { settings.width * scale }, color);
+                    if closed { Shape::closed_line(points, stroke) } else { Shape::line(points, stroke) }
+                };
+                canvas_container_painter.add(preview);
             }
           
             if ctx.app_state.current_draw_tool.clone().is_some() && clamped_canva_sense.hovered(){
                 let pos = clamped_canva_sense.hover_pos().unwrap_or_default();
                 
                 cursor.settings.add_lock_event(ui);
-                cursor.set_radius(ctx.app_state.current_stroke_width * ctx.app_state.layers_container.transform.scale);
+                cursor.set_radius(ctx.app_state.current_brush.size * ctx.app_state.layers_container.transform.scale);
                 cursor.update_pos(pos);
                 if !ctx.app_state.layers_container.is_dragged {
                     cursor.ui(&canvas_container_painter)
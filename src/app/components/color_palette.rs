@@ -1,75 +1,253 @@
-use egui::{ Align2, Color32, CursorIcon, FontFamily, FontId, Frame, PointerButton, Pos2, Sense, Stroke, StrokeKind, Vec2};
+use std::ops::RangeInclusive;
+
+use egui::{ Align2, Color32, CursorIcon, FontFamily, FontId, Frame, Id, Key, PointerButton, Pos2, Sense, Stroke, StrokeKind, Vec2};
 
 use super::AppComponentExt;
-use crate::app::{components::utils::{layer::PaintColor, new_rand_id}, App};
+use crate::app::{components::utils::{color_space::to_hex, layer::PaintColor, palette::Palette, quantize::{QuantizeMethod, QuantizeSettings}}, App};
 
 pub struct ColorPalette;
 
+#[derive(Clone, PartialEq, Default)]
+pub struct PalettePanelState {
+    pub dragged_swatch: Option<usize>,
+    // Palette or swatch whose name is being edited
+    pub renaming: Option<Id>,
+    pub rename_text: String,
+    pub rename_focus_requested: bool
+}
 
+impl PalettePanelState {
+    fn start_renaming(&mut self, id: Id, name: &str) {
+        self.renaming = Some(id);
+        self.rename_text = name.to_string();
+        self.rename_focus_requested = true;
+    }
+}
+
+/// Method and color count for "Generate palette", shared by the palette panel and the import dialog.
+pub fn quantize_controls(ui: &mut egui::Ui, settings: &mut QuantizeSettings) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_salt(ui.id().with("quantize_method"))
+            .selected_text(settings.method.to_string())
+            .show_ui(ui, |ui| {
+                for method in QuantizeMethod::ALL {
+                    ui.selectable_value(&mut settings.method, method, method.to_string());
+                }
+            });
+        ui.add(egui::DragValue::new(&mut settings.colors).range(RangeInclusive::new(1, 64)).suffix(" colors"));
+    });
+}
 
 impl AppComponentExt for ColorPalette {
     type Context = App;
     fn add(ctx: &mut Self::Context, ui: &mut eframe::egui::Ui) {
-        let palette_size: Vec2 = Vec2::new(150., 390.);
+        let palette_size: Vec2 = Vec2::new(150., 340.);
         let padding: f32 = 2.5;
         let color_block_size: f32 = 20.;
+        let cell_size = color_block_size + padding * 2.;
         Frame::canvas(ui.style()).show(ui, |ui| {
+            ui.set_width(palette_size.x);
+            let panel = &mut ctx.app_settings.palette_panel;
+            let current_palette = ctx.app_state.current_palette;
+            let palette_name = ctx.app_state.palettes.iter().find(|p| p.id == current_palette).map(|p| p.name.clone()).unwrap_or_default();
+            let mut selected_palette = None;
+            let mut renamed = None;
+            if panel.renaming.is_some() {
+                let name_edit = ui.add(egui::TextEdit::singleline(&mut panel.rename_text).hint_text("Name").desired_width(palette_size.x));
+                if panel.rename_focus_requested {
+                    name_edit.request_focus();
+                    panel.rename_focus_requested = false;
+                }
+                if name_edit.lost_focus() {
+                    if !ui.input(|i| i.key_pressed(Key::Escape)) {
+                        renamed = panel.renaming.map(|id| (id, panel.rename_text.clone()));
+                    }
+                    panel.renaming = None;
+                }
+            } else {
+                egui::ComboBox::from_id_salt("color_palette_select")
+                    .width(palette_size.x)
+                    .selected_text(palette_name.as_str())
+                    .show_ui(ui, |ui| {
+                        for palette in ctx.app_state.palettes.iter() {
+                            if ui.selectable_label(palette.id == current_palette, palette.name.as_str()).clicked() {
+                                selected_palette = Some(palette.id);
+                            }
+                        }
+                    });
+            }
+            let mut new_palette = false;
+            let mut deleted_palette = false;
+            let mut import_palette = false;
+            let mut export_palette = false;
+            ui.horizontal(|ui| {
+                new_palette = ui.button(egui_phosphor::regular::PLUS).on_hover_text("New palette").clicked();
+                if ui.button(egui_phosphor::regular::PENCIL_SIMPLE).on_hover_text("Rename palette").clicked() {
+                    panel.start_renaming(current_palette, &palette_name);
+                }
+                let can_delete = ctx.app_state.palettes.len() > 1 && ctx.app_state.indexed_palette != Some(current_palette);
+                deleted_palette = ui.add_enabled(can_delete, egui::Button::new(egui_phosphor::regular::TRASH)).on_hover_text("Delete palette").clicked();
+                import_palette = ui.button(egui_phosphor::regular::DOWNLOAD_SIMPLE).on_hover_text("Import palette (.gpl, .ase, .hex)").clicked();
+                export_palette = ui.button(egui_phosphor::regular::EXPORT).on_hover_text("Export palette (.gpl, .ase, .hex)").clicked();
+            });
+            let mut generate_palette = false;
+            ui.menu_button(format!("{} Generate from canvas", egui_phosphor::regular::SPARKLE), |ui| {
+                quantize_controls(ui, &mut ctx.app_settings.quantize_settings);
+                if ui.button("Generate palette").clicked() {
+                    generate_palette = true;
+                    ui.close();
+                }
+            });
+            let indexed_palette = ctx.app_state.indexed_palette;
+            let mut is_indexed = indexed_palette.is_some();
+            // Locking always uses the palette on show, but another one may already hold the lock
+            let indexed_label = match indexed_palette.filter(|id| *id != current_palette).and_then(|id| ctx.app_state.palettes.iter().find(|p| p.id == id)) {
+                Some(palette) => format!("Indexed to {}", palette.name),
+                None => "Indexed color".to_string()
+            };
+            let can_index = is_indexed || !ctx.app_state.color_palette().is_empty();
+            let indexed_toggled = ui.add_enabled(can_index, egui::Checkbox::new(&mut is_indexed, indexed_label))
+                .on_hover_text("Keep every pixel a swatch of this palette. Editing a swatch recolors the pixels that use it.")
+                .on_disabled_hover_text("Add a swatch before locking the document to this palette")
+                .changed();
+
             let (palette_response, palette_painter) = ui.allocate_painter(palette_size, Sense::click());
             let palette = palette_response.rect;
             palette_painter.rect_filled(palette, 0., Color32::from_rgb(200, 200, 200));
-            let block_per_row = (palette_size.x / (color_block_size + padding * 2.)).floor();
-
-            for (color_idx, paint_color) in ctx.app_state.color_palette.iter_mut().enumerate() {
-
-                let row = (color_idx as f32 / block_per_row).floor();
-                let col = color_idx % block_per_row.floor()  as usize;
+            let block_per_row = (palette_size.x / cell_size).floor();
+            let block_rect = |idx: usize| {
+                let row = (idx as f32 / block_per_row).floor();
+                let col = idx % block_per_row as usize;
+                egui::Rect::from_min_size(
+                    Pos2::new(palette.min.x + cell_size * col as f32 + padding, palette.min.y + cell_size * row + padding),
+                    Vec2::splat(color_block_size)
+                )
+            };
 
-                let color_rect = egui::Rect::from_min_max(
-                    Pos2::new(palette.min.x + (color_block_size + padding * 2.) * col as f32 + padding, palette.min.y + (color_block_size + padding * 2.) * row  + padding),
-                    Pos2::new(palette.min.x + (color_block_size + padding * 2.) * col as f32 + padding + color_block_size, palette.min.y + (color_block_size + padding * 2.) * row + padding + color_block_size) 
-                );
+            let swatches = ctx.app_state.color_palette();
+            // An indexed palette keeps at least one swatch for the pixels to use
+            let can_delete_swatch = swatches.len() > 1 || indexed_palette != Some(current_palette);
+            let mut clicked_swatch = None;
+            let mut moved_swatch = None;
+            let mut deleted_swatch = None;
+            for (color_idx, paint_color) in swatches.iter().enumerate() {
+                let color_rect = block_rect(color_idx);
                 palette_painter.rect_filled(color_rect, 2., paint_color.color);
-                // let color_block = ui.al
-                let color_block_sense = ui.allocate_rect(color_rect, Sense::click());
-                if let Some(active_color) = ctx.app_state.current_color.as_ref() {
-                    if active_color.id == paint_color.id {
-                        palette_painter.rect_stroke(
-                            egui::Rect::from_min_max(color_rect.min - Vec2::new(2.5, 2.5), color_rect.max + Vec2::new(2.5, 2.5)), 
-                            0., 
-                            Stroke::new(2., Color32::BLACK), 
-                            StrokeKind::Middle
-                        );
-                    }
+                let label = if paint_color.name.is_empty() { to_hex(paint_color.color) } else { format!("{} {}", paint_color.name, to_hex(paint_color.color)) };
+                let color_block_sense = ui.allocate_rect(color_rect, Sense::click_and_drag()).on_hover_text(label);
+                if ctx.app_state.current_color.as_ref().is_some_and(|active_color| active_color.id == paint_color.id) {
+                    palette_painter.rect_stroke(
+                        egui::Rect::from_min_max(color_rect.min - Vec2::new(2.5, 2.5), color_rect.max + Vec2::new(2.5, 2.5)),
+                        0.,
+                        Stroke::new(2., Color32::BLACK),
+                        StrokeKind::Middle
+                    );
                 }
                 if color_block_sense.hovered() {
                     ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
                 }
                 if color_block_sense.clicked_by(PointerButton::Primary) {
-                    ctx.app_settings.color_picker.r_channel = paint_color.color.r() as f32;
-                    ctx.app_settings.color_picker.g_channel = paint_color.color.g() as f32;
-                    ctx.app_settings.color_picker.b_channel = paint_color.color.b() as f32;
-                    ctx.app_settings.color_picker.a_channel = paint_color.color.a() as f32;
-                    ctx.app_state.current_color = Some(paint_color.clone());
+                    clicked_swatch = Some(paint_color.clone());
+                }
+                if color_block_sense.double_clicked_by(PointerButton::Primary) {
+                    panel.start_renaming(paint_color.id, &paint_color.name);
                 }
+                if color_block_sense.drag_started_by(PointerButton::Primary) {
+                    panel.dragged_swatch = Some(color_idx);
+                }
+                color_block_sense.context_menu(|ui| {
+                    if ui.button("Rename swatch").clicked() {
+                        panel.start_renaming(paint_color.id, &paint_color.name);
+                        ui.close();
+                    }
+                    if ui.add_enabled(color_idx > 0, egui::Button::new("Move left")).clicked() {
+                        moved_swatch = Some((color_idx, color_idx - 1));
+                        ui.close();
+                    }
+                    if ui.add_enabled(color_idx + 1 < swatches.len(), egui::Button::new("Move right")).clicked() {
+                        moved_swatch = Some((color_idx, color_idx + 1));
+                        ui.close();
+                    }
+                    if ui.add_enabled(can_delete_swatch, egui::Button::new("Delete swatch")).clicked() {
+                        deleted_swatch = Some(paint_color.id);
+                        ui.close();
+                    }
+                });
+            }
 
+            // Drag and drop reordering: the dragged swatch takes the place of the one under the pointer
+            if let Some(from) = panel.dragged_swatch && !swatches.is_empty() {
+                ui.ctx().set_cursor_icon(CursorIcon::Grabbing);
+                let pointer = ui.ctx().pointer_interact_pos().unwrap_or(palette.min) - palette.min;
+                let col = (pointer.x / cell_size).floor().clamp(0., block_per_row - 1.) as usize;
+                let row = (pointer.y / cell_size).floor().max(0.) as usize;
+                let slot = (row * block_per_row as usize + col).min(swatches.len() - 1);
+                palette_painter.rect_stroke(block_rect(slot).expand(padding), 0., Stroke::new(2., Color32::WHITE), StrokeKind::Middle);
+                if ui.input(|i| i.pointer.any_released()) {
+                    panel.dragged_swatch = None;
+                    moved_swatch = Some((from, slot));
+                }
             }
-            let add_color_row = ((ctx.app_state.color_palette.len() ) as f32 / block_per_row).floor();
-            let add_color_col = (ctx.app_state.color_palette.len()) % block_per_row.floor() as usize;
-            
-            let add_color_rect = egui::Rect::from_min_max(
-                Pos2::new(palette.min.x + (color_block_size + padding * 2.) * add_color_col as f32 + padding, palette.min.y + (color_block_size + padding * 2.) * add_color_row + padding),
-                Pos2::new(palette.min.x + (color_block_size + padding * 2.) * add_color_col as f32 + padding + color_block_size, palette.min.y + (color_block_size + padding * 2.) * add_color_row + padding + color_block_size) 
-            );
+
+            let add_color_rect = block_rect(swatches.len());
             palette_painter.rect_filled(add_color_rect, 2., Color32::WHITE);
             palette_painter.text(add_color_rect.center(), Align2::CENTER_CENTER, "+", FontId::new(12., FontFamily::Proportional), Color32::BLACK);
-            let add_color_sense = ui.allocate_rect(add_color_rect, Sense::click());
+            let add_color_sense = ui.allocate_rect(add_color_rect, Sense::click()).on_hover_text("Add the picker color");
             if add_color_sense.clicked_by(PointerButton::Primary) {
-                
-                ctx.app_state.color_palette.push(PaintColor { color: Color32::BLACK, id: new_rand_id() });
-                
+                let paint_color = PaintColor { color: ctx.app_settings.color_picker.color(), ..Default::default() };
+                ctx.app_state.edit_palette(|palette| palette.push(paint_color.clone()));
+                ctx.app_state.current_color = Some(paint_color);
+            }
+
+            if let Some(paint_color) = clicked_swatch {
+                ctx.app_settings.color_picker.set_color(paint_color.color);
+                ctx.app_state.current_color = Some(paint_color);
+            }
+            if let Some((from, to)) = moved_swatch && from != to {
+                ctx.app_state.edit_palette(|palette| {
+                    if from < palette.len() && to < palette.len() {
+                        let paint_color = palette.remove(from);
+                        palette.insert(to, paint_color);
+                    }
+                });
+            }
+            if let Some(swatch_id) = deleted_swatch {
+                ctx.app_state.edit_palette(|palette| palette.retain(|c| c.id != swatch_id));
+            }
+            if let Some((id, name)) = renamed {
+                if ctx.app_state.palettes.iter().any(|p| p.id == id) {
+                    ctx.app_state.rename_palette(id, name);
+                } else {
+                    ctx.app_state.edit_palette(|palette| {
+                        if let Some(paint_color) = palette.iter_mut().find(|c| c.id == id) {
+                            paint_color.name = name.trim().to_string();
+                        }
+                    });
+                }
+            }
+            if let Some(palette_id) = selected_palette {
+                ctx.app_state.select_palette(palette_id);
+            }
+            if new_palette {
+                let palette = Palette::new(&format!("Palette {}", ctx.app_state.palettes.len() + 1), Vec::new());
+                ctx.app_settings.palette_panel.start_renaming(palette.id, &palette.name);
+                ctx.app_state.add_palette(palette);
+            }
+            if deleted_palette {
+                ctx.app_state.delete_palette(current_palette);
+            }
+            if import_palette && let Err(error) = ctx.import_palette() {
+                ctx.app_settings.notification_widget.error(format!("Failed to import palette: {error}"));
+            }
+            if generate_palette {
+                ctx.generate_palette_from_canvas();
+            }
+            if indexed_toggled {
+                ctx.app_state.set_indexed(is_indexed.then_some(current_palette));
+            }
+            if export_palette && let Err(error) = ctx.export_palette() {
+                ctx.app_settings.notification_widget.error(format!("Failed to export palette: {error}"));
             }
-   
-          
         });
     }
-}
\ No newline at end of file
+}
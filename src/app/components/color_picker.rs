@@ -1,208 +1,280 @@
-use egui::{Align2, Color32, CursorIcon, FontFamily, FontId, Frame, PointerButton, Pos2, Sense, Vec2};
+use std::fmt::Display;
+
+use egui::{Align2, Color32, CursorIcon, FontFamily, FontId, Frame, Mesh, PointerButton, Rect, Response, Sense, Stroke, StrokeKind, Ui, Vec2};
 use super::AppComponentExt;
+use crate::app::components::utils::color_space::{
+    hsl_to_hsv, hsv_to_hsl, hsv_to_rgb, oklch_to_rgb, parse_color, rgb_to_hsv, rgb_to_oklch, to_hex, MAX_OKLCH_CHROMA
+};
 use crate::app::App;
 
-#[derive(Clone, Copy, PartialEq)]
+const PICKER_WIDTH: f32 = 150.;
+const HUE_STRIP_WIDTH: f32 = 18.;
+const BAR_HEIGHT: f32 = 14.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorModel {
+    #[default]
+    Rgb,
+    Hsl,
+    Oklch
+}
+
+impl ColorModel {
+    pub const ALL: [ColorModel; 3] = [ColorModel::Rgb, ColorModel::Hsl, ColorModel::Oklch];
+}
+
+impl Display for ColorModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ColorModel::Rgb => "RGB",
+            ColorModel::Hsl => "HSL",
+            ColorModel::Oklch => "OKLCH"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct ColorPicker {
-    pub is_dragging: bool,
-    pub drag_pos: f32,
-    pub r_channel: f32,
-    pub g_channel: f32,
-    pub b_channel: f32,
-    pub a_channel: f32,
+    // Color being edited, kept as HSV so hue and saturation survive greys and black; all 0-1
+    pub hsv: [f32; 3],
+    pub alpha: f32,
+    // Which sliders show under the wheel
+    pub model: ColorModel,
+    // Contents of the hex / rgb() field, only overwritten while it isn't being edited
+    hex_text: String
 }
 
 impl Default for ColorPicker {
     fn default() -> Self {
         Self {
-            is_dragging: false,
-            drag_pos: 0.0,
-            r_channel:0.0,
-            g_channel: 0.0,
-            b_channel: 0.0,
-            a_channel: 255.0,
+            hsv: [0., 0., 0.],
+            alpha: 1.,
+            model: ColorModel::default(),
+            hex_text: String::new()
         }
     }
 }
 
-impl AppComponentExt for ColorPicker {
-    type Context = App;
-    fn add(ctx: &mut Self::Context, ui: &mut eframe::egui::Ui) {
-        Frame::canvas(ui.style()).show(ui, |ui| {
-            let color_picker_size = Vec2::new(150.0, 200.);
-        
-            let max_channel = 255.;
-            let (color_picker_rect,  picker_painter) = ui.allocate_painter(color_picker_size, Sense::empty());
-            picker_painter.rect_filled(color_picker_rect.rect, 0.0, Color32::from_rgb(125, 125, 125));
-
-            let clone_r_channel = ctx.app_settings.color_picker.r_channel;
-            let r_rect_containter = egui::Rect::from_center_size(Pos2::new(color_picker_rect.rect.min.x + 30., color_picker_rect.rect.min.y + 45.), Vec2::new(25., 80.));
-            picker_painter.rect_filled(r_rect_containter, 0.0, Color32::from_rgb(175, 175, 175));
-            let r_rect_sense = ui.allocate_rect(r_rect_containter, Sense::click_and_drag());
-            let r_height = 1. - (clone_r_channel / max_channel);
-            let red_rect = egui::Rect::from_min_max(Pos2::new(r_rect_containter.min.x, r_rect_containter.min.y + r_height * r_rect_containter.height()), r_rect_containter.max);
-            picker_painter.rect_filled(red_rect, 0.0, Color32::RED);
-            picker_painter.text(Pos2::new(r_rect_containter.center().x, r_rect_containter.max.y + 10.), Align2::CENTER_CENTER, format!("r: {:?}", clone_r_channel.floor() as u8), FontId::new(10., FontFamily::Proportional), Color32::WHITE);
-
-            if r_rect_sense.hovered() {
-                ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
-            }
-
-            if r_rect_sense.dragged_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
-
-                ctx.app_settings.color_picker.is_dragging = true;
-            }
-
-            if r_rect_sense.clicked_by(PointerButton::Primary) ||(r_rect_sense.dragged() && ctx.app_settings.color_picker.is_dragging) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
+impl ColorPicker {
+    /// Points the picker at `color`.
+    pub fn set_color(&mut self, color: Color32) {
+        let [r, g, b, a] = color.to_srgba_unmultiplied().map(|channel| channel as f32 / 255.);
+        self.set_rgb([r, g, b]);
+        self.alpha = a;
+    }
 
-                let interaction_pos = r_rect_sense.interact_pointer_pos();
-                if let Some(pos) = interaction_pos {
-                    let y_pos = ((pos.y - r_rect_containter.min.y) / r_rect_containter.height()).clamp(0.0, 1.0);
-                    
-                    ctx.app_settings.color_picker.r_channel = ((1. - y_pos) * max_channel).floor();
-                }
-            }
-            if r_rect_sense.drag_stopped_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::Alias);
+    pub fn color(&self) -> Color32 {
+        let [r, g, b] = hsv_to_rgb(self.hsv).map(to_byte);
+        Color32::from_rgba_unmultiplied(r, g, b, to_byte(self.alpha))
+    }
 
-                ctx.app_settings.color_picker.is_dragging = false;
-            }
+    fn set_rgb(&mut self, rgb: [f32; 3]) {
+        let [hue, saturation, value] = rgb_to_hsv(rgb);
+        // Greys have no hue and black has no saturation either, so those stay where they were
+        self.hsv = if value <= 0. {
+            [self.hsv[0], self.hsv[1], 0.]
+        } else if saturation <= 0. {
+            [self.hsv[0], 0., value]
+        } else {
+            [hue, saturation, value]
+        };
+    }
+}
 
-            let clone_g_channel = ctx.app_settings.color_picker.g_channel;
-            let g_rect_containter = egui::Rect::from_center_size(Pos2::new(color_picker_rect.rect.min.x + 60., color_picker_rect.rect.min.y + 45.), Vec2::new(25., 80.));
-            picker_painter.rect_filled(g_rect_containter, 0.0, Color32::from_rgb(175, 175, 175));
-            let g_rect_sense = ui.allocate_rect(g_rect_containter, Sense::click_and_drag());
-            let g_height = 1. - (clone_g_channel / max_channel);
-            
-            let green_rect = egui::Rect::from_min_max(Pos2::new(g_rect_containter.min.x, g_rect_containter.min.y + g_height * g_rect_containter.height()), g_rect_containter.max);
+fn to_byte(channel: f32) -> u8 {
+    (channel.clamp(0., 1.) * 255.).round() as u8
+}
 
-            picker_painter.rect_filled(green_rect, 0.0, Color32::GREEN);
-            picker_painter.text(Pos2::new(g_rect_containter.center().x, g_rect_containter.max.y + 10.), Align2::CENTER_CENTER, format!("g: {:?}", clone_g_channel.floor() as u8), FontId::new(10., FontFamily::Proportional), Color32::WHITE);
+fn opaque(rgb: [f32; 3]) -> Color32 {
+    let [r, g, b] = rgb.map(to_byte);
+    Color32::from_rgb(r, g, b)
+}
 
-            
-            if g_rect_sense.hovered() {
-                ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
-            }
-            if g_rect_sense.dragged_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
+/// Grid of `steps` cells over `rect` whose vertex colors come from `color_at(x, y)`, x and y going
+/// from 0 to 1 across the rect.
+fn gradient_mesh(rect: Rect, steps: [usize; 2], color_at: impl Fn(f32, f32) -> Color32) -> Mesh {
+    let mut mesh = Mesh::default();
+    for row in 0..=steps[1] {
+        for col in 0..=steps[0] {
+            let t = Vec2::new(col as f32 / steps[0] as f32, row as f32 / steps[1] as f32);
+            mesh.colored_vertex(rect.min + t * rect.size(), color_at(t.x, t.y));
+        }
+    }
+    let row_len = steps[0] as u32 + 1;
+    for row in 0..steps[1] as u32 {
+        for col in 0..steps[0] as u32 {
+            let idx = row * row_len + col;
+            mesh.add_triangle(idx, idx + 1, idx + row_len + 1);
+            mesh.add_triangle(idx, idx + row_len + 1, idx + row_len);
+        }
+    }
+    mesh
+}
 
-                ctx.app_settings.color_picker.is_dragging = true;
-            }
+/// Grey checks behind colors that may be see-through.
+fn checkerboard(ui: &Ui, rect: Rect) {
+    let check = 5.;
+    ui.painter().rect_filled(rect, 0., Color32::from_gray(200));
+    for row in 0..(rect.height() / check).ceil() as usize {
+        for col in (row % 2..(rect.width() / check).ceil() as usize).step_by(2) {
+            let min = rect.min + Vec2::new(col as f32, row as f32) * check;
+            ui.painter().rect_filled(Rect::from_min_max(min, (min + Vec2::splat(check)).min(rect.max)), 0., Color32::from_gray(120));
+        }
+    }
+}
 
-            if g_rect_sense.clicked_by(PointerButton::Primary) || (g_rect_sense.dragged() && ctx.app_settings.color_picker.is_dragging) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
+/// Where in `rect` the pointer presses or drags, as 0-1 fractions.
+fn drag_fraction(response: &Response, rect: Rect) -> Option<Vec2> {
+    if response.hovered() {
+        response.ctx.set_cursor_icon(CursorIcon::PointingHand);
+    }
+    if !response.clicked_by(PointerButton::Primary) && !response.dragged_by(PointerButton::Primary) {
+        return None;
+    }
+    let pos = response.interact_pointer_pos()?;
+    Some(((pos - rect.min) / rect.size()).clamp(Vec2::ZERO, Vec2::splat(1.)))
+}
 
-                let interaction_pos = g_rect_sense.interact_pointer_pos();
-                if let Some(pos) = interaction_pos {
-                    let y_pos = ((pos.y - g_rect_containter.min.y) / g_rect_containter.height()).clamp(0.0, 1.0);
-                    
-                    ctx.app_settings.color_picker.g_channel = ((1. - y_pos) * max_channel).floor();
+/// Horizontal bar editing `value` (0-1), showing the color every position of it would give.
+fn channel_bar(ui: &mut Ui, value: &mut f32, label: String, color_at: impl Fn(f32) -> Color32) -> bool {
+    let (rect, response) = ui.allocate_exact_size(Vec2::new(PICKER_WIDTH, BAR_HEIGHT), Sense::click_and_drag());
+    checkerboard(ui, rect);
+    ui.painter().add(gradient_mesh(rect, [24, 1], |x, _| color_at(x)));
+    let marker_x = rect.min.x + value.clamp(0., 1.) * rect.width();
+    ui.painter().rect(Rect::from_center_size(egui::pos2(marker_x, rect.center().y), Vec2::new(3., BAR_HEIGHT)), 0., Color32::WHITE, Stroke::new(1., Color32::BLACK), StrokeKind::Middle);
+    // A shadow keeps the label readable on light and dark gradients alike
+    let font = FontId::new(10., FontFamily::Proportional);
+    ui.painter().text(rect.left_center() + Vec2::new(5., 1.), Align2::LEFT_CENTER, &label, font.clone(), Color32::BLACK);
+    ui.painter().text(rect.left_center() + Vec2::new(4., 0.), Align2::LEFT_CENTER, label, font, Color32::WHITE);
+    match drag_fraction(&response, rect) {
+        Some(fraction) => {
+            *value = fraction.x;
+            true
+        },
+        None => false
+    }
+}
 
+impl AppComponentExt for ColorPicker {
+    type Context = App;
+    fn add(ctx: &mut Self::Context, ui: &mut eframe::egui::Ui) {
+        Frame::canvas(ui.style()).show(ui, |ui| {
+            ui.spacing_mut().item_spacing = Vec2::new(4., 4.);
+            let picker = &mut ctx.app_settings.color_picker;
+
+            // Saturation/value square next to a hue strip
+            ui.horizontal(|ui| {
+                let square_side = PICKER_WIDTH - HUE_STRIP_WIDTH - ui.spacing().item_spacing.x;
+                let [hue, saturation, value] = picker.hsv;
+                let (square_rect, square_response) = ui.allocate_exact_size(Vec2::splat(square_side), Sense::click_and_drag());
+                ui.painter().add(gradient_mesh(square_rect, [12, 12], |x, y| opaque(hsv_to_rgb([hue, x, 1. - y]))));
+                let marker = square_rect.min + Vec2::new(saturation, 1. - value) * square_rect.size();
+                ui.painter().circle_stroke(marker, 4., Stroke::new(1.5, if value > 0.5 { Color32::BLACK } else { Color32::WHITE }));
+                if let Some(fraction) = drag_fraction(&square_response, square_rect) {
+                    picker.hsv = [hue, fraction.x, 1. - fraction.y];
                 }
-            }
-
-            if g_rect_sense.drag_stopped_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::Alias);
-                
-                ctx.app_settings.color_picker.is_dragging = false;
-
-            }
 
+                let (strip_rect, strip_response) = ui.allocate_exact_size(Vec2::new(HUE_STRIP_WIDTH, square_side), Sense::click_and_drag());
+                ui.painter().add(gradient_mesh(strip_rect, [1, 36], |_, y| opaque(hsv_to_rgb([y, 1., 1.]))));
+                let marker_y = strip_rect.min.y + hue * strip_rect.height();
+                ui.painter().rect(Rect::from_center_size(egui::pos2(strip_rect.center().x, marker_y), Vec2::new(HUE_STRIP_WIDTH, 3.)), 0., Color32::WHITE, Stroke::new(1., Color32::BLACK), StrokeKind::Middle);
+                if let Some(fraction) = drag_fraction(&strip_response, strip_rect) {
+                    picker.hsv[0] = fraction.y;
+                }
+            });
 
-            let clone_b_channel = ctx.app_settings.color_picker.b_channel;
-            let b_rect_containter = egui::Rect::from_center_size(Pos2::new(color_picker_rect.rect.min.x + 90., color_picker_rect.rect.min.y + 45.), Vec2::new(25., 80.));
-            picker_painter.rect_filled(b_rect_containter, 0.0, Color32::from_rgb(175, 175, 175));
-            let b_rect_sense = ui.allocate_rect(b_rect_containter, Sense::click_and_drag());
-            let b_height = 1. - (clone_b_channel / max_channel);
-            
-            let blue_rect = egui::Rect::from_min_max(Pos2::new(b_rect_containter.min.x, b_rect_containter.min.y + b_height * b_rect_containter.height()), b_rect_containter.max);
-            picker_painter.rect_filled(blue_rect, 0.0, Color32::BLUE);
-            picker_painter.text(Pos2::new(b_rect_containter.center().x, b_rect_containter.max.y + 10.), Align2::CENTER_CENTER, format!("b: {:?}", clone_b_channel.floor() as u8), FontId::new(10., FontFamily::Proportional), Color32::WHITE);
-
-
-            if b_rect_sense.hovered() {
-                ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
-            }
-
-            if b_rect_sense.dragged_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
-
-                ctx.app_settings.color_picker.is_dragging = true;
-            }
-            if b_rect_sense.clicked_by(PointerButton::Primary) ||(b_rect_sense.dragged() && ctx.app_settings.color_picker.is_dragging) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
-
-                let interaction_pos = b_rect_sense.interact_pointer_pos();
-                if let Some(pos) = interaction_pos {
-                    let y_pos = ((pos.y - b_rect_containter.min.y) / b_rect_containter.height()).clamp(0.0, 1.0);
-                  
-                    ctx.app_settings.color_picker.b_channel = ((1. - y_pos) * max_channel).floor();
-
+            ui.horizontal(|ui| {
+                for model in ColorModel::ALL {
+                    ui.selectable_value(&mut picker.model, model, model.to_string());
+                }
+            });
+            let rgb = hsv_to_rgb(picker.hsv);
+            match picker.model {
+                ColorModel::Rgb => {
+                    let mut new_rgb = rgb;
+                    for (channel, name) in ["R", "G", "B"].iter().enumerate() {
+                        let label = format!("{name} {}", to_byte(rgb[channel]));
+                        if channel_bar(ui, &mut new_rgb[channel], label, |t| opaque(std::array::from_fn(|i| if i == channel { t } else { rgb[i] }))) {
+                            picker.set_rgb(new_rgb);
+                        }
+                    }
+                },
+                ColorModel::Hsl => {
+                    let hsl = hsv_to_hsl(picker.hsv);
+                    let labels = [
+                        format!("H {:.0}°", hsl[0] * 360.),
+                        format!("S {:.0}%", hsl[1] * 100.),
+                        format!("L {:.0}%", hsl[2] * 100.)
+                    ];
+                    let mut new_hsl = hsl;
+                    for (channel, label) in labels.into_iter().enumerate() {
+                        let color_at = |t: f32| opaque(hsv_to_rgb(hsl_to_hsv(std::array::from_fn(|i| if i == channel { t } else { hsl[i] }))));
+                        if channel_bar(ui, &mut new_hsl[channel], label, color_at) {
+                            picker.hsv = hsl_to_hsv(new_hsl);
+                        }
+                    }
+                },
+                ColorModel::Oklch => {
+                    // Bars edit 0-1 fractions of lightness, chroma up to the most sRGB can show, and hue
+                    let ranges = [1., MAX_OKLCH_CHROMA, 360.];
+                    let lch = rgb_to_oklch(rgb);
+                    let labels = [
+                        format!("L {:.2}", lch[0]),
+                        format!("C {:.3}", lch[1]),
+                        format!("H {:.0}°", lch[2])
+                    ];
+                    let mut fractions: [f32; 3] = std::array::from_fn(|i| lch[i] / ranges[i]);
+                    for (channel, label) in labels.into_iter().enumerate() {
+                        let color_at = |t: f32| opaque(oklch_to_rgb(std::array::from_fn(|i| if i == channel { t * ranges[i] } else { lch[i] })));
+                        if channel_bar(ui, &mut fractions[channel], label, color_at) {
+                            picker.set_rgb(oklch_to_rgb(std::array::from_fn(|i| fractions[i] * ranges[i])));
+                        }
+                    }
                 }
             }
-            if b_rect_sense.drag_stopped_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::Alias);
-
-                ctx.app_settings.color_picker.is_dragging = false;
+            let alpha_label = format!("A {:.0}%", picker.alpha * 100.);
+            channel_bar(ui, &mut picker.alpha, alpha_label, |t| {
+                let [r, g, b] = rgb.map(to_byte);
+                Color32::from_rgba_unmultiplied(r, g, b, to_byte(t))
+            });
+
+            // Hex or rgb() text, applied as soon as it reads as a color
+            let hex_response = ui.add(egui::TextEdit::singleline(&mut picker.hex_text).desired_width(PICKER_WIDTH - 8.).hint_text("#rrggbb or rgb()"));
+            if hex_response.changed() && let Some(color) = parse_color(&picker.hex_text) {
+                picker.set_color(color);
             }
-            
-            let clone_a_channel: f32 = ctx.app_settings.color_picker.a_channel;
-            let a_rect_containter = egui::Rect::from_center_size(Pos2::new(color_picker_rect.rect.min.x + 120., color_picker_rect.rect.min.y + 45.), Vec2::new(25., 80.));
-            picker_painter.rect_filled(a_rect_containter, 0.0, Color32::from_rgb(175, 175, 175));
-            let a_rect_sense = ui.allocate_rect(a_rect_containter, Sense::click_and_drag());
-            let a_height = 1. - (clone_a_channel / max_channel);
-            
-            let alpha_rect = egui::Rect::from_min_max(Pos2::new(a_rect_containter.min.x, a_rect_containter.min.y + a_height * a_rect_containter.height()), a_rect_containter.max);
-            picker_painter.rect_filled(alpha_rect, 0.0, Color32::BLACK);
-            picker_painter.text(Pos2::new(a_rect_containter.center().x, a_rect_containter.max.y + 10.), Align2::CENTER_CENTER, format!("a: {:.02}", clone_a_channel.floor()  / max_channel), FontId::new(10., FontFamily::Proportional), Color32::WHITE);
-
-
-            if a_rect_sense.hovered() {
-                ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+            if !hex_response.has_focus() {
+                picker.hex_text = to_hex(picker.color());
             }
 
-            if a_rect_sense.dragged_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
-
-                ctx.app_settings.color_picker.is_dragging = true;
-            }
-            if a_rect_sense.clicked_by(PointerButton::Primary) || (a_rect_sense.dragged() && ctx.app_settings.color_picker.is_dragging) {
-                ui.ctx().set_cursor_icon(CursorIcon::ResizeVertical);
-
-                let interaction_pos = a_rect_sense.interact_pointer_pos();
-                if let Some(pos) = interaction_pos {
-                    let y_pos = ((pos.y - a_rect_containter.min.y) / a_rect_containter.height()).clamp(0.0, 1.0);
-                  
-                    ctx.app_settings.color_picker.a_channel = ((1. - y_pos) * max_channel).floor();
-
+            // Before/after swatch: the current color on the left, the edited one on the right
+            let result_color = picker.color();
+            let current_color = ctx.app_state.current_color.as_ref().map(|color| color.color).unwrap_or_default();
+            let (swatch_rect, _) = ui.allocate_exact_size(Vec2::new(PICKER_WIDTH, 30.), Sense::hover());
+            let (before_rect, after_rect) = swatch_rect.split_left_right_at_fraction(0.5);
+            checkerboard(ui, swatch_rect);
+            ui.painter().rect_filled(before_rect, 0., current_color);
+            ui.painter().rect_filled(after_rect, 0., result_color);
+            let before_sense = ui.allocate_rect(before_rect, Sense::click()).on_hover_text("Current color, click to go back to it");
+            let after_sense = ui.allocate_rect(after_rect, Sense::click()).on_hover_text("Click to overwrite the current color");
+            for sense in [&before_sense, &after_sense] {
+                if sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
                 }
             }
-            if a_rect_sense.drag_stopped_by(PointerButton::Primary) {
-                ui.ctx().set_cursor_icon(CursorIcon::Alias);
-
-                ctx.app_settings.color_picker.is_dragging = false;
-            }
-
-            picker_painter.text(Pos2::new(color_picker_rect.rect.center().x, r_rect_containter.max.y + 50.), Align2::CENTER_CENTER, "Click to \n overwrite the color", FontId::new(12., FontFamily::Monospace), Color32::WHITE);
-            let result_rect = egui::Rect::from_min_max(Pos2::new(r_rect_containter.min.x, r_rect_containter.max.y + 75. ), Pos2::new(b_rect_containter.max.x, b_rect_containter.max.y + 105.));
-            let result_color = Color32::from_rgba_unmultiplied(ctx.app_settings.color_picker.r_channel.floor() as u8, ctx.app_settings.color_picker.g_channel.floor() as u8, ctx.app_settings.color_picker.b_channel.floor() as u8, ctx.app_settings.color_picker.a_channel.floor() as u8);
-            picker_painter.rect_filled(result_rect, 2., result_color.clone()) ;
-            let result_sense = ui.allocate_rect(result_rect, Sense::click());
-            if result_sense.hovered() {
-                ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+            if before_sense.clicked_by(PointerButton::Primary) {
+                ctx.app_settings.color_picker.set_color(current_color);
             }
-            if result_sense.clicked_by(PointerButton::Primary) {
-                if let Some(color) = &mut ctx.app_state.current_color {
-                    if let Some(find_color) = ctx.app_state.color_palette.iter_mut().find(|c| c.id == color.id) {
-                        find_color.color = result_color.clone();
+            if after_sense.clicked_by(PointerButton::Primary) && let Some(color) = &mut ctx.app_state.current_color {
+                let color_id = color.id;
+                color.color = result_color;
+                ctx.app_state.edit_palette(|palette| {
+                    if let Some(find_color) = palette.iter_mut().find(|c| c.id == color_id) {
+                        find_color.color = result_color;
                     };
-                    color.color = result_color;
-                }
+                });
             }
         });
-
-        
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,19 @@
-use egui::{Align2, Color32, CursorIcon, FontFamily, FontId, Frame, PointerButton, Pos2, Sense, Vec2};
+use std::ops::RangeInclusive;
+
+use egui::{Align2, Color32, CursorIcon, FontFamily, FontId, Frame, Id, Key, PointerButton, Pos2, Sense, Stroke, Vec2};
 use super::AppComponentExt;
-use crate::app::{components::utils::{layer::{Layer, LayerTexture}, new_rand_id}, App};
+use crate::app::{components::utils::{image_color::BlendMode, layer::{Layer, LayerTexture}, new_rand_id}, App};
 
 
 pub struct LayersDisplayContainer;
 
+#[derive(Clone, PartialEq, Default)]
+pub struct LayersPanelState {
+    pub dragged_layer: Option<usize>,
+    pub renaming_layer: Option<Id>,
+    pub rename_text: String,
+    pub rename_focus_requested: bool
+}
 
 
 impl AppComponentExt for LayersDisplayContainer {
@@ -28,22 +37,85 @@ impl AppComponentExt for LayersDisplayContainer {
                     ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
                 }
                 if add_layer_sense.clicked_by(PointerButton::Primary) {
-                    let new_layer: Layer = Layer {id: new_rand_id(), name: format!("Layer {}", (ctx.app_state.layers_container.layers.len() + 1).to_string()), is_visible: true, texture: LayerTexture::new(ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize)};
-                    ctx.app_state.layers_container.layers.insert(0, new_layer.clone());
-                    ctx.app_state.current_layer = Some(new_layer.id);
+                    let new_layer: Layer = Layer {id: new_rand_id(), name: format!("Layer {}", ctx.app_state.layers_container.layers.len() + 1), is_visible: true, blend_mode: BlendMode::Normal, opacity: 1., texture: LayerTexture::new(ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize)};
+                    ctx.app_state.add_layer(0, new_layer);
                 }
                 ui.add_space(10.);
+                let current_layer = ctx.app_state.current_layer.and_then(|id| ctx.app_state.layers_container.layers.iter().find(|l| l.id == id));
+                if let Some((layer_id, current_blend_mode, current_opacity)) = current_layer.map(|l| (l.id, l.blend_mode, l.opacity)) {
+                    let mut blend_mode = current_blend_mode;
+                    ui.horizontal(|ui| {
+                        ui.label("Blend");
+                        egui::ComboBox::from_id_salt("layer_blend_mode")
+                            .selected_text(blend_mode.to_string())
+                            .show_ui(ui, |ui| {
+                                for mode in BlendMode::ALL {
+                                    ui.selectable_value(&mut blend_mode, mode, mode.to_string());
+                                }
+                            });
+                    });
+                    if blend_mode != current_blend_mode {
+                        ctx.app_state.set_layer_blend_mode(layer_id, blend_mode);
+                    }
+                    let mut opacity_percent = current_opacity * 100.;
+                    ui.horizontal(|ui| {
+                        ui.label("Opacity");
+                        let opacity_slider = ui.add(egui::Slider::new(&mut opacity_percent, RangeInclusive::new(0., 100.)).suffix("%").fixed_decimals(0));
+                        if opacity_slider.changed() {
+                            // Every drag gets an undo step of its own, however close together they are
+                            let continues_drag = opacity_slider.dragged() && !opacity_slider.drag_started();
+                            ctx.app_state.set_layer_opacity(layer_id, opacity_percent / 100., continues_drag);
+                        }
+                    });
+                    let layers_len = ctx.app_state.layers_container.layers.len();
+                    ui.horizontal(|ui| {
+                        if ui.button(egui_phosphor::regular::COPY).on_hover_text("Duplicate layer").clicked() {
+                            ctx.app_state.duplicate_layer(layer_id);
+                        }
+                        if ui.add_enabled(layers_len > 1, egui::Button::new(egui_phosphor::regular::TRASH)).on_hover_text("Delete layer").clicked() {
+                            ctx.app_state.delete_layer(layer_id);
+                        }
+                        if ui.add_enabled(ctx.app_state.can_merge_down(layer_id), egui::Button::new(egui_phosphor::regular::ARROW_LINE_DOWN)).on_hover_text("Merge down").clicked() {
+                            ctx.app_state.merge_down(layer_id);
+                        }
+                        if ui.add_enabled(layers_len > 1, egui::Button::new(egui_phosphor::regular::STACK_SIMPLE)).on_hover_text("Flatten image").clicked() {
+                            let layer_size = [ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize];
+                            ctx.app_state.flatten(layer_size);
+                        }
+                    });
+                }
                 ui.separator();
                 egui::ScrollArea::vertical().max_height(container_height).show(ui, |ui| {
                     // ui.ctx().set_style(style);
                     ui.set_height(container_height);
                     ui.set_width(ui.available_width());
                     ui.vertical(|ui| {
-                        for (_layer_idx, layer) in ctx.app_state.layers_container.layers.iter_mut().enumerate() {
+                        let panel = &mut ctx.app_settings.layers_panel;
+                        let mut toggled_layer = None;
+                        let mut renamed_layer = None;
+                        let mut row_rects: Vec<egui::Rect> = Vec::new();
+                        for (layer_idx, layer) in ctx.app_state.layers_container.layers.iter().enumerate() {
                             
-                            let (layer_rect, layer_painter) = ui.allocate_painter(layer_size, Sense::click());
-                            layer_painter.rect_filled(layer_rect.rect, 0.0, Color32::from_rgb(100, 100, 100));
-                            layer_painter.text(Pos2::new(layer_rect.rect.min.x + 10., layer_rect.rect.center().y), Align2::LEFT_CENTER, format!("{}", layer.name), FontId::new(16., FontFamily::Proportional), Color32::WHITE);
+                            let (layer_rect, layer_painter) = ui.allocate_painter(layer_size, Sense::click_and_drag());
+                            row_rects.push(layer_rect.rect);
+                            let row_color = if panel.dragged_layer == Some(layer_idx) { Color32::from_rgb(70, 70, 70) } else { Color32::from_rgb(100, 100, 100) };
+                            layer_painter.rect_filled(layer_rect.rect, 0.0, row_color);
+                            if panel.renaming_layer == Some(layer.id) {
+                                let name_rect = egui::Rect::from_min_max(layer_rect.rect.min + Vec2::new(5., 4.), Pos2::new(layer_rect.rect.max.x - 50., layer_rect.rect.max.y - 4.));
+                                let name_edit = ui.put(name_rect, egui::TextEdit::singleline(&mut panel.rename_text));
+                                if panel.rename_focus_requested {
+                                    name_edit.request_focus();
+                                    panel.rename_focus_requested = false;
+                                }
+                                if name_edit.lost_focus() {
+                                    if !ui.input(|i| i.key_pressed(Key::Escape)) {
+                                        renamed_layer = Some((layer.id, panel.rename_text.clone()));
+                                    }
+                                    panel.renaming_layer = None;
+                                }
+                            } else {
+                                layer_painter.text(Pos2::new(layer_rect.rect.min.x + 10., layer_rect.rect.center().y), Align2::LEFT_CENTER, layer.name.as_str(), FontId::new(16., FontFamily::Proportional), Color32::WHITE);
+                            }
                             
                             if let Some(active_layer) = ctx.app_state.current_layer {
                                 if layer.id == active_layer {
@@ -57,6 +129,14 @@ impl AppComponentExt for LayersDisplayContainer {
                             if layer_rect.clicked_by(PointerButton::Primary) {
                                 ctx.app_state.current_layer = Some(layer.id);
                             }
+                            if layer_rect.double_clicked_by(PointerButton::Primary) {
+                                panel.renaming_layer = Some(layer.id);
+                                panel.rename_text = layer.name.clone();
+                                panel.rename_focus_requested = true;
+                            }
+                            if layer_rect.drag_started_by(PointerButton::Primary) {
+                                panel.dragged_layer = Some(layer_idx);
+                            }
                         
                             let visible_rect_container = egui::Rect::from_center_size(Pos2::new(layer_rect.rect.max.x - 30., layer_rect.rect.center().y), Vec2::new(30., 20.));
                             layer_painter.rect_filled(visible_rect_container, 2., Color32::WHITE);
@@ -70,10 +150,32 @@ impl AppComponentExt for LayersDisplayContainer {
                             let visible_sense =  ui.allocate_rect(visible_rect_container, Sense::click());
                           
                             if visible_sense.clicked_by(PointerButton::Primary) {
-                                layer.is_visible = !layer.is_visible;
+                                toggled_layer = Some(layer.id);
                             }
                             
                         }
+
+                        // Drag and drop reordering: the drop slot is the first row whose middle is below the pointer
+                        if let Some(from) = panel.dragged_layer {
+                            ui.ctx().set_cursor_icon(CursorIcon::Grabbing);
+                            let pointer_y = ui.ctx().pointer_interact_pos().map(|pos| pos.y).unwrap_or_default();
+                            let slot = row_rects.iter().position(|rect| pointer_y < rect.center().y).unwrap_or(row_rects.len());
+                            let line_y = row_rects.get(slot).map(|rect| rect.min.y).or(row_rects.last().map(|rect| rect.max.y)).unwrap_or_default();
+                            if let Some(first_row) = row_rects.first() {
+                                ui.painter().hline(first_row.x_range(), line_y, Stroke::new(2., Color32::WHITE));
+                            }
+                            if ui.input(|i| i.pointer.any_released()) {
+                                panel.dragged_layer = None;
+                                let to = if slot > from { slot - 1 } else { slot };
+                                ctx.app_state.move_layer(from, to);
+                            }
+                        }
+                        if let Some(layer_id) = toggled_layer {
+                            ctx.app_state.toggle_layer_visibility(layer_id);
+                        }
+                        if let Some((layer_id, name)) = renamed_layer {
+                            ctx.app_state.rename_layer(layer_id, name);
+                        }
                     });
                 });
             });
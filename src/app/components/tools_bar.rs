@@ -2,11 +2,32 @@ use std::ops::RangeInclusive;
 
 use egui::{Align2, Color32, CursorIcon, FontFamily, FontId, PointerButton, Pos2, Sense, Stroke, StrokeKind, Vec2};
 
+use crate::app::components::utils::create_paint::MAX_CANVAS_SIZE;
+use crate::app::components::utils::draw_tool::Pencil;
+use crate::app::components::utils::eyedropper::SampleSize;
+use crate::app::components::utils::flood_fill::FillSample;
+use crate::app::components::utils::selection::SelectionMode;
+use crate::app::components::utils::transform::{Interpolation, TransformTarget};
 use crate::app::{components::AppComponentExt, App};
 
 
 pub struct ToolBar;
 
+#[derive(Clone, PartialEq, Default)]
+pub struct ToolBarState {
+    preset_name: String
+}
+
+/// Slider editing a 0..1 fraction as a percentage.
+fn percent_slider<'a>(value: &'a mut f32, range: RangeInclusive<f64>, prefix: &str) -> egui::Slider<'a> {
+    egui::Slider::from_get_set(range, move |new_value| {
+        if let Some(new_value) = new_value {
+            *value = new_value as f32 / 100.;
+        }
+        (*value * 100.) as f64
+    }).prefix(prefix).suffix("%").fixed_decimals(0)
+}
+
 
 impl AppComponentExt for ToolBar {
     type Context = App;
@@ -53,7 +74,93 @@ impl AppComponentExt for ToolBar {
                     ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
                 }
                 if export_image_sense.clicked_by(PointerButton::Primary) {
-                    let _saved = ctx.save_to_image();
+                    ctx.app_settings.export_image_widget.is_open = true;
+                }
+
+                let undo_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 350., options_sense.rect.center().y), Vec2::new(80., 25.));
+                let undo_text_color = if ctx.app_state.history.can_undo() { Color32::BLACK } else { Color32::GRAY };
+                options_painter.rect_filled(undo_rect, 5., Color32::WHITE);
+                options_painter.text(undo_rect.center(), Align2::CENTER_CENTER, "Undo", FontId::new(12., FontFamily::Monospace), undo_text_color);
+
+                let undo_sense = ui.allocate_rect(undo_rect, Sense::click());
+                if undo_sense.hovered() && ctx.app_state.history.can_undo() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if undo_sense.clicked_by(PointerButton::Primary) {
+                    ctx.app_state.undo();
+                }
+
+                let redo_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 450., options_sense.rect.center().y), Vec2::new(80., 25.));
+                let redo_text_color = if ctx.app_state.history.can_redo() { Color32::BLACK } else { Color32::GRAY };
+                options_painter.rect_filled(redo_rect, 5., Color32::WHITE);
+                options_painter.text(redo_rect.center(), Align2::CENTER_CENTER, "Redo", FontId::new(12., FontFamily::Monospace), redo_text_color);
+
+                let redo_sense = ui.allocate_rect(redo_rect, Sense::click());
+                if redo_sense.hovered() && ctx.app_state.history.can_redo() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if redo_sense.clicked_by(PointerButton::Primary) {
+                    ctx.app_state.redo();
+                }
+
+                let open_project_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 550., options_sense.rect.center().y), Vec2::new(80., 25.));
+                options_painter.rect_filled(open_project_rect, 5., Color32::WHITE);
+                options_painter.text(open_project_rect.center(), Align2::CENTER_CENTER, "Open", FontId::new(12., FontFamily::Monospace), Color32::BLACK);
+
+                let open_project_sense = ui.allocate_rect(open_project_rect, Sense::click());
+                if open_project_sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if open_project_sense.clicked_by(PointerButton::Primary) && let Err(error) = ctx.open_project() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to open project: {error}"));
+                }
+
+                let save_project_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 650., options_sense.rect.center().y), Vec2::new(80., 25.));
+                options_painter.rect_filled(save_project_rect, 5., Color32::WHITE);
+                options_painter.text(save_project_rect.center(), Align2::CENTER_CENTER, "Save", FontId::new(12., FontFamily::Monospace), Color32::BLACK);
+
+                let save_project_sense = ui.allocate_rect(save_project_rect, Sense::click());
+                if save_project_sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if save_project_sense.clicked_by(PointerButton::Primary) && let Err(error) = ctx.save_project() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to save project: {error}"));
+                }
+
+                let save_project_as_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 750., options_sense.rect.center().y), Vec2::new(80., 25.));
+                options_painter.rect_filled(save_project_as_rect, 5., Color32::WHITE);
+                options_painter.text(save_project_as_rect.center(), Align2::CENTER_CENTER, "Save As", FontId::new(12., FontFamily::Monospace), Color32::BLACK);
+
+                let save_project_as_sense = ui.allocate_rect(save_project_as_rect, Sense::click());
+                if save_project_as_sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if save_project_as_sense.clicked_by(PointerButton::Primary) && let Err(error) = ctx.save_project_as() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to save project: {error}"));
+                }
+
+                let import_ora_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 850., options_sense.rect.center().y), Vec2::new(80., 25.));
+                options_painter.rect_filled(import_ora_rect, 5., Color32::WHITE);
+                options_painter.text(import_ora_rect.center(), Align2::CENTER_CENTER, "Open ORA", FontId::new(12., FontFamily::Monospace), Color32::BLACK);
+
+                let import_ora_sense = ui.allocate_rect(import_ora_rect, Sense::click());
+                if import_ora_sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if import_ora_sense.clicked_by(PointerButton::Primary) && let Err(error) = ctx.import_open_raster() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to open OpenRaster file: {error}"));
+                }
+
+                let export_ora_rect = egui::Rect::from_center_size(Pos2::new(options_sense.rect.min.x + 950., options_sense.rect.center().y), Vec2::new(80., 25.));
+                options_painter.rect_filled(export_ora_rect, 5., Color32::WHITE);
+                options_painter.text(export_ora_rect.center(), Align2::CENTER_CENTER, "Export ORA", FontId::new(12., FontFamily::Monospace), Color32::BLACK);
+
+                let export_ora_sense = ui.allocate_rect(export_ora_rect, Sense::click());
+                if export_ora_sense.hovered() {
+                    ui.ctx().set_cursor_icon(CursorIcon::PointingHand);
+                }
+                if export_ora_sense.clicked_by(PointerButton::Primary) && let Err(error) = ctx.export_open_raster() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to export OpenRaster file: {error}"));
                 }
                 if ctx.app_settings.new_paint_settings.is_open {
                     egui::Window::new("New paint")
@@ -64,12 +171,12 @@ impl AppComponentExt for ToolBar {
                             ui.horizontal(|ui| {
                                 
                                 ui.label("New canva width");
-                                ui.add(egui::DragValue::new(&mut ctx.app_settings.new_paint_settings.width).speed(5.).range(RangeInclusive::new(50.0, 1600.)));
+                                ui.add(egui::DragValue::new(&mut ctx.app_settings.new_paint_settings.width).speed(5.).range(RangeInclusive::new(50.0, MAX_CANVAS_SIZE as f64)));
                             });
                              ui.horizontal(|ui| {
                                 
                                 ui.label("New canva height");
-                                ui.add(egui::DragValue::new(&mut ctx.app_settings.new_paint_settings.height).speed(5.).range(RangeInclusive::new(50.0, 1200.)));
+                                ui.add(egui::DragValue::new(&mut ctx.app_settings.new_paint_settings.height).speed(5.).range(RangeInclusive::new(50.0, MAX_CANVAS_SIZE as f64)));
                             });
                             ui.separator();
                             ui.horizontal(|ui| {
@@ -87,8 +194,10 @@ impl AppComponentExt for ToolBar {
                 }
             });
             ui.horizontal(|ui| {
-                let (container_response, container_painter) = ui.allocate_painter(Vec2::new(1000., 25.), Sense::click());
                 let starting_offset = Vec2::new(100., 0.0);
+                // Only as wide as the tool buttons so the brush presets can follow them
+                let tools_width = starting_offset.x + (button_size.x + 2. * padding) * ctx.app_settings.draw_tools.tools.len() as f32;
+                let (container_response, container_painter) = ui.allocate_painter(Vec2::new(tools_width, 25.), Sense::click());
                 
                 let load_image_rect = egui::Rect::from_min_max(
                     Pos2::new(container_response.rect.min.x + padding, container_response.rect.min.y + padding), 
@@ -130,19 +239,208 @@ impl AppComponentExt for ToolBar {
                     }
                     if button_sense.clicked_by(PointerButton::Primary) {
                         ctx.app_state.current_draw_tool = Some(tool.clone());
+                        ctx.app_state.shape_anchors.clear();
                         ctx.app_settings.pencil_cursor.set_pencil(tool.pencil.clone());
                     }
                 }
                 // ui.label("This is tool bar");
+                ui.separator();
+                let mut selected_preset = None;
+                let mut deleted_preset = None;
+                for preset in ctx.app_settings.brush_presets.presets.iter() {
+                    // A preset stays highlighted until one of its settings is changed
+                    let preset_label = ui.selectable_label(preset.brush == ctx.app_state.current_brush, preset.name.as_str());
+                    if preset_label.clicked() {
+                        selected_preset = Some(preset.brush.clone());
+                    }
+                    if preset.is_user {
+                        preset_label.context_menu(|ui| {
+                            if ui.button("Delete preset").clicked() {
+                                deleted_preset = Some(preset.id);
+                                ui.close();
+                            }
+                        });
+                    }
+                }
+                if let Some(brush) = selected_preset {
+                    ctx.app_settings.pencil_cursor.set_radius(brush.size);
+                    ctx.app_state.current_brush = brush;
+                }
+                if let Some(preset_id) = deleted_preset && let Err(error) = ctx.app_settings.brush_presets.delete_preset(preset_id) {
+                    ctx.app_settings.notification_widget.error(format!("Failed to delete brush preset: {error}"));
+                }
+                ui.separator();
+                ui.add(egui::TextEdit::singleline(&mut ctx.app_settings.tool_bar.preset_name).hint_text("Preset name").desired_width(100.));
+                if ui.button("Save preset").clicked() {
+                    let name = std::mem::take(&mut ctx.app_settings.tool_bar.preset_name);
+                    if let Err(error) = ctx.app_settings.brush_presets.save_preset(&name, ctx.app_state.current_brush.clone()) {
+                        ctx.app_settings.notification_widget.error(format!("Failed to save brush preset: {error}"));
+                    }
+                }
             });
             ui.add_space(10.);
+            // The bucket has no use for brush settings, so it shows its own instead
+            if ctx.app_state.current_draw_tool.as_ref().is_some_and(|tool| tool.pencil == Pencil::Fill) {
+                let fill = &mut ctx.app_settings.fill_settings;
+                ui.horizontal(|ui| {
+                    ui.add(percent_slider(&mut fill.tolerance, RangeInclusive::new(0., 100.), "Tolerance: "));
+                    ui.checkbox(&mut fill.contiguous, "Contiguous");
+                    ui.checkbox(&mut fill.anti_alias, "Anti-alias");
+                    egui::ComboBox::from_id_salt("fill_sample")
+                        .selected_text(format!("Sample: {}", fill.sample))
+                        .show_ui(ui, |ui| {
+                            for sample in FillSample::ALL {
+                                ui.selectable_value(&mut fill.sample, sample, sample.to_string());
+                            }
+                        });
+                    ui.add(egui::Slider::new(&mut fill.gap_closing, RangeInclusive::new(0, 20)).prefix("Close gaps: ").suffix(" px"));
+                });
+                return;
+            }
+            if ctx.app_state.current_draw_tool.as_ref().is_some_and(|tool| tool.pencil == Pencil::Eyedropper) {
+                let eyedropper = &mut ctx.app_settings.eyedropper_settings;
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("eyedropper_sample")
+                        .selected_text(format!("Sample: {}", eyedropper.sample))
+                        .show_ui(ui, |ui| {
+                            for sample in FillSample::ALL {
+                                ui.selectable_value(&mut eyedropper.sample, sample, sample.to_string());
+                            }
+                        });
+                    for size in SampleSize::ALL {
+                        ui.selectable_value(&mut eyedropper.size, size, size.to_string());
+                    }
+                    ui.checkbox(&mut eyedropper.add_to_palette, "Add to palette");
+                    ui.label("Hold Alt with the brush, pen or bucket to pick too");
+                });
+                return;
+            }
+            if ctx.app_state.current_draw_tool.as_ref().is_some_and(|tool| tool.pencil == Pencil::Move) {
+                let layer_size = [ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize];
+                let is_transforming = ctx.app_state.transform.is_some();
+                let transform_settings = &mut ctx.app_settings.transform_settings;
+                let mut flip = None;
+                let (mut is_apply_clicked, mut is_cancel_clicked) = (false, false);
+                ui.horizontal(|ui| {
+                    // What gets lifted can't change halfway through a transform
+                    ui.add_enabled_ui(!is_transforming, |ui| {
+                        for target in TransformTarget::ALL {
+                            ui.selectable_value(&mut transform_settings.target, target, target.to_string());
+                        }
+                    });
+                    egui::ComboBox::from_id_salt("transform_interpolation")
+                        .selected_text(format!("Resample: {}", transform_settings.interpolation))
+                        .show_ui(ui, |ui| {
+                            for interpolation in Interpolation::ALL {
+                                ui.selectable_value(&mut transform_settings.interpolation, interpolation, interpolation.to_string());
+                            }
+                        });
+                    ui.separator();
+                    if ui.button("Flip H").clicked() {
+                        flip = Some(true);
+                    }
+                    if ui.button("Flip V").clicked() {
+                        flip = Some(false);
+                    }
+                    is_apply_clicked = ui.add_enabled(is_transforming, egui::Button::new("Apply")).clicked();
+                    is_cancel_clicked = ui.add_enabled(is_transforming, egui::Button::new("Cancel")).clicked();
+                    ui.label("Handles scale, outside rotates, Ctrl+edge skews, Shift constrains; Enter applies, Esc cancels");
+                });
+                let settings = ctx.app_settings.transform_settings;
+                if let Some(horizontal) = flip && ctx.app_state.begin_transform(settings.target) && let Some(transform) = &mut ctx.app_state.transform {
+                    transform.flip(horizontal);
+                }
+                if is_apply_clicked {
+                    ctx.app_state.apply_transform(settings.interpolation, layer_size);
+                } else if is_cancel_clicked {
+                    ctx.app_state.cancel_transform();
+                }
+                return;
+            }
+            if ctx.app_state.current_draw_tool.as_ref().is_some_and(|tool| tool.pencil.is_shape()) {
+                let shape = &mut ctx.app_settings.shape_settings;
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut shape.filled, "Filled");
+                    ui.add_enabled(!shape.filled, egui::Slider::new(&mut shape.width, RangeInclusive::new(1., 50.)).prefix("Width: "));
+                    ui.checkbox(&mut shape.anti_alias, "Anti-alias");
+                    ui.label("Shift: squares, circles and 15° angles");
+                });
+                return;
+            }
+            if ctx.app_state.current_draw_tool.as_ref().is_some_and(|tool| tool.pencil.is_selection()) {
+                let layer_size = [ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize];
+                let selection_settings = &mut ctx.app_settings.selection_settings;
+                ui.horizontal(|ui| {
+                    for mode in SelectionMode::ALL {
+                        ui.selectable_value(&mut selection_settings.mode, mode, mode.to_string());
+                    }
+                    ui.add(egui::Slider::new(&mut selection_settings.feather, RangeInclusive::new(0, 50)).prefix("Feather: ").suffix(" px"));
+                    ui.separator();
+                    if ui.button("Select all").clicked() {
+                        ctx.app_state.selection.select_all();
+                    }
+                    if ui.add_enabled(ctx.app_state.selection.is_active(), egui::Button::new("Deselect")).clicked() {
+                        ctx.app_state.selection.deselect();
+                    }
+                    if ui.add_enabled(ctx.app_state.selection.is_active(), egui::Button::new("Invert")).clicked() {
+                        ctx.app_state.selection.invert();
+                    }
+                    ui.label("Shift adds, Alt subtracts, both intersect");
+                });
+                // Magic wand and color range
+                let wand = &mut ctx.app_settings.selection_settings.wand;
+                let mut is_color_range_clicked = false;
+                ui.horizontal(|ui| {
+                    ui.add(percent_slider(&mut wand.tolerance, RangeInclusive::new(0., 100.), "Tolerance: "));
+                    ui.checkbox(&mut wand.contiguous, "Contiguous");
+                    ui.checkbox(&mut wand.anti_alias, "Anti-alias");
+                    egui::ComboBox::from_id_salt("wand_sample")
+                        .selected_text(format!("Sample: {}", wand.sample))
+                        .show_ui(ui, |ui| {
+                            for sample in FillSample::ALL {
+                                ui.selectable_value(&mut wand.sample, sample, sample.to_string());
+                            }
+                        });
+                    is_color_range_clicked = ui.button("Select color range").on_hover_text("Selects every pixel close to the current color").clicked();
+                });
+                if is_color_range_clicked {
+                    let color = ctx.app_state.current_color.clone().unwrap_or_default().color;
+                    let mode = SelectionMode::from_modifiers(ui.input(|i| i.modifiers), ctx.app_settings.selection_settings.mode);
+                    ctx.app_state.select_color_range(color, &ctx.app_settings.selection_settings, mode, layer_size);
+                }
+                return;
+            }
+            let brush = &mut ctx.app_state.current_brush;
             ui.horizontal(|ui| {
-                let stroke_width_slider_sense= ui.add(egui::Slider::new(&mut ctx.app_state.current_stroke_width, RangeInclusive::new(1., 50.)));
+                let stroke_width_slider_sense= ui.add(egui::Slider::new(&mut brush.size, RangeInclusive::new(1., 50.)));
                     if stroke_width_slider_sense.changed() {
-                        ctx.app_settings.pencil_cursor.set_radius(ctx.app_state.current_stroke_width);
+                        ctx.app_settings.pencil_cursor.set_radius(brush.size);
                     }
+                    ui.add(percent_slider(&mut brush.spacing, RangeInclusive::new(5., 200.), "Spacing: "));
+                    ui.add(percent_slider(&mut brush.opacity, RangeInclusive::new(0., 100.), "Opacity: "));
+                    ui.add(percent_slider(&mut brush.flow, RangeInclusive::new(1., 100.), "Flow: "));
+                    ui.add(percent_slider(&mut brush.hardness, RangeInclusive::new(0., 100.), "Hardness: "));
+                    ui.checkbox(&mut ctx.app_settings.stroke_settings.smoothing, "Smoothing");
                 }
             );
+            ui.horizontal(|ui| {
+                ui.add(percent_slider(&mut brush.roundness, RangeInclusive::new(5., 100.), "Roundness: "));
+                ui.add(egui::Slider::new(&mut brush.angle, RangeInclusive::new(-90., 90.)).prefix("Angle: ").suffix("°").fixed_decimals(0));
+                ui.add(percent_slider(&mut brush.size_jitter, RangeInclusive::new(0., 100.), "Size jitter: "));
+                ui.add(egui::Slider::new(&mut brush.angle_jitter, RangeInclusive::new(0., 180.)).prefix("Angle jitter: ").suffix("°").fixed_decimals(0));
+                ui.add(percent_slider(&mut brush.position_jitter, RangeInclusive::new(0., 100.), "Position jitter: "));
+                ui.add(percent_slider(&mut brush.scatter, RangeInclusive::new(0., 300.), "Scatter: "));
+            });
+            ui.horizontal(|ui| {
+                let tip_name = ctx.app_state.current_brush.tip.as_ref().map(|tip| tip.name()).unwrap_or_else(|| "Round".to_string());
+                ui.label(format!("Tip: {tip_name}"));
+                if ui.button("Load tip").clicked() && let Err(error) = ctx.load_brush_tip() {
+                    ctx.app_settings.notification_widget.error(format!("Failed to load brush tip: {error}"));
+                }
+                if ui.add_enabled(ctx.app_state.current_brush.tip.is_some(), egui::Button::new("Round tip")).clicked() {
+                    ctx.app_state.current_brush.tip = None;
+                }
+            });
         });
         
     }
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use egui::{Color32, Id, Pos2, Vec2};
+use rand::random_range;
+
+use crate::app::components::utils::new_rand_id;
+
+const USER_PRESETS_FILE: &str = "brush_presets.txt";
+
+/// Grayscale image stamped instead of the round tip.
+#[derive(Clone, Debug)]
+pub struct BrushTip {
+    pub path: PathBuf,
+    pub size: [usize; 2],
+    // Coverage per pixel, 0.0 to 1.0
+    coverage: Arc<Vec<f32>>
+}
+
+// Tips are compared every frame to highlight the active preset, so skip the pixels.
+impl PartialEq for BrushTip {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.size == other.size
+    }
+}
+
+impl BrushTip {
+    /// Loads a tip from an image file. Images with transparency paint where they are opaque,
+    /// otherwise dark pixels paint and white stays clear, like a brush mark on paper.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let image = image::ImageReader::open(path)?.decode().map_err(std::io::Error::other)?.to_luma_alpha8();
+        let size = [image.width() as usize, image.height() as usize];
+        if size[0] == 0 || size[1] == 0 {
+            return Err(std::io::Error::other("Brush tip image is empty"));
+        }
+        let has_alpha = image.pixels().any(|pixel| pixel.0[1] < 255);
+        let coverage = image.pixels()
+            .map(|pixel| {
+                let [luma, alpha] = pixel.0;
+                if has_alpha { alpha as f32 / 255. } else { 1. - luma as f32 / 255. }
+            })
+            .collect();
+        Ok(Self { path: path.to_path_buf(), size, coverage: Arc::new(coverage) })
+    }
+
+    pub fn name(&self) -> String {
+        self.path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    /// Bilinear sample at `uv`, where 0..1 spans the image and anything outside is clear.
+    fn sample(&self, uv: Vec2) -> f32 {
+        let [width, height] = self.size;
+        let x = uv.x * width as f32 - 0.5;
+        let y = uv.y * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |tx: f32, ty: f32| -> f32 {
+            if tx < 0. || ty < 0. || tx >= width as f32 || ty >= height as f32 {
+                0.
+            } else {
+                self.coverage[ty as usize * width + tx as usize]
+            }
+        };
+        let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1., y0) * fx;
+        let bottom = texel(x0, y0 + 1.) * (1. - fx) + texel(x0 + 1., y0 + 1.) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+}
+
+/// Data-driven description of the dabs a stroke lays down.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Brush {
+    pub size: f32,
+    // Distance between dabs as a fraction of the size
+    pub spacing: f32,
+    // Most coverage a single stroke can build up, 0.0 to 1.0
+    pub opacity: f32,
+    // Coverage each dab adds, 0.0 to 1.0
+    pub flow: f32,
+    // 0.0 is fully soft, 1.0 only anti-aliases the edge
+    pub hardness: f32,
+    // Random size reduction as a fraction of the size
+    pub size_jitter: f32,
+    // Random rotation in degrees, either way
+    pub angle_jitter: f32,
+    // Random offset in any direction as a fraction of the size
+    pub position_jitter: f32,
+    // Random offset across the stroke direction as a fraction of the size
+    pub scatter: f32,
+    // Height of the tip relative to its width
+    pub roundness: f32,
+    // Tip rotation in degrees
+    pub angle: f32,
+    // Image stamped instead of the round tip, hardness doesn't apply to it
+    pub tip: Option<BrushTip>
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            size: 10.,
+            spacing: 0.25,
+            opacity: 1.,
+            flow: 1.,
+            hardness: 0.8,
+            size_jitter: 0.,
+            angle_jitter: 0.,
+            position_jitter: 0.,
+            scatter: 0.,
+            roundness: 1.,
+            angle: 0.,
+            tip: None
+        }
+    }
+}
+
+/// One stamp of the brush tip, with the jitter already applied.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Dab {
+    pub pos: Pos2,
+    pub size: f32,
+    // Radians
+    pub angle: f32
+}
+
+impl Brush {
+    /// Dab spacing in pixels.
+    pub fn spacing_px(&self) -> f32 {
+        (self.size * self.spacing).max(1.)
+    }
+
+    /// Jitters a dab placed at `pos` while the stroke heads along `direction`.
+    pub fn dab(&self, pos: Pos2, direction: Vec2) -> Dab {
+        let mut dab = Dab { pos, size: self.size, angle: self.angle.to_radians() };
+        if self.size_jitter > 0. {
+            dab.size *= 1. - self.size_jitter.clamp(0., 1.) * random_range(0.0..1.0);
+        }
+        if self.angle_jitter > 0. {
+            dab.angle += self.angle_jitter.to_radians() * random_range(-1.0..1.0);
+        }
+        if self.position_jitter > 0. {
+            // sqrt keeps the offsets evenly spread over the disc instead of bunched in the middle
+            let distance = self.position_jitter * self.size * random_range(0.0f32..1.0).sqrt();
+            dab.pos += Vec2::angled(random_range(0.0..std::f32::consts::TAU)) * distance;
+        }
+        if self.scatter > 0. {
+            let across = if direction.length_sq() > 0. { direction.normalized().rot90() } else { Vec2::angled(random_range(0.0..std::f32::consts::TAU)) };
+            dab.pos += across * self.scatter * self.size * random_range(-1.0..1.0);
+        }
+        dab
+    }
+
+    /// How much of a pixel at `offset` from the dab center the tip covers, before flow.
+    pub fn tip_coverage(&self, dab: &Dab, offset: Vec2) -> f32 {
+        let radius = dab.size / 2.;
+        let (sin, cos) = dab.angle.sin_cos();
+        let along = offset.x * cos + offset.y * sin;
+        let across = (offset.y * cos - offset.x * sin) / self.roundness.clamp(0.05, 1.);
+        if let Some(tip) = &self.tip {
+            // The longer side of the image spans the dab size
+            let scale = dab.size / tip.size[0].max(tip.size[1]) as f32;
+            let uv = Vec2::new(along / (tip.size[0] as f32 * scale) + 0.5, across / (tip.size[1] as f32 * scale) + 0.5);
+            return tip.sample(uv);
+        }
+        let dist = (along * along + across * across).sqrt();
+
+        // Smooth falloff from `radius * hardness` outwards, times a one pixel anti-aliased edge
+        let edge = (radius - dist + 0.5).clamp(0., 1.);
+        let hard_radius = radius * self.hardness.clamp(0., 1.);
+        if dist <= hard_radius || radius <= hard_radius {
+            return edge;
+        }
+        let falloff = (1. - (dist - hard_radius) / (radius - hard_radius)).clamp(0., 1.);
+        edge * falloff * falloff * (3. - 2. * falloff)
+    }
+}
+
+/// Coverage the stroke in progress has built up per pixel, next to the pixel color from before the stroke.
+/// Flow builds coverage up dab by dab while opacity caps what the whole stroke can reach.
+#[derive(Clone, PartialEq, Default)]
+pub struct StrokeCoverage {
+    pixels: HashMap<usize, (Color32, f32)>
+}
+
+impl StrokeCoverage {
+    /// Adds `amount` to the pixel at `idx` and returns its original color and total coverage.
+    pub fn add(&mut self, idx: usize, current: Color32, amount: f32) -> (Color32, f32) {
+        let (original, coverage) = self.pixels.entry(idx).or_insert((current, 0.));
+        *coverage += amount * (1. - *coverage);
+        (*original, *coverage)
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.clear();
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct BrushPreset {
+    pub id: Id,
+    pub name: String,
+    pub brush: Brush,
+    // Built-in presets can't be deleted and are never written to disk
+    pub is_user: bool
+}
+
+impl BrushPreset {
+    fn built_in(name: &str, brush: Brush) -> Self {
+        Self { id: Id::new(name), name: name.to_string(), brush, is_user: false }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct BrushPresets {
+    pub presets: Vec<BrushPreset>
+}
+
+impl Default for BrushPresets {
+    fn default() -> Self {
+        let round = Brush::default();
+        Self {
+            presets: vec![
+                BrushPreset::built_in("Round", round.clone()),
+                BrushPreset::built_in("Soft", Brush { size: 30., spacing: 0.15, flow: 0.5, hardness: 0., ..round.clone() }),
+                BrushPreset::built_in("Airbrush", Brush { size: 40., spacing: 0.1, flow: 0.08, hardness: 0., ..round.clone() }),
+                BrushPreset::built_in("Ink", Brush { size: 6., spacing: 0.1, hardness: 1., ..round.clone() }),
+                BrushPreset::built_in("Calligraphy", Brush { size: 16., spacing: 0.1, hardness: 0.9, roundness: 0.25, angle: 45., ..round.clone() }),
+                BrushPreset::built_in("Scatter", Brush {
+                    size: 12.,
+                    spacing: 1.,
+                    hardness: 0.6,
+                    size_jitter: 0.5,
+                    angle_jitter: 180.,
+                    position_jitter: 0.2,
+                    scatter: 1.5,
+                    ..round
+                })
+            ]
+        }
+    }
+}
+
+fn user_presets_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("painting").join(USER_PRESETS_FILE))
+}
+
+/// Every saved brush setting with its key in the presets file and the range of its tool bar slider.
+fn brush_fields(brush: &mut Brush) -> [(&'static str, &mut f32, RangeInclusive<f32>); 11] {
+    [
+        ("size", &mut brush.size, 1.0..=50.),
+        ("spacing", &mut brush.spacing, 0.05..=2.),
+        ("opacity", &mut brush.opacity, 0.0..=1.),
+        ("flow", &mut brush.flow, 0.01..=1.),
+        ("hardness", &mut brush.hardness, 0.0..=1.),
+        ("size_jitter", &mut brush.size_jitter, 0.0..=1.),
+        ("angle_jitter", &mut brush.angle_jitter, 0.0..=180.),
+        ("position_jitter", &mut brush.position_jitter, 0.0..=1.),
+        ("scatter", &mut brush.scatter, 0.0..=3.),
+        ("roundness", &mut brush.roundness, 0.05..=1.),
+        ("angle", &mut brush.angle, -90.0..=90.)
+    ]
+}
+
+impl BrushPresets {
+    pub fn user_presets(&self) -> impl Iterator<Item = &BrushPreset> {
+        self.presets.iter().filter(|preset| preset.is_user)
+    }
+
+    /// Adds a user preset, replacing any user preset with the same name, and writes them to disk.
+    pub fn save_preset(&mut self, name: &str, brush: Brush) -> std::io::Result<()> {
+        let name = name.trim();
+        // Names are written as `[name]` headers, one line each
+        if name.contains(|c: char| c == ']' || c.is_control()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Preset names can't contain ] or line breaks"));
+        }
+        let name = if name.is_empty() { format!("Preset {}", self.user_presets().count() + 1) } else { name.to_string() };
+        match self.presets.iter_mut().find(|preset| preset.is_user && preset.name == name) {
+            Some(preset) => preset.brush = brush,
+            None => self.presets.push(BrushPreset { id: new_rand_id(), name, brush, is_user: true })
+        }
+        self.write_user_presets()
+    }
+
+    pub fn delete_preset(&mut self, id: Id) -> std::io::Result<()> {
+        self.presets.retain(|preset| !(preset.is_user && preset.id == id));
+        self.write_user_presets()
+    }
+
+    /// Reads user presets saved by earlier sessions; a missing file just means there are none yet. Returns
+    /// why any tip image couldn't be loaded, as those presets still load with the round tip.
+    pub fn load_user_presets(&mut self) -> std::io::Result<Vec<String>> {
+        let Some(path) = user_presets_path() else {
+            return Ok(Vec::new());
+        };
+        match File::open(path) {
+            Ok(file) => self.read_user_presets(BufReader::new(file)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn read_user_presets(&mut self, reader: impl BufRead) -> std::io::Result<Vec<String>> {
+        let mut tip_errors = Vec::new();
+        self.presets.retain(|preset| !preset.is_user);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                self.presets.push(BrushPreset { id: new_rand_id(), name: name.to_string(), brush: Brush::default(), is_user: true });
+            } else if let Some((key, value)) = line.split_once('=')
+                && let Some(preset) = self.presets.last_mut().filter(|preset| preset.is_user)
+            {
+                if key.trim() == "tip" {
+                    // A tip image that moved or was deleted leaves the preset with the round tip
+                    match BrushTip::load(Path::new(value.trim())) {
+                        Ok(tip) => preset.brush.tip = Some(tip),
+                        Err(error) => tip_errors.push(format!("Failed to load brush tip for {}: {error}", preset.name))
+                    }
+                } else if let Some((_, field, range)) = brush_fields(&mut preset.brush).into_iter().find(|(field_key, _, _)| *field_key == key.trim())
+                    && let Ok(value) = value.trim().parse::<f32>()
+                    && value.is_finite()
+                {
+                    // Hand-edited files keep to what the tool bar could have set
+                    *field = value.clamp(*range.start(), *range.end());
+                }
+            }
+        }
+        Ok(tip_errors)
+    }
+
+    fn write_user_presets(&self) -> std::io::Result<()> {
+        let path = user_presets_path().ok_or_else(|| std::io::Error::other("No config directory for brush presets"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_user_presets_to(&mut writer)?;
+        writer.flush()
+    }
+
+    fn write_user_presets_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for preset in self.user_presets() {
+            writeln!(writer, "[{}]", preset.name)?;
+            let mut brush = preset.brush.clone();
+            if let Some(tip) = &brush.tip {
+                writeln!(writer, "tip={}", tip.path.display())?;
+            }
+            for (key, value, _) in brush_fields(&mut brush) {
+                writeln!(writer, "{key}={value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_names_that_break_the_file_are_rejected() {
+        let mut presets = BrushPresets::default();
+        let count = presets.presets.len();
+        for name in ["Ink]", "Two\nlines", "Tab\there"] {
+            let error = presets.save_preset(name, Brush::default()).err().unwrap();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(presets.presets.len(), count);
+    }
+
+    fn user_presets(presets: &BrushPresets) -> Vec<(String, Brush)> {
+        presets.user_presets().map(|preset| (preset.name.clone(), preset.brush.clone())).collect()
+    }
+
+    #[test]
+    fn user_presets_round_trip() {
+        let mut presets = BrushPresets::default();
+        let built_in = presets.presets.len();
+        presets.presets.push(BrushPreset { id: new_rand_id(), name: "Dry ink".to_string(), brush: Brush { size: 7.5, spacing: 0.3, hardness: 0.8, angle: -30., ..Default::default() }, is_user: true });
+        presets.presets.push(BrushPreset { id: new_rand_id(), name: "Mist [wide]".to_string(), brush: Brush { size: 48., flow: 0.05, scatter: 2.5, roundness: 0.4, angle_jitter: 90., ..Default::default() }, is_user: true });
+        let mut bytes = Vec::new();
+        presets.write_user_presets_to(&mut bytes).unwrap();
+
+        let mut read = BrushPresets::default();
+        let tip_errors = read.read_user_presets(bytes.as_slice()).unwrap();
+        assert!(tip_errors.is_empty());
+        assert_eq!(read.presets.len(), built_in + 2);
+        assert_eq!(user_presets(&read), user_presets(&presets));
+    }
+
+    #[test]
+    fn loaded_values_stay_in_slider_range() {
+        let file = "[Broken]\nsize=inf\nspacing=NaN\nopacity=-1\nhardness=4\nangle=-400\nscatter=1e9\nflow=soft\n";
+        let mut presets = BrushPresets::default();
+        presets.read_user_presets(file.as_bytes()).unwrap();
+        let brush = &presets.user_presets().next().unwrap().brush;
+        let default = Brush::default();
+        assert_eq!(brush.size, default.size);
+        assert_eq!(brush.spacing, default.spacing);
+        assert_eq!(brush.flow, default.flow);
+        assert_eq!(brush.opacity, 0.);
+        assert_eq!(brush.hardness, 1.);
+        assert_eq!(brush.angle, -90.);
+        assert_eq!(brush.scatter, 3.);
+    }
+
+    #[test]
+    fn missing_tips_are_reported_and_the_preset_still_loads() {
+        let mut presets = BrushPresets::default();
+        let tip_errors = presets.read_user_presets("[Stamp]\ntip=/no/such/tip.png\nsize=20\n".as_bytes()).unwrap();
+        assert_eq!(tip_errors.len(), 1);
+        let preset = presets.user_presets().next().unwrap();
+        assert_eq!(preset.brush.size, 20.);
+        assert!(preset.brush.tip.is_none());
+    }
+}
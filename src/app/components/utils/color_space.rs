@@ -0,0 +1,167 @@
+use egui::Color32;
+
+/// Largest OKLCH chroma the sliders offer; every sRGB color stays below it.
+pub const MAX_OKLCH_CHROMA: f32 = 0.37;
+
+/// sRGB from hue, saturation and value, everything 0-1.
+pub fn hsv_to_rgb([hue, saturation, value]: [f32; 3]) -> [f32; 3] {
+    let channel = |n: f32| {
+        let k = (n + hue.rem_euclid(1.) * 6.) % 6.;
+        value - value * saturation * k.min(4. - k).clamp(0., 1.)
+    };
+    [channel(5.), channel(3.), channel(1.)]
+}
+
+/// Hue, saturation and value of an sRGB color, everything 0-1. Greys get hue 0.
+pub fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta <= 0. {
+        0.
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.)
+    } else if max == g {
+        (b - r) / delta + 2.
+    } else {
+        (r - g) / delta + 4.
+    };
+    let saturation = if max > 0. { delta / max } else { 0. };
+    [hue / 6., saturation, max]
+}
+
+pub fn hsv_to_hsl([hue, saturation, value]: [f32; 3]) -> [f32; 3] {
+    let lightness = value * (1. - saturation / 2.);
+    let min = lightness.min(1. - lightness);
+    [hue, if min > 0. { (value - lightness) / min } else { 0. }, lightness]
+}
+
+pub fn hsl_to_hsv([hue, saturation, lightness]: [f32; 3]) -> [f32; 3] {
+    let value = lightness + saturation * lightness.min(1. - lightness);
+    [hue, if value > 0. { 2. * (1. - lightness / value) } else { 0. }, value]
+}
+
+fn to_linear(channel: f32) -> f32 {
+    if channel <= 0.04045 { channel / 12.92 } else { ((channel + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn from_linear(channel: f32) -> f32 {
+    if channel <= 0.0031308 { channel * 12.92 } else { 1.055 * channel.powf(1. / 2.4) - 0.055 }
+}
+
+/// Lightness (0-1), chroma and hue in degrees of an sRGB color in OKLCH, the perceptual space where
+/// equal steps look like equal changes.
+pub fn rgb_to_oklch(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(to_linear);
+    let l = (0.41222146 * r + 0.53633255 * g + 0.051445995 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171885 * g + 0.6299787 * b).cbrt();
+    let lightness = 0.21045426 * l + 0.7936178 * m - 0.004072047 * s;
+    let a = 1.9779985 * l - 2.4285922 * m + 0.4505937 * s;
+    let b = 0.025904037 * l + 0.78277177 * m - 0.80867577 * s;
+    [lightness, a.hypot(b), b.atan2(a).to_degrees().rem_euclid(360.)]
+}
+
+/// Inverse of `rgb_to_oklch`, with channels outside the sRGB gamut clipped.
+pub fn oklch_to_rgb([lightness, chroma, hue]: [f32; 3]) -> [f32; 3] {
+    let (sin, cos) = hue.to_radians().sin_cos();
+    let (a, b) = (chroma * cos, chroma * sin);
+    let l = (lightness + 0.39633778 * a + 0.21580376 * b).powi(3);
+    let m = (lightness - 0.105561346 * a - 0.06385417 * b).powi(3);
+    let s = (lightness - 0.08948418 * a - 1.2914855 * b).powi(3);
+    [
+        4.0767417 * l - 3.3077116 * m + 0.23096994 * s,
+        -1.268438 * l + 2.6097574 * m - 0.34131938 * s,
+        -0.0041960864 * l - 0.7034186 * m + 1.7076147 * s
+    ].map(|channel| from_linear(channel.clamp(0., 1.)))
+}
+
+/// `#rrggbb`, with the alpha appended when the color isn't opaque.
+pub fn to_hex(color: Color32) -> String {
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    if a == 255 { format!("#{r:02x}{g:02x}{b:02x}") } else { format!("#{r:02x}{g:02x}{b:02x}{a:02x}") }
+}
+
+/// Reads `#rgb`, `#rgba`, `#rrggbb` and `#rrggbbaa` (the `#` is optional) as well as CSS `rgb()` and
+/// `rgba()` with numbers or percentages.
+pub fn parse_color(text: &str) -> Option<Color32> {
+    let text = text.trim().to_ascii_lowercase();
+    if let Some(arguments) = text.strip_prefix("rgba(").or_else(|| text.strip_prefix("rgb(")) {
+        let values = arguments.strip_suffix(')')?
+            .split(|c: char| c == ',' || c == '/' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<&str>>();
+        if values.len() != 3 && values.len() != 4 {
+            return None;
+        }
+        let channel = |value: &str, full: f32| -> Option<u8> {
+            let amount = match value.strip_suffix('%') {
+                Some(percent) => percent.parse::<f32>().ok()? / 100. * 255.,
+                None => value.parse::<f32>().ok()? / full * 255.
+            };
+            Some(amount.round().clamp(0., 255.) as u8)
+        };
+        let [r, g, b] = [values[0], values[1], values[2]].map(|value| channel(value, 255.));
+        // Alpha is a 0-1 fraction in CSS, unlike the color channels
+        let a = values.get(3).map_or(Some(255), |value| channel(value, 1.));
+        return Some(Color32::from_rgba_unmultiplied(r?, g?, b?, a?));
+    }
+
+    let digits = text.strip_prefix('#').unwrap_or(&text);
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes = match digits.len() {
+        3 | 4 => digits.chars().map(|c| u8::from_str_radix(&c.to_string(), 16).ok().map(|nibble| nibble * 17)).collect::<Option<Vec<u8>>>()?,
+        6 | 8 => (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect::<Option<Vec<u8>>>()?,
+        _ => return None
+    };
+    Some(Color32::from_rgba_unmultiplied(bytes[0], bytes[1], bytes[2], bytes.get(3).copied().unwrap_or(255)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(parse_color("#ff8000"), Some(Color32::from_rgb(255, 128, 0)));
+        assert_eq!(parse_color("  FF8000 "), Some(Color32::from_rgb(255, 128, 0)));
+        assert_eq!(parse_color("#f80"), Some(Color32::from_rgb(255, 136, 0)));
+        assert_eq!(parse_color("#f808"), Some(Color32::from_rgba_unmultiplied(255, 136, 0, 136)));
+        assert_eq!(parse_color("#ff800080"), Some(Color32::from_rgba_unmultiplied(255, 128, 0, 128)));
+        for invalid in ["", "#", "#ff", "#ff800", "#ff80000", "#gg8000", "ff 800", "#ffé00"] {
+            assert_eq!(parse_color(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn parses_css_functions() {
+        assert_eq!(parse_color("rgb(255, 128, 0)"), Some(Color32::from_rgb(255, 128, 0)));
+        assert_eq!(parse_color("RGB(100% 50% 0%)"), Some(Color32::from_rgb(255, 128, 0)));
+        assert_eq!(parse_color("rgba(255, 128, 0, 0.5)"), Some(Color32::from_rgba_unmultiplied(255, 128, 0, 128)));
+        assert_eq!(parse_color("rgb(255 128 0 / 50%)"), Some(Color32::from_rgba_unmultiplied(255, 128, 0, 128)));
+        // Out of range values are clamped
+        assert_eq!(parse_color("rgb(300, -5, 0)"), Some(Color32::from_rgb(255, 0, 0)));
+        for invalid in ["rgb(255, 128)", "rgb(255, 128, 0", "rgb(a, b, c)", "rgba(1, 2, 3, 4, 5)"] {
+            assert_eq!(parse_color(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        for color in [Color32::from_rgb(18, 52, 86), Color32::from_rgba_unmultiplied(200, 100, 50, 128)] {
+            assert_eq!(parse_color(&to_hex(color)), Some(color));
+        }
+        assert_eq!(to_hex(Color32::from_rgb(255, 128, 0)), "#ff8000");
+    }
+
+    #[test]
+    fn color_spaces_round_trip() {
+        for rgb in [[1., 0.5, 0.], [0.2, 0.4, 0.9], [0.5, 0.5, 0.5]] {
+            let close = |other: [f32; 3]| rgb.iter().zip(other).all(|(a, b)| (a - b).abs() < 1e-3);
+            assert!(close(hsv_to_rgb(rgb_to_hsv(rgb))));
+            assert!(close(hsv_to_rgb(hsl_to_hsv(hsv_to_hsl(rgb_to_hsv(rgb))))));
+            assert!(close(oklch_to_rgb(rgb_to_oklch(rgb))));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use egui::{Color32, Id, Painter, Rect};
+
+use crate::app::components::utils::image_color::{composite_layers, composite_onto, BlendMode};
+use crate::app::components::utils::layer::{Layer, LayerTexture, LayersContainer};
+use crate::app::components::utils::tiled_image::TiledImage;
+
+/// What a cached composite was built from; any edit, reorder or property change makes it differ.
+#[derive(Clone, PartialEq)]
+struct StackKey {
+    size: [usize; 2],
+    layers: Vec<(Id, u64, BlendMode, u32)>
+}
+
+impl StackKey {
+    fn of(layers: &[&Layer], size: [usize; 2]) -> Self {
+        Self {
+            size,
+            layers: layers.iter().map(|layer| (layer.id, layer.texture.revision(), layer.blend_mode, layer.opacity.to_bits())).collect()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct CachedComposite {
+    key: Option<StackKey>,
+    texture: LayerTexture
+}
+
+impl Default for CachedComposite {
+    fn default() -> Self {
+        Self {
+            key: None,
+            texture: LayerTexture::new(0, 0)
+        }
+    }
+}
+
+impl CachedComposite {
+    /// Rebuilds the composite only when `key` differs from the one it was built from.
+    fn refresh(&mut self, key: StackKey, composite: impl FnOnce() -> TiledImage) {
+        if self.key.as_ref() != Some(&key) {
+            self.texture.replace_image(composite());
+            self.key = Some(key);
+        }
+    }
+}
+
+/// Canvas preview split around the active layer: everything below it and everything above it are
+/// composited once and cached, so painting only re-blends those two images with the active layer.
+#[derive(Clone, PartialEq, Default)]
+pub struct CompositeCache {
+    below: CachedComposite,
+    above: CachedComposite,
+    // Active layer and everything above it blended onto `below`, for when the GPU's source-over isn't enough
+    blended: CachedComposite
+}
+
+impl CompositeCache {
+    pub fn paint(&mut self, ctx: &egui::Context, painter: &Painter, canvas_rect: Rect, container: &mut LayersContainer, active_layer: Option<Id>, size: [usize; 2]) {
+        let active_index = active_layer.and_then(|id| container.layers.iter().position(|layer| layer.id == id && layer.is_visible));
+        let Some(active_index) = active_index else {
+            let visible = container.visible_layers_bottom_up();
+            self.below.refresh(StackKey::of(&visible, size), || composite_layers(&visible, size));
+            self.below.texture.paint(ctx, painter, canvas_rect, Color32::WHITE);
+            return;
+        };
+
+        // `layers` is ordered top-most first
+        let below = container.layers[active_index + 1..].iter().rev().filter(|layer| layer.is_visible).collect::<Vec<&Layer>>();
+        let above = container.layers[..active_index].iter().rev().filter(|layer| layer.is_visible).collect::<Vec<&Layer>>();
+        let active = &container.layers[active_index];
+        self.below.refresh(StackKey::of(&below, size), || composite_layers(&below, size));
+
+        // Source-over is associative, so a Normal active layer under Normal layers can be drawn by the GPU
+        // between the two cached composites. Any other blend mode depends on the pixels below it.
+        if active.blend_mode == BlendMode::Normal && above.iter().all(|layer| layer.blend_mode == BlendMode::Normal) {
+            self.above.refresh(StackKey::of(&above, size), || composite_layers(&above, size));
+            let opacity = active.opacity.clamp(0., 1.);
+            self.below.texture.paint(ctx, painter, canvas_rect, Color32::WHITE);
+            container.layers[active_index].texture.paint(ctx, painter, canvas_rect, Color32::WHITE.gamma_multiply(opacity));
+            self.above.texture.paint(ctx, painter, canvas_rect, Color32::WHITE);
+        } else {
+            let rest = std::iter::once(active).chain(above).collect::<Vec<&Layer>>();
+            let key = StackKey::of(&below.iter().chain(rest.iter()).copied().collect::<Vec<&Layer>>(), size);
+            let below_image = &self.below.texture.image_data;
+            self.blended.refresh(key, || composite_onto(below_image, &rest));
+            self.blended.texture.paint(ctx, painter, canvas_rect, Color32::WHITE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::components::utils::new_rand_id;
+
+    const SIZE: [usize; 2] = [4, 4];
+
+    fn layer(color: Color32) -> Layer {
+        let mut texture = LayerTexture::new(SIZE[0], SIZE[1]);
+        texture.image_data.set(5, color);
+        Layer { id: new_rand_id(), name: String::new(), is_visible: true, blend_mode: BlendMode::Normal, opacity: 1., texture }
+    }
+
+    /// Paints the canvas and returns which cached composites (below, above, blended) were rebuilt.
+    fn paint(cache: &mut CompositeCache, container: &mut LayersContainer, active_layer: Id) -> [bool; 3] {
+        let keys = |cache: &CompositeCache| [cache.below.key.clone(), cache.above.key.clone(), cache.blended.key.clone()];
+        let before = keys(cache);
+        let ctx = egui::Context::default();
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            let painter = ctx.layer_painter(egui::LayerId::background());
+            cache.paint(ctx, &painter, Rect::from_min_size(egui::Pos2::ZERO, egui::Vec2::splat(4.)), container, Some(active_layer), SIZE);
+        });
+        // A composite's key only changes when it is rebuilt
+        let after = keys(cache);
+        [0, 1, 2].map(|i| before[i] != after[i])
+    }
+
+    #[test]
+    fn rebuilds_only_the_composites_that_changed() {
+        // Top-most first: one layer above the active one and one below it
+        let mut container = LayersContainer { layers: vec![layer(Color32::RED), layer(Color32::GREEN), layer(Color32::BLUE)], ..Default::default() };
+        let active = container.layers[1].id;
+        let mut cache = CompositeCache::default();
+        assert_eq!(paint(&mut cache, &mut container, active), [true, true, false]);
+        assert_eq!(paint(&mut cache, &mut container, active), [false, false, false]);
+
+        // Painting on the active layer needs no rebuild at all
+        container.layers[1].texture.mark_dirty(0);
+        assert_eq!(paint(&mut cache, &mut container, active), [false, false, false]);
+
+        container.layers[2].texture.mark_dirty(0);
+        assert_eq!(paint(&mut cache, &mut container, active), [true, false, false]);
+        container.layers[0].texture.mark_dirty(0);
+        assert_eq!(paint(&mut cache, &mut container, active), [false, true, false]);
+
+        container.layers[2].is_visible = false;
+        assert_eq!(paint(&mut cache, &mut container, active), [true, false, false]);
+        container.layers[0].is_visible = false;
+        assert_eq!(paint(&mut cache, &mut container, active), [false, true, false]);
+        container.layers[0].is_visible = true;
+        container.layers[2].is_visible = true;
+        assert_eq!(paint(&mut cache, &mut container, active), [true, true, false]);
+
+        // A blend mode above the active layer moves it and everything above into the blended composite
+        container.layers[0].blend_mode = BlendMode::Multiply;
+        assert_eq!(paint(&mut cache, &mut container, active), [false, false, true]);
+        assert_eq!(paint(&mut cache, &mut container, active), [false, false, false]);
+        container.layers[1].texture.mark_dirty(0);
+        assert_eq!(paint(&mut cache, &mut container, active), [false, false, true]);
+        container.layers[2].blend_mode = BlendMode::Screen;
+        assert_eq!(paint(&mut cache, &mut container, active), [true, false, true]);
+    }
+}
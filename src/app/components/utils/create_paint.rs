@@ -1,3 +1,6 @@
+// Largest canvas side, for new canvases and for files opened from disk
+pub const MAX_CANVAS_SIZE: usize = 8192;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NewPaintSetting {
     pub width: usize,
@@ -8,12 +8,58 @@ use crate::app::components::utils::new_rand_id;
 pub enum Pencil {
     Brush,
     Pen,
-    Eraser
+    Eraser,
+    Fill,
+    Line,
+    Rect,
+    Ellipse,
+    Polygon,
+    SelectRect,
+    SelectEllipse,
+    Lasso,
+    PolygonLasso,
+    MagicWand,
+    Move,
+    Eyedropper
+}
+
+impl Pencil {
+    pub fn is_shape(&self) -> bool {
+        matches!(self, Pencil::Line | Pencil::Rect | Pencil::Ellipse | Pencil::Polygon)
+    }
+
+    pub fn is_selection(&self) -> bool {
+        matches!(self, Pencil::SelectRect | Pencil::SelectEllipse | Pencil::Lasso | Pencil::PolygonLasso | Pencil::MagicWand)
+    }
+
+    /// Tools that put down the current color, which Alt switches to the eyedropper. Selection tools
+    /// keep Alt for subtracting.
+    pub fn picks_with_alt(&self) -> bool {
+        matches!(self, Pencil::Brush | Pencil::Pen | Pencil::Fill)
+    }
+
+    /// Shape tool whose outline a selection tool draws; shape tools draw their own.
+    pub fn outline_shape(&self) -> Pencil {
+        match self {
+            Pencil::SelectRect => Pencil::Rect,
+            Pencil::SelectEllipse => Pencil::Ellipse,
+            Pencil::Lasso | Pencil::PolygonLasso => Pencil::Polygon,
+            _ => *self
+        }
+    }
 }
 
 impl Display for Pencil {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        // Short enough for the tool bar buttons
+        match self {
+            Pencil::SelectRect => write!(f, "Select"),
+            Pencil::SelectEllipse => write!(f, "Oval sel"),
+            Pencil::PolygonLasso => write!(f, "P. lasso"),
+            Pencil::MagicWand => write!(f, "Wand"),
+            Pencil::Eyedropper => write!(f, "Picker"),
+            _ => write!(f, "{:?}", self)
+        }
     }
 }
 
@@ -56,6 +102,18 @@ impl Default for Tools {
         tools.push(brush.clone());
         tools.push(DrawTool::new(Pencil::Pen));
         tools.push(DrawTool::new(Pencil::Eraser));
+        tools.push(DrawTool::new(Pencil::Fill));
+        tools.push(DrawTool::new(Pencil::Line));
+        tools.push(DrawTool::new(Pencil::Rect));
+        tools.push(DrawTool::new(Pencil::Ellipse));
+        tools.push(DrawTool::new(Pencil::Polygon));
+        tools.push(DrawTool::new(Pencil::SelectRect));
+        tools.push(DrawTool::new(Pencil::SelectEllipse));
+        tools.push(DrawTool::new(Pencil::Lasso));
+        tools.push(DrawTool::new(Pencil::PolygonLasso));
+        tools.push(DrawTool::new(Pencil::MagicWand));
+        tools.push(DrawTool::new(Pencil::Move));
+        tools.push(DrawTool::new(Pencil::Eyedropper));
         Self {
             tools
         }
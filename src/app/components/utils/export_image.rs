@@ -0,0 +1,335 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use egui::{Color32, ColorImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+use crate::app::components::utils::image_color::{blend_pixel, to_rgba_image, BlendMode};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    Jpeg,
+    WebP,
+    Bmp,
+    Tiff,
+    Tga
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 6] = [
+        ExportFormat::Png,
+        ExportFormat::Jpeg,
+        ExportFormat::WebP,
+        ExportFormat::Bmp,
+        ExportFormat::Tiff,
+        ExportFormat::Tga
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::WebP => "webp",
+            ExportFormat::Bmp => "bmp",
+            ExportFormat::Tiff => "tiff",
+            ExportFormat::Tga => "tga"
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            ExportFormat::Png => ImageFormat::Png,
+            ExportFormat::Jpeg => ImageFormat::Jpeg,
+            ExportFormat::WebP => ImageFormat::WebP,
+            ExportFormat::Bmp => ImageFormat::Bmp,
+            ExportFormat::Tiff => ImageFormat::Tiff,
+            ExportFormat::Tga => ImageFormat::Tga
+        }
+    }
+
+    /// `name` without an export extension the user typed themselves, since the chosen format adds its own.
+    pub fn file_stem(name: &str) -> &str {
+        let name = name.trim();
+        match name.rsplit_once('.') {
+            Some((stem, extension)) if ExportFormat::ALL.iter().any(|format| format.extension().eq_ignore_ascii_case(extension))
+                || ["jpeg", "tif"].iter().any(|alias| alias.eq_ignore_ascii_case(extension)) => stem.trim_end(),
+            _ => name
+        }
+    }
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ExportFormat::Png => "PNG",
+            ExportFormat::Jpeg => "JPEG",
+            ExportFormat::WebP => "WebP",
+            ExportFormat::Bmp => "BMP",
+            ExportFormat::Tiff => "TIFF",
+            ExportFormat::Tga => "TGA"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Largest width or height a scaled export may have.
+pub const MAX_EXPORT_SIZE: usize = 16384;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ResampleFilter {
+    Nearest,
+    Bilinear,
+    #[default]
+    Bicubic,
+    Lanczos
+}
+
+impl ResampleFilter {
+    pub const ALL: [ResampleFilter; 4] = [
+        ResampleFilter::Nearest,
+        ResampleFilter::Bilinear,
+        ResampleFilter::Bicubic,
+        ResampleFilter::Lanczos
+    ];
+
+    fn filter_type(&self) -> FilterType {
+        match self {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Bilinear => FilterType::Triangle,
+            ResampleFilter::Bicubic => FilterType::CatmullRom,
+            ResampleFilter::Lanczos => FilterType::Lanczos3
+        }
+    }
+}
+
+impl Display for ResampleFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ResampleFilter::Nearest => "Nearest",
+            ResampleFilter::Bilinear => "Bilinear",
+            ResampleFilter::Bicubic => "Bicubic",
+            ResampleFilter::Lanczos => "Lanczos"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Part of the canvas to export, in canvas pixels.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ExportRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl ExportRegion {
+    /// The part of the region that lies on a canvas of `size`; `None` when nothing does.
+    pub fn clamped(&self, size: [usize; 2]) -> Option<ExportRegion> {
+        let x = self.x.min(size[0]);
+        let y = self.y.min(size[1]);
+        let width = self.width.min(size[0] - x);
+        let height = self.height.min(size[1] - y);
+        (width > 0 && height > 0).then_some(ExportRegion { x, y, width, height })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    // 1-100, JPEG only
+    pub jpeg_quality: u8,
+    // Output size relative to the exported region, 1.0 being 100%
+    pub scale: f32,
+    pub filter: ResampleFilter,
+    pub keep_transparency: bool,
+    // Flattened under the image when transparency isn't kept
+    pub background: Color32,
+    // Crop rect; `None` exports the whole canvas
+    pub region: Option<ExportRegion>,
+    // Crops to the selection and leaves unselected pixels transparent, replacing `region`
+    pub selection_only: bool,
+    pub per_layer: bool,
+    // PNG only: an 8-bit palette image instead of RGBA
+    pub indexed: bool
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::default(),
+            jpeg_quality: 90,
+            scale: 1.,
+            filter: ResampleFilter::default(),
+            keep_transparency: true,
+            background: Color32::WHITE,
+            region: None,
+            selection_only: false,
+            per_layer: false,
+            indexed: false
+        }
+    }
+}
+
+impl ExportOptions {
+    /// Crops, scales and flattens a canvas-sized composite the way these options ask for.
+    pub fn apply(&self, image: &ColorImage) -> std::io::Result<ColorImage> {
+        let image = match self.region {
+            Some(region) => {
+                let region = region.clamped(image.size)
+                    .ok_or_else(|| std::io::Error::other("The export region lies outside the canvas"))?;
+                crop(image, region)
+            },
+            None => image.clone()
+        };
+        let size = image.size.map(|side| ((side as f32 * self.scale).round() as usize).max(1));
+        if size.iter().any(|side| *side > MAX_EXPORT_SIZE) {
+            return Err(std::io::Error::other(format!("A {}x{} export is larger than {MAX_EXPORT_SIZE} px", size[0], size[1])));
+        }
+        let mut image = resize(&image, size, self.filter);
+        if !self.keep_transparency {
+            for pixel in image.pixels.iter_mut() {
+                *pixel = blend_pixel(self.background, *pixel, BlendMode::Normal);
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn crop(image: &ColorImage, region: ExportRegion) -> ColorImage {
+    let width = image.size[0];
+    let pixels = (region.y..region.y + region.height)
+        .flat_map(|y| image.pixels[y * width + region.x..y * width + region.x + region.width].iter().copied())
+        .collect();
+    ColorImage::new([region.width, region.height], pixels)
+}
+
+/// Resamples the premultiplied pixels directly so transparent neighbours don't bleed their color into edges.
+fn resize(image: &ColorImage, size: [usize; 2], filter: ResampleFilter) -> ColorImage {
+    if image.size == size {
+        return image.clone();
+    }
+    let raw = image.pixels.iter().flat_map(|pixel| pixel.to_array()).collect::<Vec<u8>>();
+    let Some(source) = RgbaImage::from_raw(image.size[0] as u32, image.size[1] as u32, raw) else {
+        return image.clone();
+    };
+    let resized = image::imageops::resize(&source, size[0] as u32, size[1] as u32, filter.filter_type());
+    // Sharpening filters can overshoot a channel past its alpha, which premultiplied colors can't hold
+    let pixels = resized.pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            Color32::from_rgba_premultiplied(r.min(a), g.min(a), b.min(a), a)
+        })
+        .collect();
+    ColorImage::new(size, pixels)
+}
+
+/// Name for a file holding a single layer: `base` followed by the layer name, limited to characters
+/// every file system accepts.
+pub fn layer_file_stem(base: &str, layer_name: &str) -> String {
+    let layer_name = layer_name.trim().chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' })
+        .collect::<String>();
+    format!("{base}_{layer_name}")
+}
+
+/// Writes `image` to `path`. `jpeg_quality` (1-100) only applies to JPEG, which has no alpha channel,
+/// so transparent areas are flattened onto white there instead of turning black.
+pub fn write_image(image: &ColorImage, path: &Path, format: ExportFormat, jpeg_quality: u8) -> std::io::Result<()> {
+    match format {
+        ExportFormat::Jpeg => {
+            let flattened = ColorImage::new(image.size, image.pixels.iter().map(|pixel| blend_pixel(Color32::WHITE, *pixel, BlendMode::Normal)).collect());
+            let rgb = DynamicImage::ImageRgba8(to_rgba_image(&flattened)).to_rgb8();
+            let writer = BufWriter::new(File::create(path)?);
+            JpegEncoder::new_with_quality(writer, jpeg_quality.clamp(1, 100)).encode_image(&rgb).map_err(std::io::Error::other)
+        },
+        _ => DynamicImage::ImageRgba8(to_rgba_image(image)).save_with_format(path, format.image_format()).map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_stem_drops_export_extensions_only() {
+        assert_eq!(ExportFormat::file_stem("poster.png"), "poster");
+        assert_eq!(ExportFormat::file_stem(" poster .TIF "), "poster");
+        assert_eq!(ExportFormat::file_stem("poster.jpeg"), "poster");
+        assert_eq!(ExportFormat::file_stem("poster.final.webp"), "poster.final");
+        assert_eq!(ExportFormat::file_stem("poster.v2"), "poster.v2");
+        assert_eq!(ExportFormat::file_stem("poster"), "poster");
+        assert_eq!(ExportFormat::file_stem(".png"), "");
+    }
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> ExportRegion {
+        ExportRegion { x, y, width, height }
+    }
+
+    // Pixel value encodes its position, so crops can be checked
+    fn numbered_image(size: [usize; 2]) -> ColorImage {
+        ColorImage::new(size, (0..size[0] * size[1]).map(|idx| Color32::from_rgb((idx % size[0]) as u8, (idx / size[0]) as u8, 0)).collect())
+    }
+
+    #[test]
+    fn regions_are_clamped_to_the_canvas() {
+        assert_eq!(region(2, 3, 4, 5).clamped([10, 10]), Some(region(2, 3, 4, 5)));
+        assert_eq!(region(6, 8, 10, 10).clamped([10, 10]), Some(region(6, 8, 4, 2)));
+        assert_eq!(region(10, 0, 5, 5).clamped([10, 10]), None);
+        assert_eq!(region(0, 12, 5, 5).clamped([10, 10]), None);
+        assert_eq!(region(3, 3, 0, 4).clamped([10, 10]), None);
+    }
+
+    #[test]
+    fn regions_outside_the_canvas_fail_to_export() {
+        let options = ExportOptions { region: Some(region(20, 20, 5, 5)), ..Default::default() };
+        assert!(options.apply(&numbered_image([10, 10])).is_err());
+    }
+
+    #[test]
+    fn scaling_resizes_the_output() {
+        let options = ExportOptions { scale: 2., filter: ResampleFilter::Nearest, ..Default::default() };
+        let scaled = options.apply(&numbered_image([10, 6])).unwrap();
+        assert_eq!(scaled.size, [20, 12]);
+        assert_eq!(scaled.pixels[3 * 20 + 5], Color32::from_rgb(2, 1, 0));
+
+        let too_large = ExportOptions { scale: 4000., ..Default::default() };
+        assert!(too_large.apply(&numbered_image([10, 6])).is_err());
+    }
+
+    #[test]
+    fn crop_then_scale() {
+        let options = ExportOptions { region: Some(region(2, 4, 30, 3)), scale: 1.5, filter: ResampleFilter::Nearest, ..Default::default() };
+        let exported = options.apply(&numbered_image([10, 10])).unwrap();
+        // The region is clamped to 8x3 before scaling
+        assert_eq!(exported.size, [12, 5]);
+        assert_eq!(exported.pixels[0], Color32::from_rgb(2, 4, 0));
+
+        let cropped = crop(&numbered_image([10, 10]), region(2, 4, 3, 2));
+        assert_eq!(cropped.size, [3, 2]);
+        assert_eq!(cropped.pixels, vec![
+            Color32::from_rgb(2, 4, 0), Color32::from_rgb(3, 4, 0), Color32::from_rgb(4, 4, 0),
+            Color32::from_rgb(2, 5, 0), Color32::from_rgb(3, 5, 0), Color32::from_rgb(4, 5, 0)
+        ]);
+    }
+
+    #[test]
+    fn flattening_fills_transparency_with_the_background() {
+        let image = ColorImage::new([2, 1], vec![Color32::TRANSPARENT, Color32::RED]);
+        let options = ExportOptions { keep_transparency: false, background: Color32::BLUE, ..Default::default() };
+        assert_eq!(options.apply(&image).unwrap().pixels, vec![Color32::BLUE, Color32::RED]);
+    }
+
+    #[test]
+    fn layer_file_stems_are_safe_file_names() {
+        assert_eq!(layer_file_stem("art", " Sky layer "), "art_Sky layer");
+        assert_eq!(layer_file_stem("art", "a/b\\c:d*e"), "art_a_b_c_d_e");
+        assert_eq!(layer_file_stem("art", "line-art_2"), "art_line-art_2");
+    }
+}
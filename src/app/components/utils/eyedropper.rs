@@ -0,0 +1,105 @@
+use std::fmt::Display;
+
+use egui::Color32;
+
+use crate::app::components::utils::flood_fill::FillSample;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SampleSize {
+    #[default]
+    Point,
+    Average3,
+    Average5
+}
+
+impl SampleSize {
+    pub const ALL: [SampleSize; 3] = [SampleSize::Point, SampleSize::Average3, SampleSize::Average5];
+
+    /// Pixels averaged on each side of the one under the pointer.
+    fn radius(&self) -> usize {
+        match self {
+            SampleSize::Point => 0,
+            SampleSize::Average3 => 1,
+            SampleSize::Average5 => 2
+        }
+    }
+}
+
+impl Display for SampleSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SampleSize::Point => "1×1",
+            SampleSize::Average3 => "3×3",
+            SampleSize::Average5 => "5×5"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct EyedropperSettings {
+    pub sample: FillSample,
+    pub size: SampleSize,
+    // Appends picked colors the palette doesn't have yet
+    pub add_to_palette: bool
+}
+
+/// Average of the pixels around `pos` in an image of `size`, clipped to the image, reading each one with
+/// `pixel`. Only those few pixels are read, so a merged sample needs no composite of the whole document.
+/// Averaging the premultiplied values keeps transparent neighbours from darkening the result.
+pub fn sample_color(size: [usize; 2], pos: [usize; 2], sample_size: SampleSize, pixel: impl Fn(usize) -> Color32) -> Color32 {
+    let [width, height] = size;
+    let radius = sample_size.radius();
+    let mut sum = [0_u32; 4];
+    let mut count = 0;
+    for y in pos[1].saturating_sub(radius)..(pos[1] + radius + 1).min(height) {
+        for x in pos[0].saturating_sub(radius)..(pos[0] + radius + 1).min(width) {
+            for (total, channel) in sum.iter_mut().zip(pixel(y * width + x).to_array()) {
+                *total += channel as u32;
+            }
+            count += 1;
+        }
+    }
+    let [r, g, b, a] = sum.map(|total| ((total + count / 2) / count.max(1)) as u8);
+    Color32::from_rgba_premultiplied(r, g, b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: [usize; 2] = [10, 10];
+
+    // Red grows by 10 per column and green by 10 per row
+    fn gradient(idx: usize) -> Color32 {
+        Color32::from_rgb((idx % SIZE[0] * 10) as u8, (idx / SIZE[0] * 10) as u8, 0)
+    }
+
+    #[test]
+    fn averages_around_the_pointer() {
+        assert_eq!(sample_color(SIZE, [4, 6], SampleSize::Point, gradient), Color32::from_rgb(40, 60, 0));
+        assert_eq!(sample_color(SIZE, [4, 6], SampleSize::Average3, gradient), Color32::from_rgb(40, 60, 0));
+        assert_eq!(sample_color(SIZE, [4, 6], SampleSize::Average5, gradient), Color32::from_rgb(40, 60, 0));
+        // A lone bright pixel is diluted more the wider the sample
+        let spot = |idx: usize| if idx == 5 * SIZE[0] + 5 { Color32::from_rgb(90, 180, 225) } else { Color32::BLACK };
+        assert_eq!(sample_color(SIZE, [5, 5], SampleSize::Average3, spot), Color32::from_rgb(10, 20, 25));
+        assert_eq!(sample_color(SIZE, [5, 5], SampleSize::Average5, spot), Color32::from_rgb(4, 7, 9));
+    }
+
+    #[test]
+    fn clamps_the_window_at_corners() {
+        // Only the 2x2 and 3x3 pixels inside the image count
+        assert_eq!(sample_color(SIZE, [0, 0], SampleSize::Average3, gradient), Color32::from_rgb(5, 5, 0));
+        assert_eq!(sample_color(SIZE, [0, 0], SampleSize::Average5, gradient), Color32::from_rgb(10, 10, 0));
+        assert_eq!(sample_color(SIZE, [9, 9], SampleSize::Average3, gradient), Color32::from_rgb(85, 85, 0));
+        assert_eq!(sample_color(SIZE, [9, 0], SampleSize::Average5, gradient), Color32::from_rgb(80, 10, 0));
+    }
+
+    #[test]
+    fn transparent_neighbours_keep_the_hue() {
+        let spot = |idx: usize| if idx == 55 { Color32::RED } else { Color32::TRANSPARENT };
+        let sampled = sample_color(SIZE, [5, 5], SampleSize::Average3, spot);
+        assert_eq!(sampled.a(), 28);
+        assert_eq!(sampled.to_srgba_unmultiplied(), [255, 0, 0, 28]);
+    }
+}
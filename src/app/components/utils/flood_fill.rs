@@ -0,0 +1,259 @@
+use std::fmt::Display;
+
+use egui::Color32;
+
+use crate::app::components::utils::tiled_image::{TiledImage, TILE_SIZE};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FillSample {
+    #[default]
+    CurrentLayer,
+    Merged
+}
+
+impl FillSample {
+    pub const ALL: [FillSample; 2] = [FillSample::CurrentLayer, FillSample::Merged];
+}
+
+impl Display for FillSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FillSample::CurrentLayer => "Current layer",
+            FillSample::Merged => "Merged"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FillSettings {
+    // 0.0 only fills the exact color clicked, 1.0 fills everything
+    pub tolerance: f32,
+    // Off fills every matching pixel on the canvas, connected or not
+    pub contiguous: bool,
+    pub anti_alias: bool,
+    pub sample: FillSample,
+    // Openings in the outline up to this many pixels wide are treated as closed
+    pub gap_closing: usize
+}
+
+impl Default for FillSettings {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.1,
+            contiguous: true,
+            anti_alias: true,
+            sample: FillSample::default(),
+            gap_closing: 0
+        }
+    }
+}
+
+/// Largest difference between any two channels.
+fn color_distance(a: Color32, b: Color32) -> u8 {
+    a.to_array().iter().zip(b.to_array()).map(|(a, b)| a.abs_diff(b)).max().unwrap_or(0)
+}
+
+/// How much of each pixel of `source` a fill started at `start` covers, 0-255 in `y * width + x` order.
+pub fn fill_coverage(source: &TiledImage, start: [usize; 2], settings: &FillSettings) -> Vec<u8> {
+    let start_idx = start[1] * source.width() + start[0];
+    region_coverage(source, source.get(start_idx), Some(start_idx), settings)
+}
+
+/// Coverage of every pixel of `source` within the tolerance of `target`, wherever it is on the layer.
+pub fn color_range_coverage(source: &TiledImage, target: Color32, settings: &FillSettings) -> Vec<u8> {
+    region_coverage(source, target, None, settings)
+}
+
+/// Pixels matching `target`; only those connected to `start` when the fill is contiguous and has one.
+fn region_coverage(source: &TiledImage, target: Color32, start: Option<usize>, settings: &FillSettings) -> Vec<u8> {
+    let [width, height] = source.size;
+    let threshold = (settings.tolerance.clamp(0., 1.) * 255.).round() as u8;
+
+    // Missing tiles are all transparent, so only allocated ones need comparing pixel by pixel
+    let mut similar = vec![color_distance(Color32::TRANSPARENT, target) <= threshold; width * height];
+    for (tile_x, tile_y, tile) in source.allocated_tiles() {
+        let min_x = tile_x * TILE_SIZE;
+        let min_y = tile_y * TILE_SIZE;
+        for y in min_y..(min_y + TILE_SIZE).min(height) {
+            for x in min_x..(min_x + TILE_SIZE).min(width) {
+                similar[y * width + x] = color_distance(tile[(y - min_y) * TILE_SIZE + x - min_x], target) <= threshold;
+            }
+        }
+    }
+
+    let filled = if settings.contiguous && let Some(start_idx) = start {
+        let radius = settings.gap_closing.div_ceil(2);
+        // Growing the outline by half the gap width seals the gaps; the fill is grown back by the same
+        // amount afterwards so it still reaches the outline everywhere else
+        let passable = if radius > 0 {
+            let outline = similar.iter().map(|is_similar| !is_similar).collect::<Vec<bool>>();
+            dilate(&outline, width, height, radius).into_iter().map(|is_outline| !is_outline).collect()
+        } else {
+            similar.clone()
+        };
+        if radius > 0 && passable[start_idx] {
+            let region = flood(&passable, width, height, start_idx);
+            dilate(&region, width, height, radius).into_iter().zip(similar.iter()).map(|(grown, is_similar)| grown && *is_similar).collect()
+        } else {
+            // Clicked inside an area narrower than the gap size; fill it as if there were no gaps
+            flood(&similar, width, height, start_idx)
+        }
+    } else {
+        similar
+    };
+
+    let mut coverage = filled.iter().map(|is_filled| if *is_filled { 255 } else { 0 }).collect::<Vec<u8>>();
+    if settings.anti_alias {
+        // Pixels just outside the fill get part of it, by how many of their neighbours are filled
+        for y in 0..height {
+            for x in 0..width {
+                let idx = y * width + x;
+                if filled[idx] {
+                    continue;
+                }
+                let mut neighbours = 0;
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        neighbours += filled[ny * width + nx] as u32;
+                    }
+                }
+                coverage[idx] = (neighbours * 255 / 9) as u8;
+            }
+        }
+    }
+    coverage
+}
+
+/// Scanline flood fill through `passable` pixels connected to `start`.
+fn flood(passable: &[bool], width: usize, height: usize, start: usize) -> Vec<bool> {
+    let mut filled = vec![false; passable.len()];
+    let mut stack = vec![start];
+    while let Some(idx) = stack.pop() {
+        if filled[idx] || !passable[idx] {
+            continue;
+        }
+        let y = idx / width;
+        let row = y * width;
+        let mut left = idx;
+        while left > row && passable[left - 1] && !filled[left - 1] {
+            left -= 1;
+        }
+        let mut right = idx;
+        while right + 1 < row + width && passable[right + 1] && !filled[right + 1] {
+            right += 1;
+        }
+        for span_idx in left..=right {
+            filled[span_idx] = true;
+            if y > 0 && passable[span_idx - width] && !filled[span_idx - width] {
+                stack.push(span_idx - width);
+            }
+            if y + 1 < height && passable[span_idx + width] && !filled[span_idx + width] {
+                stack.push(span_idx + width);
+            }
+        }
+    }
+    filled
+}
+
+/// Grows `mask` by `radius` pixels in every direction, as a square.
+fn dilate(mask: &[bool], width: usize, height: usize, radius: usize) -> Vec<bool> {
+    let mut horizontal = vec![false; mask.len()];
+    let mut counts = Vec::with_capacity(width.max(height) + 1);
+    for y in 0..height {
+        let row = y * width;
+        prefix_counts(&mut counts, (0..width).map(|x| mask[row + x]));
+        for x in 0..width {
+            horizontal[row + x] = counts[(x + radius + 1).min(width)] > counts[x.saturating_sub(radius)];
+        }
+    }
+    let mut dilated = vec![false; mask.len()];
+    for x in 0..width {
+        prefix_counts(&mut counts, (0..height).map(|y| horizontal[y * width + x]));
+        for y in 0..height {
+            dilated[y * width + x] = counts[(y + radius + 1).min(height)] > counts[y.saturating_sub(radius)];
+        }
+    }
+    dilated
+}
+
+/// `counts[i]` becomes the number of set values before index `i`.
+fn prefix_counts(counts: &mut Vec<usize>, values: impl Iterator<Item = bool>) {
+    counts.clear();
+    counts.push(0);
+    let mut total = 0;
+    for value in values {
+        total += value as usize;
+        counts.push(total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 20;
+
+    /// Transparent canvas split by a black wall at x = 10, with an opening at `gap` rows.
+    fn walled(gap: std::ops::Range<usize>) -> TiledImage {
+        let mut image = TiledImage::new([SIZE, SIZE]);
+        for y in (0..SIZE).filter(|y| !gap.contains(y)) {
+            image.set(y * SIZE + 10, Color32::BLACK);
+        }
+        image
+    }
+
+    fn settings(gap_closing: usize) -> FillSettings {
+        FillSettings { tolerance: 0., anti_alias: false, gap_closing, ..Default::default() }
+    }
+
+    fn filled_columns(coverage: &[u8], y: usize) -> Vec<usize> {
+        (0..SIZE).filter(|x| coverage[y * SIZE + x] == 255).collect()
+    }
+
+    #[test]
+    fn contiguous_fill_stops_at_the_outline() {
+        let coverage = fill_coverage(&walled(0..0), [2, 2], &settings(0));
+        for y in 0..SIZE {
+            assert_eq!(filled_columns(&coverage, y), (0..10).collect::<Vec<usize>>());
+        }
+        let everywhere = fill_coverage(&walled(0..0), [2, 2], &FillSettings { contiguous: false, ..settings(0) });
+        assert_eq!(everywhere.iter().filter(|amount| **amount == 255).count(), SIZE * SIZE - SIZE);
+    }
+
+    #[test]
+    fn fill_leaks_through_gaps_unless_they_are_closed() {
+        let leaked = fill_coverage(&walled(9..11), [2, 2], &settings(0));
+        assert_eq!(leaked[5 * SIZE + 15], 255);
+
+        let closed = fill_coverage(&walled(9..11), [2, 2], &settings(2));
+        for y in 0..SIZE {
+            // Grown back to the outline, but not into the gap or past it
+            assert_eq!(filled_columns(&closed, y), (0..10).collect::<Vec<usize>>(), "row {y}");
+        }
+    }
+
+    #[test]
+    fn gap_closing_still_fills_areas_narrower_than_the_gap() {
+        let coverage = fill_coverage(&walled(9..11), [15, 2], &settings(30));
+        assert_eq!(filled_columns(&coverage, 2), (0..SIZE).filter(|x| *x != 10).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn tolerance_and_anti_aliasing() {
+        let mut image = TiledImage::new([SIZE, SIZE]);
+        image.set(0, Color32::from_rgb(250, 0, 0));
+        image.set(1, Color32::from_rgb(200, 0, 0));
+        let strict = color_range_coverage(&image, Color32::RED, &settings(0));
+        assert_eq!(strict.iter().filter(|amount| **amount == 255).count(), 0);
+        let loose = color_range_coverage(&image, Color32::RED, &FillSettings { tolerance: 0.1, ..settings(0) });
+        assert_eq!(loose.iter().enumerate().filter(|(_, amount)| **amount == 255).map(|(idx, _)| idx).collect::<Vec<usize>>(), vec![0]);
+
+        let smooth = color_range_coverage(&image, Color32::RED, &FillSettings { tolerance: 0.1, anti_alias: true, ..settings(0) });
+        // Neighbours of the filled pixel get part of it, pixels further away none
+        assert_eq!(smooth[0], 255);
+        assert_eq!(smooth[1], 255 / 9);
+        assert_eq!(smooth[SIZE + 1], 255 / 9);
+        assert_eq!(smooth[2], 0);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use egui::{Color32, Id};
+
+use crate::app::AppState;
+use crate::app::components::utils::image_color::BlendMode;
+use crate::app::components::utils::layer::{Layer, PaintColor};
+use crate::app::components::utils::palette::Palette;
+
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PixelDelta {
+    pub idx: usize,
+    pub before: Color32,
+    pub after: Color32
+}
+
+#[derive(Clone, PartialEq)]
+pub enum HistoryAction {
+    Stroke {
+        layer_id: Id,
+        deltas: Vec<PixelDelta>
+    },
+    AddLayer {
+        index: usize,
+        layer_id: Id,
+        previous_layer: Option<Id>,
+        // Only filled while the action sits on the redo stack
+        removed: Option<Layer>
+    },
+    RemoveLayer {
+        index: usize,
+        layer_id: Id,
+        // Only filled while the action sits on the undo stack
+        removed: Option<Layer>
+    },
+    MoveLayer {
+        from: usize,
+        to: usize
+    },
+    RenameLayer {
+        layer_id: Id,
+        before: String,
+        after: String
+    },
+    ToggleVisibility {
+        layer_id: Id
+    },
+    SetBlendMode {
+        layer_id: Id,
+        before: BlendMode,
+        after: BlendMode
+    },
+    SetOpacity {
+        layer_id: Id,
+        before: f32,
+        after: f32
+    },
+    Palette {
+        palette_id: Id,
+        before: Vec<PaintColor>,
+        after: Vec<PaintColor>
+    },
+    // Palettes added, removed or renamed
+    Palettes {
+        before: Vec<Palette>,
+        after: Vec<Palette>
+    },
+    // Palette an indexed document is locked to, `None` for full color
+    SetIndexed {
+        before: Option<Id>,
+        after: Option<Id>
+    },
+    // Several actions undone and redone as one step, e.g. merge down
+    Batch(Vec<HistoryAction>)
+}
+
+fn move_layer(layers: &mut Vec<Layer>, from: usize, to: usize) {
+    if from < layers.len() && to < layers.len() {
+        let layer = layers.remove(from);
+        layers.insert(to, layer);
+    }
+}
+
+impl HistoryAction {
+    pub fn undo(&mut self, state: &mut AppState) {
+        match self {
+            HistoryAction::Stroke { layer_id, deltas } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    for delta in deltas.iter() {
+                        layer.texture.image_data.set(delta.idx, delta.before);
+                        layer.texture.mark_dirty(delta.idx);
+                    }
+                }
+            },
+            HistoryAction::AddLayer { layer_id, previous_layer, removed, .. } => {
+                if let Some(position) = state.layers_container.layers.iter().position(|l| l.id == *layer_id) {
+                    *removed = Some(state.layers_container.layers.remove(position));
+                }
+                state.current_layer = previous_layer.filter(|id| state.layers_container.layers.iter().any(|l| l.id == *id))
+                    .or(state.layers_container.layers.first().map(|l| l.id));
+            },
+            HistoryAction::RemoveLayer { index, layer_id, removed } => {
+                if let Some(layer) = removed.take() {
+                    let index = (*index).min(state.layers_container.layers.len());
+                    state.layers_container.layers.insert(index, layer);
+                    state.current_layer = Some(*layer_id);
+                }
+            },
+            HistoryAction::MoveLayer { from, to } => {
+                move_layer(&mut state.layers_container.layers, *to, *from);
+            },
+            HistoryAction::RenameLayer { layer_id, before, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.name = before.clone();
+                }
+            },
+            HistoryAction::ToggleVisibility { layer_id } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.is_visible = !layer.is_visible;
+                }
+            },
+            HistoryAction::SetBlendMode { layer_id, before, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.blend_mode = *before;
+                }
+            },
+            HistoryAction::SetOpacity { layer_id, before, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.opacity = *before;
+                }
+            },
+            HistoryAction::Palette { palette_id, before, .. } => {
+                state.set_palette(*palette_id, before.clone());
+            },
+            HistoryAction::Palettes { before, .. } => {
+                state.set_palettes(before.clone());
+            },
+            HistoryAction::SetIndexed { before, .. } => {
+                state.indexed_palette = *before;
+            },
+            HistoryAction::Batch(actions) => {
+                for action in actions.iter_mut().rev() {
+                    action.undo(state);
+                }
+            }
+        }
+    }
+
+    pub fn redo(&mut self, state: &mut AppState) {
+        match self {
+            HistoryAction::Stroke { layer_id, deltas } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    for delta in deltas.iter() {
+                        layer.texture.image_data.set(delta.idx, delta.after);
+                        layer.texture.mark_dirty(delta.idx);
+                    }
+                }
+            },
+            HistoryAction::AddLayer { index, layer_id, removed, .. } => {
+                if let Some(layer) = removed.take() {
+                    let index = (*index).min(state.layers_container.layers.len());
+                    state.layers_container.layers.insert(index, layer);
+                    state.current_layer = Some(*layer_id);
+                }
+            },
+            HistoryAction::RemoveLayer { layer_id, removed, .. } => {
+                if let Some(position) = state.layers_container.layers.iter().position(|l| l.id == *layer_id) {
+                    *removed = Some(state.layers_container.layers.remove(position));
+                    if state.current_layer == Some(*layer_id) {
+                        let next = position.min(state.layers_container.layers.len().saturating_sub(1));
+                        state.current_layer = state.layers_container.layers.get(next).map(|l| l.id);
+                    }
+                }
+            },
+            HistoryAction::MoveLayer { from, to } => {
+                move_layer(&mut state.layers_container.layers, *from, *to);
+            },
+            HistoryAction::RenameLayer { layer_id, after, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.name = after.clone();
+                }
+            },
+            HistoryAction::ToggleVisibility { layer_id } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.is_visible = !layer.is_visible;
+                }
+            },
+            HistoryAction::SetBlendMode { layer_id, after, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.blend_mode = *after;
+                }
+            },
+            HistoryAction::SetOpacity { layer_id, after, .. } => {
+                if let Some(layer) = state.layers_container.layers.iter_mut().find(|l| l.id == *layer_id) {
+                    layer.opacity = *after;
+                }
+            },
+            HistoryAction::Palette { palette_id, after, .. } => {
+                state.set_palette(*palette_id, after.clone());
+            },
+            HistoryAction::Palettes { after, .. } => {
+                state.set_palettes(after.clone());
+            },
+            HistoryAction::SetIndexed { after, .. } => {
+                state.indexed_palette = *after;
+            },
+            HistoryAction::Batch(actions) => {
+                for action in actions.iter_mut() {
+                    action.redo(state);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct PendingStroke {
+    layer_id: Id,
+    // Pixel index -> color before the stroke first touched it
+    originals: HashMap<usize, Color32>
+}
+
+#[derive(Clone, PartialEq, Default)]
+pub struct History {
+    undo_stack: Vec<HistoryAction>,
+    redo_stack: Vec<HistoryAction>,
+    pending_stroke: Option<PendingStroke>
+}
+
+impl History {
+    pub fn push(&mut self, action: HistoryAction) {
+        self.redo_stack.clear();
+        self.undo_stack.push(action);
+        if self.undo_stack.len() > HISTORY_LIMIT {
+            self.undo_stack.remove(0);
+        }
+    }
+
+    /// Slider drags change opacity every frame, so the edits of one drag share an entry. `continues_drag`
+    /// is false for the first edit of a drag, which always starts a new entry.
+    pub fn push_or_merge_opacity(&mut self, layer_id: Id, before: f32, after: f32, continues_drag: bool) {
+        if continues_drag && self.redo_stack.is_empty() && let Some(HistoryAction::SetOpacity { layer_id: last_id, after: last_after, .. }) = self.undo_stack.last_mut() && *last_id == layer_id {
+            *last_after = after;
+            return;
+        }
+        self.push(HistoryAction::SetOpacity { layer_id, before, after });
+    }
+
+    /// Adds pixel edits to the pending stroke. A stroke covers one layer, so callers must commit the
+    /// pending stroke before recording edits of another layer.
+    pub fn record_pixels(&mut self, layer_id: Id, changes: Vec<(usize, Color32)>) {
+        debug_assert!(self.pending_layer().is_none_or(|pending_id| pending_id == layer_id), "pending stroke belongs to another layer");
+        let pending = self.pending_stroke.get_or_insert_with(|| PendingStroke {
+            layer_id,
+            originals: HashMap::new()
+        });
+        for (idx, before) in changes {
+            pending.originals.entry(idx).or_insert(before);
+        }
+    }
+
+    pub fn has_pending_stroke(&self) -> bool {
+        self.pending_stroke.is_some()
+    }
+
+    pub fn pending_layer(&self) -> Option<Id> {
+        self.pending_stroke.as_ref().map(|pending| pending.layer_id)
+    }
+
+    pub fn finish_stroke(&mut self, layers: &[Layer]) -> Option<HistoryAction> {
+        let pending = self.pending_stroke.take()?;
+        let layer = layers.iter().find(|l| l.id == pending.layer_id)?;
+        let mut deltas: Vec<PixelDelta> = pending.originals.into_iter()
+            .map(|(idx, before)| PixelDelta { idx, before, after: layer.texture.image_data.get(idx) })
+            .filter(|delta| delta.before != delta.after)
+            .collect();
+        if deltas.is_empty() {
+            return None;
+        }
+        deltas.sort_by_key(|delta| delta.idx);
+        Some(HistoryAction::Stroke { layer_id: pending.layer_id, deltas })
+    }
+
+    pub fn pop_undo(&mut self) -> Option<HistoryAction> {
+        self.undo_stack.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<HistoryAction> {
+        self.redo_stack.pop()
+    }
+
+    pub fn push_undone(&mut self, action: HistoryAction) {
+        self.redo_stack.push(action);
+    }
+
+    pub fn push_redone(&mut self, action: HistoryAction) {
+        self.undo_stack.push(action);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty() || self.pending_stroke.is_some()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::AppSettings;
+    use crate::app::components::utils::layer::LayerTexture;
+    use crate::app::components::utils::new_rand_id;
+
+    fn paint(state: &mut AppState, idx: usize, color: Color32) {
+        let layer_id = state.current_layer.unwrap();
+        let layer = state.layers_container.layers.iter_mut().find(|l| l.id == layer_id).unwrap();
+        let before = layer.texture.image_data.get(idx);
+        layer.texture.image_data.set(idx, color);
+        state.history.record_pixels(layer_id, vec![(idx, before)]);
+    }
+
+    fn pixel(state: &AppState, idx: usize) -> Color32 {
+        state.layers_container.layers[0].texture.image_data.get(idx)
+    }
+
+    #[test]
+    fn stroke_undo_and_redo() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        paint(&mut state, 5, Color32::RED);
+        // Painting a pixel twice keeps the color from before the stroke
+        paint(&mut state, 5, Color32::GREEN);
+        paint(&mut state, 6, Color32::GREEN);
+        state.commit_stroke();
+        assert!(state.history.can_undo());
+
+        state.undo();
+        assert_eq!(pixel(&state, 5), Color32::TRANSPARENT);
+        assert_eq!(pixel(&state, 6), Color32::TRANSPARENT);
+        assert!(!state.history.can_undo());
+
+        state.redo();
+        assert_eq!(pixel(&state, 5), Color32::GREEN);
+        assert_eq!(pixel(&state, 6), Color32::GREEN);
+        assert!(!state.history.can_redo());
+    }
+
+    #[test]
+    fn stroke_that_changes_nothing_is_not_recorded() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        paint(&mut state, 5, Color32::RED);
+        paint(&mut state, 5, Color32::TRANSPARENT);
+        state.commit_stroke();
+        assert!(!state.history.can_undo());
+    }
+
+    #[test]
+    fn undo_pending_stroke_commits_it_first() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        paint(&mut state, 0, Color32::RED);
+        state.undo();
+        assert_eq!(pixel(&state, 0), Color32::TRANSPARENT);
+        assert!(state.history.can_redo());
+    }
+
+    #[test]
+    fn opacity_drags_merge_only_within_one_drag() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        let layer_id = state.current_layer.unwrap();
+        state.set_layer_opacity(layer_id, 0.8, false);
+        state.set_layer_opacity(layer_id, 0.6, true);
+        state.set_layer_opacity(layer_id, 0.4, false);
+        state.set_layer_opacity(layer_id, 0.2, true);
+
+        state.undo();
+        assert_eq!(state.layers_container.layers[0].opacity, 0.6);
+        state.undo();
+        assert_eq!(state.layers_container.layers[0].opacity, 1.);
+        assert!(!state.history.can_undo());
+    }
+
+    #[test]
+    fn batch_undoes_in_reverse_order() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        let layer_id = state.current_layer.unwrap();
+        let mut action = HistoryAction::Batch(vec![
+            HistoryAction::Stroke { layer_id, deltas: vec![PixelDelta { idx: 3, before: Color32::TRANSPARENT, after: Color32::RED }] },
+            // Overwrites the first action, so only undoing in reverse gets back to the start
+            HistoryAction::Stroke { layer_id, deltas: vec![PixelDelta { idx: 3, before: Color32::RED, after: Color32::BLUE }] },
+            HistoryAction::RenameLayer { layer_id, before: "Layer 1".to_string(), after: "Sky".to_string() }
+        ]);
+        action.redo(&mut state);
+        state.history.push(action);
+        assert_eq!(pixel(&state, 3), Color32::BLUE);
+        assert_eq!(state.layers_container.layers[0].name, "Sky");
+
+        state.undo();
+        assert_eq!(pixel(&state, 3), Color32::TRANSPARENT);
+        assert_eq!(state.layers_container.layers[0].name, "Layer 1");
+
+        state.redo();
+        assert_eq!(pixel(&state, 3), Color32::BLUE);
+        assert_eq!(state.layers_container.layers[0].name, "Sky");
+    }
+
+    #[test]
+    fn removed_layer_comes_back_on_undo() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        let first = state.current_layer.unwrap();
+        state.add_layer(0, Layer {
+            id: new_rand_id(),
+            name: "Layer 2".to_string(),
+            is_visible: true,
+            blend_mode: BlendMode::Normal,
+            opacity: 1.,
+            texture: LayerTexture::new(8, 8)
+        });
+        state.delete_layer(first);
+        assert_eq!(state.layers_container.layers.len(), 1);
+        state.undo();
+        assert_eq!(state.layers_container.layers.len(), 2);
+        assert_eq!(state.layers_container.layers[1].id, first);
+        assert_eq!(state.current_layer, Some(first));
+        state.undo();
+        assert_eq!(state.layers_container.layers.len(), 1);
+    }
+
+    #[test]
+    fn indexed_palette_is_never_left_without_swatches() {
+        let mut state = AppState::from_settings(AppSettings::default());
+        let default_palette = state.current_palette;
+        state.add_palette(Palette::new("Empty", Vec::new()));
+        state.set_indexed(Some(state.current_palette));
+        assert_eq!(state.indexed_palette, None);
+
+        state.select_palette(default_palette);
+        state.edit_palette(|palette| palette.truncate(1));
+        paint(&mut state, 4, Color32::GREEN);
+        state.set_indexed(Some(default_palette));
+        let snapped = pixel(&state, 4);
+        assert_ne!(snapped, Color32::TRANSPARENT);
+
+        // Deleting the last swatch would erase every layer
+        state.edit_palette(|palette| palette.clear());
+        assert_eq!(state.color_palette().len(), 1);
+        assert_eq!(pixel(&state, 4), snapped);
+    }
+}
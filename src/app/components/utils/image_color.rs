@@ -1,35 +1,325 @@
+use std::fmt::Display;
+
 use egui::ColorImage;
+use image::RgbaImage;
+
+
+use crate::app::components::utils::layer::Layer;
+use crate::app::components::utils::tiled_image::{TiledImage, TILE_SIZE};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    Difference,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity
+}
 
+impl BlendMode {
+    pub const ALL: [BlendMode; 14] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Add,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::Difference,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity
+    ];
+}
 
-pub fn blend_pixel(bottom: egui::Color32, top: egui::Color32) -> egui::Color32 {
+impl Display for BlendMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            BlendMode::ColorDodge => "Color Dodge",
+            BlendMode::ColorBurn => "Color Burn",
+            _ => return write!(f, "{:?}", self)
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Separable blend functions from the W3C compositing spec, on unmultiplied channels in 0..=1.
+fn blend_channel(mode: BlendMode, backdrop: f32, source: f32) -> f32 {
+    match mode {
+        BlendMode::Multiply => backdrop * source,
+        BlendMode::Screen => backdrop + source - backdrop * source,
+        BlendMode::Overlay => {
+            if backdrop <= 0.5 {
+                source * 2. * backdrop
+            } else {
+                let doubled = 2. * backdrop - 1.;
+                source + doubled - source * doubled
+            }
+        },
+        BlendMode::Add => (backdrop + source).min(1.),
+        BlendMode::Darken => backdrop.min(source),
+        BlendMode::Lighten => backdrop.max(source),
+        BlendMode::ColorDodge => {
+            if backdrop == 0. {
+                0.
+            } else if source >= 1. {
+                1.
+            } else {
+                (backdrop / (1. - source)).min(1.)
+            }
+        },
+        BlendMode::ColorBurn => {
+            if backdrop >= 1. {
+                1.
+            } else if source == 0. {
+                0.
+            } else {
+                1. - ((1. - backdrop) / source).min(1.)
+            }
+        },
+        BlendMode::Difference => (backdrop - source).abs(),
+        _ => source
+    }
+}
+
+fn luminosity(color: [f32; 3]) -> f32 {
+    0.3 * color[0] + 0.59 * color[1] + 0.11 * color[2]
+}
+
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let lum = luminosity(color);
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+    color.map(|c| {
+        let mut c = c;
+        if min < 0. {
+            c = lum + (c - lum) * lum / (lum - min);
+        }
+        if max > 1. {
+            c = lum + (c - lum) * (1. - lum) / (max - lum);
+        }
+        c
+    })
+}
+
+fn set_luminosity(color: [f32; 3], lum: f32) -> [f32; 3] {
+    let delta = lum - luminosity(color);
+    clip_color(color.map(|c| c + delta))
+}
+
+fn saturation(color: [f32; 3]) -> f32 {
+    color[0].max(color[1]).max(color[2]) - color[0].min(color[1]).min(color[2])
+}
+
+fn set_saturation(color: [f32; 3], sat: f32) -> [f32; 3] {
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+    if max <= min {
+        return [0.; 3];
+    }
+    color.map(|c| (c - min) * sat / (max - min))
+}
+
+fn blend_color(mode: BlendMode, backdrop: [f32; 3], source: [f32; 3]) -> [f32; 3] {
+    match mode {
+        BlendMode::Hue => set_luminosity(set_saturation(source, saturation(backdrop)), luminosity(backdrop)),
+        BlendMode::Saturation => set_luminosity(set_saturation(backdrop, saturation(source)), luminosity(backdrop)),
+        BlendMode::Color => set_luminosity(source, luminosity(backdrop)),
+        BlendMode::Luminosity => set_luminosity(backdrop, luminosity(source)),
+        _ => [0, 1, 2].map(|i| blend_channel(mode, backdrop[i], source[i]))
+    }
+}
+
+/// Premultiplied source-over in integer maths, the common case of `blend_pixel`.
+fn source_over(bottom: egui::Color32, top: egui::Color32) -> egui::Color32 {
+    let inverse_alpha = 255 - top.a() as u32;
+    let over = |top: u8, bottom: u8| (top as u32 + (bottom as u32 * inverse_alpha + 127) / 255).min(255) as u8;
+    egui::Color32::from_rgba_premultiplied(over(top.r(), bottom.r()), over(top.g(), bottom.g()), over(top.b(), bottom.b()), over(top.a(), bottom.a()))
+}
 
+pub fn blend_pixel(bottom: egui::Color32, top: egui::Color32, mode: BlendMode) -> egui::Color32 {
+    if top.a() == 0 {
+        return bottom;
+    }
+    if top.a() == 255 && mode == BlendMode::Normal {
+        return top;
+    }
+    if mode == BlendMode::Normal || bottom.a() == 0 {
+        return source_over(bottom, top);
+    }
     let top_a = top.a() as f32 / 255.0;
     let bottom_a = bottom.a() as f32 / 255.0;
+    // Color32 is premultiplied, so plain source-over needs no division
+    let top_premultiplied = [top.r(), top.g(), top.b()].map(|c| c as f32 / 255.0);
+    let bottom_premultiplied = [bottom.r(), bottom.g(), bottom.b()].map(|c| c as f32 / 255.0);
+
+    let source = top_premultiplied.map(|c| c / top_a);
+    let backdrop = bottom_premultiplied.map(|c| c / bottom_a);
+    let mixed = blend_color(mode, backdrop, source);
+    let source_color = [0, 1, 2].map(|i| top_premultiplied[i] * (1.0 - bottom_a) + top_a * bottom_a * mixed[i].clamp(0., 1.));
 
     let out_a = top_a + bottom_a * (1.0 - top_a);
+    let [r, g, b] = [0, 1, 2].map(|i| ((source_color[i] + bottom_premultiplied[i] * (1.0 - top_a)) * 255.0).round().min(255.0) as u8);
+    let a = (out_a * 255.0).round() as u8;
 
-    if out_a == 0.0 {
-        return egui::Color32::TRANSPARENT;
-    }
+    egui::Color32::from_rgba_premultiplied(r.min(a), g.min(a), b.min(a), a)
+}
 
-    let r = ((top.r() as f32 * top_a + bottom.r() as f32 * bottom_a * (1.0 - top_a)) / out_a).round() as u8;
-    let g = ((top.g() as f32 * top_a + bottom.g() as f32 * bottom_a * (1.0 - top_a)) / out_a).round() as u8;
-    let b = ((top.b() as f32 * top_a + bottom.b() as f32 * bottom_a * (1.0 - top_a)) / out_a).round() as u8;
-    let a = (out_a * 255.0).round() as u8;
+/// Unmultiplies premultiplied pixels into the straight alpha image files expect.
+pub fn to_rgba_image(image: &ColorImage) -> RgbaImage {
+    let [width, height] = image.size;
+    let bytes = image.pixels.iter().flat_map(|pixel| pixel.to_srgba_unmultiplied()).collect::<Vec<u8>>();
+    RgbaImage::from_raw(width as u32, height as u32, bytes).unwrap_or_default()
+}
+
+/// Blends `layers` bottom to top, each with its own blend mode and opacity.
+pub fn composite_layers(layers: &[&Layer], size: [usize; 2]) -> TiledImage {
+    composite_onto(&TiledImage::new(size), layers)
+}
+
+/// One pixel of what `composite_layers` would give for `layers`, for when only a few are needed.
+pub fn composite_pixel(layers: &[&Layer], idx: usize) -> egui::Color32 {
+    layers.iter().fold(egui::Color32::TRANSPARENT, |below, layer| {
+        let opacity = layer.opacity.clamp(0., 1.);
+        let pixel = layer.texture.image_data.get(idx);
+        let pixel = if opacity < 1. { pixel.gamma_multiply(opacity) } else { pixel };
+        blend_pixel(below, pixel, layer.blend_mode)
+    })
+}
+
+fn composite_tile(base: &TiledImage, layers: &[&Layer], tile_x: usize, tile_y: usize) -> Vec<egui::Color32> {
+    let mut pixels = base.tile(tile_x, tile_y).map(|tile| tile.to_vec()).unwrap_or_else(|| vec![egui::Color32::TRANSPARENT; TILE_SIZE * TILE_SIZE]);
+    for layer in layers {
+        let Some(layer_tile) = layer.texture.image_data.tile(tile_x, tile_y) else {
+            continue;
+        };
+        let opacity = layer.opacity.clamp(0., 1.);
+        for (final_pixel, layer_pixel) in pixels.iter_mut().zip(layer_tile.iter()) {
+            // Same gamma-space multiply the canvas preview uses as its tint
+            let layer_pixel = if opacity < 1. { layer_pixel.gamma_multiply(opacity) } else { *layer_pixel };
+            *final_pixel = blend_pixel(*final_pixel, layer_pixel, layer.blend_mode);
+        }
+    }
+    pixels
+}
 
-    egui::Color32::from_rgba_unmultiplied(r, g, b, a)
+/// Blends `layers` bottom to top over `base`. Tiles are independent, so they are spread over all cores,
+/// and tiles empty in `base` and every layer are skipped since transparency leaves what is below unchanged.
+pub fn composite_onto(base: &TiledImage, layers: &[&Layer]) -> TiledImage {
+    let mut composite = base.clone();
+    let tiles_x = composite.tiles_x();
+    let mut tiles = layers.iter()
+        .flat_map(|layer| layer.texture.image_data.allocated_tiles().map(|(tile_x, tile_y, _)| (tile_x, tile_y)))
+        .collect::<Vec<(usize, usize)>>();
+    tiles.sort_by_key(|(tile_x, tile_y)| tile_y * tiles_x + tile_x);
+    tiles.dedup();
+    if tiles.is_empty() {
+        return composite;
+    }
 
+    let workers = std::thread::available_parallelism().map(|workers| workers.get()).unwrap_or(1);
+    let blended = std::thread::scope(|scope| {
+        let handles = tiles.chunks(tiles.len().div_ceil(workers))
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter().map(|&(tile_x, tile_y)| (tile_x, tile_y, composite_tile(base, layers, tile_x, tile_y))).collect::<Vec<_>>()
+            }))
+            .collect::<Vec<_>>();
+        handles.into_iter().flat_map(|handle| handle.join().expect("Compositing thread panicked")).collect::<Vec<_>>()
+    });
+    for (tile_x, tile_y, pixels) in blended {
+        composite.set_tile(tile_x, tile_y, pixels);
+    }
+    composite
 }
-pub fn composite_layers(layers: &[egui::ColorImage]) -> egui::ColorImage {
-    let size = layers[0].size;
-    let mut final_pixels = layers[0].pixels.clone();
 
-    for layer in &layers[1..] {
-        for i in 0..final_pixels.len() {
-            final_pixels[i] = blend_pixel(final_pixels[i], layer.pixels[i]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::Color32;
+    use crate::app::components::utils::layer::LayerTexture;
+    use crate::app::components::utils::new_rand_id;
+
+    #[test]
+    fn normal_mode_is_source_over() {
+        let bottom = Color32::from_rgb(10, 200, 30);
+        assert_eq!(blend_pixel(bottom, Color32::TRANSPARENT, BlendMode::Normal), bottom);
+        assert_eq!(blend_pixel(bottom, Color32::RED, BlendMode::Normal), Color32::RED);
+        // Half transparent black over white
+        let top = Color32::from_rgba_premultiplied(0, 0, 0, 128);
+        assert_eq!(blend_pixel(Color32::WHITE, top, BlendMode::Normal), Color32::from_rgba_premultiplied(127, 127, 127, 255));
+        assert_eq!(blend_pixel(Color32::TRANSPARENT, top, BlendMode::Normal), top);
+    }
+
+    #[test]
+    fn separable_modes_on_opaque_pixels() {
+        let bottom = Color32::from_rgb(200, 100, 50);
+        let top = Color32::from_rgb(128, 255, 0);
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Multiply), Color32::from_rgb(100, 100, 0));
+        assert_eq!(blend_pixel(bottom, Color32::WHITE, BlendMode::Screen), Color32::WHITE);
+        assert_eq!(blend_pixel(bottom, Color32::from_rgb(100, 200, 255), BlendMode::Add), Color32::from_rgb(255, 255, 255));
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Darken), Color32::from_rgb(128, 100, 0));
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Lighten), Color32::from_rgb(200, 255, 50));
+        assert_eq!(blend_pixel(bottom, bottom, BlendMode::Difference), Color32::BLACK);
+        assert_eq!(blend_pixel(bottom, Color32::BLACK, BlendMode::ColorDodge), bottom);
+        assert_eq!(blend_pixel(bottom, Color32::WHITE, BlendMode::ColorBurn), bottom);
+        // Overlay keeps a mid-gray top from changing the backdrop much
+        let overlaid = blend_pixel(bottom, Color32::from_gray(128), BlendMode::Overlay);
+        assert!(overlaid.r().abs_diff(bottom.r()) <= 1 && overlaid.g().abs_diff(bottom.g()) <= 1 && overlaid.b().abs_diff(bottom.b()) <= 1);
+    }
+
+    #[test]
+    fn non_separable_modes_on_grays() {
+        let bottom = Color32::from_gray(60);
+        let top = Color32::from_gray(180);
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Luminosity), top);
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Color), bottom);
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Hue), bottom);
+        assert_eq!(blend_pixel(bottom, top, BlendMode::Saturation), bottom);
+    }
+
+    #[test]
+    fn composite_pixel_matches_composite_layers() {
+        let layer = |color: Color32, blend_mode: BlendMode, opacity: f32| {
+            let mut image = TiledImage::new([300, 2]);
+            for idx in [0, 299, 500] {
+                image.set(idx, color);
+            }
+            Layer { id: new_rand_id(), name: String::new(), is_visible: true, blend_mode, opacity, texture: LayerTexture::from_tiled(image) }
+        };
+        let stack = [
+            layer(Color32::from_rgb(200, 120, 40), BlendMode::Normal, 1.),
+            layer(Color32::from_rgba_premultiplied(20, 60, 90, 160), BlendMode::Multiply, 0.7),
+            layer(Color32::from_rgb(90, 30, 200), BlendMode::Screen, 0.4)
+        ];
+        let layers = stack.iter().collect::<Vec<&Layer>>();
+        let composite = composite_layers(&layers, [300, 2]);
+        for idx in [0, 1, 299, 500] {
+            assert_eq!(composite_pixel(&layers, idx), composite.get(idx));
         }
     }
 
-    
-    ColorImage::new(size, final_pixels)
+    #[test]
+    fn results_stay_premultiplied() {
+        let bottom = Color32::from_rgba_premultiplied(90, 40, 10, 120);
+        let top = Color32::from_rgba_premultiplied(30, 70, 100, 100);
+        for mode in BlendMode::ALL {
+            let blended = blend_pixel(bottom, top, mode);
+            assert!(blended.r() <= blended.a() && blended.g() <= blended.a() && blended.b() <= blended.a(), "{mode} gave {blended:?}");
+            // Alpha composites the same way whatever the mode
+            assert_eq!(blended.a(), 173, "{mode}");
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use egui::{Color32, ColorImage};
+
+use crate::app::components::utils::layer::{LayerTexture, PaintColor};
+use crate::app::components::utils::tiled_image::TiledImage;
+
+// Entries a PNG palette can hold
+const MAX_PNG_PALETTE: usize = 256;
+
+/// Colors the layers of an indexed document may hold: the swatches of its palette, plus full
+/// transparency which is always allowed.
+#[derive(Clone, PartialEq)]
+pub struct PaletteLock {
+    colors: Vec<Color32>
+}
+
+fn distance(a: Color32, b: Color32) -> u32 {
+    a.to_array().iter().zip(b.to_array()).map(|(a, b)| (*a as i32 - b as i32).pow(2) as u32).sum()
+}
+
+impl PaletteLock {
+    pub fn new(palette: &[PaintColor]) -> Self {
+        Self { colors: palette.iter().map(|paint_color| paint_color.color).collect() }
+    }
+
+    /// The allowed color nearest to `color`. Distances are measured on premultiplied values, so a pixel
+    /// turns transparent once it is more see-through than colored.
+    pub fn snap(&self, color: Color32) -> Color32 {
+        self.colors.iter().copied()
+            .chain([Color32::TRANSPARENT])
+            .min_by_key(|allowed| distance(*allowed, color))
+            .unwrap_or(Color32::TRANSPARENT)
+    }
+
+    /// Snaps the pixels at `indices` in place and returns the previous color of every one that changed.
+    pub fn snap_pixels(&self, texture: &mut LayerTexture, indices: impl Iterator<Item = usize>) -> Vec<(usize, Color32)> {
+        let mut changes = Vec::new();
+        for idx in indices {
+            let previous = texture.image_data.get(idx);
+            let snapped = self.snap(previous);
+            if snapped != previous {
+                changes.push((idx, previous));
+                texture.image_data.set(idx, snapped);
+                texture.mark_dirty(idx);
+            }
+        }
+        changes
+    }
+
+    /// Snaps every pixel of `image`, for layers that join an indexed document whole.
+    pub fn snap_image(&self, image: &mut TiledImage) {
+        let indices = image.allocated_indices().collect::<Vec<usize>>();
+        for idx in indices {
+            image.set(idx, self.snap(image.get(idx)));
+        }
+    }
+}
+
+/// How the pixels of an indexed document change when its palette goes from `before` to `after`. A pixel
+/// belongs to the first swatch of its color, found by id after the edit, so recoloring a swatch recolors
+/// its pixels even when swatches were reordered. Pixels of deleted swatches go to the nearest one left.
+pub struct PaletteSwap {
+    moved: HashMap<Color32, Color32>,
+    lock: PaletteLock
+}
+
+impl PaletteSwap {
+    pub fn new(before: &[PaintColor], after: &[PaintColor]) -> Self {
+        let mut moved = HashMap::new();
+        for (index, swatch) in before.iter().enumerate() {
+            if before[..index].iter().any(|earlier| earlier.color == swatch.color) {
+                continue;
+            }
+            if let Some(edited) = after.iter().find(|edited| edited.id == swatch.id) && edited.color != swatch.color {
+                moved.insert(swatch.color, edited.color);
+            }
+        }
+        Self { moved, lock: PaletteLock::new(after) }
+    }
+
+    pub fn recolor(&self, color: Color32) -> Color32 {
+        self.moved.get(&color).copied().unwrap_or_else(|| self.lock.snap(color))
+    }
+}
+
+/// Writes `image` as an 8-bit palette PNG whose entries are the swatches of `lock` in palette order,
+/// snapping any pixel that isn't one of them. Transparency gets an entry of its own after the swatches
+/// when the image needs one.
+pub fn write_indexed_png(image: &ColorImage, path: &Path, lock: &PaletteLock) -> std::io::Result<()> {
+    let mut entries: Vec<Color32> = Vec::new();
+    for color in lock.colors.iter() {
+        if !entries.contains(color) {
+            entries.push(*color);
+        }
+    }
+    let snapped = image.pixels.iter().map(|pixel| lock.snap(*pixel)).collect::<Vec<Color32>>();
+    if snapped.contains(&Color32::TRANSPARENT) && !entries.contains(&Color32::TRANSPARENT) {
+        entries.push(Color32::TRANSPARENT);
+    }
+    if entries.len() > MAX_PNG_PALETTE {
+        return Err(std::io::Error::other(format!("An indexed PNG holds at most {MAX_PNG_PALETTE} colors")));
+    }
+    let entry_of = entries.iter().enumerate().map(|(index, color)| (*color, index as u8)).collect::<HashMap<Color32, u8>>();
+    let indices = snapped.iter().map(|color| entry_of[color]).collect::<Vec<u8>>();
+
+    let unmultiplied = entries.iter().map(|color| color.to_srgba_unmultiplied()).collect::<Vec<[u8; 4]>>();
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, image.size[0] as u32, image.size[1] as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(unmultiplied.iter().flat_map(|[r, g, b, _]| [*r, *g, *b]).collect::<Vec<u8>>());
+    if unmultiplied.iter().any(|[_, _, _, a]| *a < 255) {
+        encoder.set_trns(unmultiplied.iter().map(|[_, _, _, a]| *a).collect::<Vec<u8>>());
+    }
+    let mut png_writer = encoder.write_header().map_err(std::io::Error::other)?;
+    png_writer.write_image_data(&indices).map_err(std::io::Error::other)?;
+    png_writer.finish().map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paint_color(color: Color32) -> PaintColor {
+        PaintColor { color, ..Default::default() }
+    }
+
+    #[test]
+    fn snap_picks_the_nearest_swatch_or_transparency() {
+        let lock = PaletteLock::new(&[paint_color(Color32::RED), paint_color(Color32::BLUE)]);
+        assert_eq!(lock.snap(Color32::from_rgb(200, 30, 40)), Color32::RED);
+        assert_eq!(lock.snap(Color32::from_rgb(20, 0, 220)), Color32::BLUE);
+        assert_eq!(lock.snap(Color32::from_rgba_unmultiplied(255, 0, 0, 200)), Color32::RED);
+        // Faint pixels are closer to nothing than to any swatch
+        assert_eq!(lock.snap(Color32::from_rgba_unmultiplied(255, 0, 0, 40)), Color32::TRANSPARENT);
+        assert_eq!(lock.snap(Color32::TRANSPARENT), Color32::TRANSPARENT);
+    }
+
+    #[test]
+    fn recolor_follows_swatches_by_id() {
+        let red = paint_color(Color32::RED);
+        let blue = paint_color(Color32::BLUE);
+        let green = paint_color(Color32::GREEN);
+        let before = [red.clone(), blue.clone(), green.clone()];
+        // Red turns yellow and moves to the end, green is deleted
+        let after = [blue.clone(), PaintColor { color: Color32::YELLOW, ..red.clone() }];
+        let swap = PaletteSwap::new(&before, &after);
+        assert_eq!(swap.recolor(Color32::RED), Color32::YELLOW);
+        assert_eq!(swap.recolor(Color32::BLUE), Color32::BLUE);
+        assert_eq!(swap.recolor(Color32::GREEN), Color32::YELLOW);
+        assert_eq!(swap.recolor(Color32::TRANSPARENT), Color32::TRANSPARENT);
+    }
+
+    #[test]
+    fn recolor_moves_duplicate_colors_with_the_first_swatch() {
+        let first = paint_color(Color32::RED);
+        let second = paint_color(Color32::RED);
+        let after = [PaintColor { color: Color32::WHITE, ..second.clone() }, first.clone()];
+        let swap = PaletteSwap::new(&[first.clone(), second.clone()], &after);
+        assert_eq!(swap.recolor(Color32::RED), Color32::RED);
+    }
+
+    #[test]
+    fn indexed_png_uses_palette_order() {
+        let lock = PaletteLock::new(&[paint_color(Color32::RED), paint_color(Color32::BLUE), paint_color(Color32::RED)]);
+        let image = ColorImage::new([3, 1], vec![Color32::BLUE, Color32::TRANSPARENT, Color32::from_rgb(250, 10, 0)]);
+        let path = std::env::temp_dir().join(format!("indexed_test_{}.png", std::process::id()));
+        write_indexed_png(&image, &path, &lock).unwrap();
+
+        let decoder = png::Decoder::new(std::io::BufReader::new(File::open(&path).unwrap()));
+        let mut reader = decoder.read_info().unwrap();
+        let mut indices = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut indices).unwrap();
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        // The duplicate swatch shares its entry and transparency comes last
+        assert_eq!(info.palette.as_deref(), Some(&[255, 0, 0, 0, 0, 255, 0, 0, 0][..]));
+        assert_eq!(info.trns.as_deref(), Some(&[255, 255, 0][..]));
+        assert_eq!(indices, vec![1, 2, 0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn indexed_png_rejects_large_palettes() {
+        let swatches = (0..=MAX_PNG_PALETTE).map(|i| paint_color(Color32::from_rgb(i as u8, (i / 256) as u8, 0))).collect::<Vec<_>>();
+        let image = ColorImage::new([1, 1], vec![Color32::BLACK]);
+        let path = std::env::temp_dir().join(format!("indexed_large_test_{}.png", std::process::id()));
+        assert!(write_indexed_png(&image, &path, &PaletteLock::new(&swatches)).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,9 +1,12 @@
 
 use egui::{Color32, Id, Pos2, Vec2};
-use egui::{ColorImage, TextureHandle};
-use rand::random_range;
+use egui::{ColorImage, Painter, Rect, TextureHandle, TextureOptions};
+use crate::app::components::utils::brush::{Brush, Dab, StrokeCoverage};
 use crate::app::components::utils::draw_tool::Pencil;
+use crate::app::components::utils::image_color::{blend_pixel, BlendMode};
 use crate::app::components::utils::new_rand_id;
+use crate::app::components::utils::selection::Selection;
+use crate::app::components::utils::tiled_image::{TiledImage, TILE_SIZE};
 
 #[derive(Clone, PartialEq)]
 pub struct LayersContainer {
@@ -26,6 +29,13 @@ impl Default for LayersContainer {
     }
 }
 
+impl LayersContainer {
+    /// Visible layers ordered bottom to top, ready for `composite_layers`.
+    pub fn visible_layers_bottom_up(&self) -> Vec<&Layer> {
+        self.layers.iter().rev().filter(|layer| layer.is_visible).collect()
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Transform {
     pub position: Pos2,
@@ -46,59 +56,179 @@ pub struct Layer {
     pub id: Id,
     pub name: String,
     pub is_visible: bool,
+    pub blend_mode: BlendMode,
+    // 0.0 (transparent) to 1.0 (opaque)
+    pub opacity: f32,
     pub texture: LayerTexture
 }
 
-#[derive(Clone, PartialEq)]
-pub struct LayerTexture {
-    pub texture_handle: Option<TextureHandle>,
-    pub image_data: ColorImage,
-    pub layer_size: Vec2
+/// Pixel bounds inside a tile of the edits not yet uploaded to the GPU, `max` exclusive.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DirtyRect {
+    pub min: [usize; 2],
+    pub max: [usize; 2]
 }
 
-fn random_draw() -> bool {
-    random_range(0.0..1.0) > 0.5
+#[derive(Clone, PartialEq, Default)]
+pub struct TileTexture {
+    handle: Option<TextureHandle>,
+    dirty: Option<DirtyRect>
+}
+
+#[derive(Clone, PartialEq)]
+pub struct LayerTexture {
+    // One texture per tile, created the first time an allocated tile is drawn
+    tile_textures: Vec<TileTexture>,
+    pub image_data: TiledImage,
+    pub layer_size: Vec2,
+    // Bumped on every pixel edit so cached composites know when they are stale
+    revision: u64
 }
 
 impl LayerTexture {
     pub fn new(width: usize, height: usize) -> Self {
-        let mut filled: Vec<Color32>  = vec![];
-        filled.resize_with(width * height,|| Color32::from_rgba_unmultiplied(255, 255, 255, 0));
-        
-        let image_data = ColorImage::new([width, height], filled);
+        Self::from_tiled(TiledImage::new([width, height]))
+    }
+    pub fn from_image(image_data: ColorImage) -> Self {
+        Self::from_tiled(TiledImage::from_color_image(&image_data))
+    }
+    pub fn from_tiled(image_data: TiledImage) -> Self {
+        let [width, height] = image_data.size;
         Self {
-            texture_handle: None,
+            tile_textures: vec![TileTexture::default(); image_data.tiles_x() * image_data.tiles_y()],
             image_data,
-            layer_size: Vec2::new(width as f32, height as f32)
+            layer_size: Vec2::new(width as f32, height as f32),
+            revision: 0
+        }
+    }
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+    /// Swaps in new pixels of the same size, keeping the textures of tiles that didn't change.
+    pub fn replace_image(&mut self, image_data: TiledImage) {
+        if image_data.size != self.image_data.size {
+            *self = Self::from_tiled(image_data);
+            return;
+        }
+        let tiles_x = image_data.tiles_x();
+        for (tile_x, tile_y) in image_data.changed_tiles(&self.image_data) {
+            let tile_texture = &mut self.tile_textures[tile_y * tiles_x + tile_x];
+            if tile_texture.handle.is_some() {
+                tile_texture.dirty = Some(DirtyRect { min: [0, 0], max: [TILE_SIZE, TILE_SIZE] });
+            }
         }
+        self.image_data = image_data;
+        self.revision += 1;
     }
-    pub fn paint_at(&mut self, pos: Pos2, tool: Pencil, brush_size: f32, color: Color32) {
+    /// Forgets the GPU textures, for copies that must not share them with the original.
+    pub fn reset_textures(&mut self) {
+        self.tile_textures.fill(TileTexture::default());
+    }
+    /// Records an edit of the pixel at `idx`, growing the region of its tile to upload on the next `paint`.
+    pub fn mark_dirty(&mut self, idx: usize) {
+        self.revision += 1;
+        let width = self.image_data.width();
+        let (x, y) = (idx % width, idx / width);
+        let tile_texture = &mut self.tile_textures[(y / TILE_SIZE) * self.image_data.tiles_x() + x / TILE_SIZE];
+        // Tiles without a texture get uploaded whole anyway
+        if tile_texture.handle.is_none() {
+            return;
+        }
+        let (x, y) = (x % TILE_SIZE, y % TILE_SIZE);
+        tile_texture.dirty = Some(match tile_texture.dirty {
+            Some(rect) => DirtyRect {
+                min: [rect.min[0].min(x), rect.min[1].min(y)],
+                max: [rect.max[0].max(x + 1), rect.max[1].max(y + 1)]
+            },
+            None => DirtyRect { min: [x, y], max: [x + 1, y + 1] }
+        });
+    }
+    /// Draws the allocated tiles into `canvas_rect`, the on-screen rect of the whole layer. Tiles that
+    /// are on screen are uploaded first, sending only their dirty region once their texture exists.
+    pub fn paint(&mut self, ctx: &egui::Context, painter: &Painter, canvas_rect: Rect, tint: Color32) {
+        let scale = canvas_rect.width() / self.layer_size.x;
+        let tile_side = TILE_SIZE as f32 * scale;
+        let full_uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1., 1.));
+        for (tile_x, tile_y, tile) in self.image_data.allocated_tiles() {
+            let tile_rect = Rect::from_min_size(canvas_rect.min + Vec2::new(tile_x as f32, tile_y as f32) * tile_side, Vec2::splat(tile_side));
+            if !painter.clip_rect().intersects(tile_rect) {
+                continue;
+            }
+            let tile_texture = &mut self.tile_textures[tile_y * self.image_data.tiles_x() + tile_x];
+            let dirty = tile_texture.dirty.take();
+            if let (Some(handle), Some(rect)) = (&mut tile_texture.handle, dirty) {
+                let size = [rect.max[0] - rect.min[0], rect.max[1] - rect.min[1]];
+                let pixels = (rect.min[1]..rect.max[1])
+                    .flat_map(|y| tile[y * TILE_SIZE + rect.min[0]..y * TILE_SIZE + rect.max[0]].iter().copied())
+                    .collect();
+                handle.set_partial(rect.min, ColorImage::new(size, pixels), TextureOptions::LINEAR);
+            }
+            let handle = tile_texture.handle.get_or_insert_with(|| {
+                ctx.load_texture(format!("layer_tile_{tile_x}_{tile_y}"), ColorImage::new([TILE_SIZE, TILE_SIZE], tile.to_vec()), TextureOptions::LINEAR)
+            });
+            painter.image(handle.id(), tile_rect, full_uv, tint);
+        }
+    }
+    /// Pixel range covered by a dab of `radius` around `pos`, clamped to the layer.
+    fn dab_bounds(&self, pos: Pos2, radius: f32) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let width = self.layer_size.x.floor() as usize;
+        let height = self.layer_size.y.floor() as usize;
+        let min_x = (pos.x - radius - 1.).floor().max(0.) as usize;
+        let min_y = (pos.y - radius - 1.).floor().max(0.) as usize;
+        let max_x = ((pos.x + radius + 1.).ceil().max(0.) as usize).min(width);
+        let max_y = ((pos.y + radius + 1.).ceil().max(0.) as usize).min(height);
+        (min_x..max_x, min_y..max_y)
+    }
+    /// Blends `color` over every selected pixel by its `coverage` (0-255) and returns the previous color of every pixel it changed.
+    pub fn fill(&mut self, coverage: &[u8], color: Color32, selection: &Selection) -> Vec<(usize, Color32)> {
+        let mut changes: Vec<(usize, Color32)> = Vec::new();
+        for (idx, amount) in coverage.iter().enumerate().filter(|(_, amount)| **amount > 0) {
+            let previous = self.image_data.get(idx);
+            let filled = blend_pixel(previous, color.gamma_multiply(*amount as f32 / 255. * selection.coverage(idx)), BlendMode::Normal);
+            if filled != previous {
+                changes.push((idx, previous));
+                self.image_data.set(idx, filled);
+                self.mark_dirty(idx);
+            }
+        }
+        changes
+    }
+    /// Paints one dab inside the selection and returns the previous color of every pixel it changed.
+    pub fn paint_at(&mut self, dab: Dab, tool: Pencil, brush: &Brush, color: Color32, coverage: &mut StrokeCoverage, selection: &Selection) -> Vec<(usize, Color32)> {
+        let pos = dab.pos;
+        let brush_size = dab.size;
         let x = pos.x as usize;
         let y = pos.y as usize;
+        let width = self.layer_size.x.floor() as usize;
+        let flow = brush.flow.clamp(0., 1.);
+        let opacity = brush.opacity.clamp(0., 1.);
+        let mut changes: Vec<(usize, Color32)> = Vec::new();
         
-        // Simple circular brush
         match tool {
             Pencil::Brush => {
-                let radius = (brush_size / 2.0) as i32;
-                for dy in -radius..=radius {
-                    for dx in -radius..=radius {
-                        let px = x as i32 + dx;
-                        let py = y as i32 + dy;
-                        
-                        if px >= 0 && py >= 0 && 
-                        (px as usize) < self.layer_size.x.floor() as usize && 
-                        (py as usize) < self.layer_size.y.floor() as usize &&
-                            random_draw()
-                        {
-                            let dist_sq = dx * dx + dy * dy;
-                            if dist_sq <= radius * radius {
-                                let idx = py as usize * self.layer_size.x.floor() as usize + px as usize;
-                                self.image_data.pixels[idx] = color;
-                            }
+                // A rotated image tip reaches into the corners of its square
+                let reach = if brush.tip.is_some() { std::f32::consts::SQRT_2 } else { 1. };
+                let (x_range, y_range) = self.dab_bounds(pos, brush_size / 2.0 * reach);
+                for py in y_range {
+                    for px in x_range.clone() {
+                        let amount = brush.tip_coverage(&dab, Pos2::new(px as f32 + 0.5, py as f32 + 0.5) - pos) * flow;
+                        let idx = py * width + px;
+                        // Applied to the stroke's total so overlapping dabs can't build up past a soft selection edge
+                        let selected = selection.coverage(idx);
+                        if amount <= 0. || selected <= 0. {
+                            continue;
+                        }
+                        let previous = self.image_data.get(idx);
+                        let (original, total) = coverage.add(idx, previous, amount);
+                        let painted = blend_pixel(original, color.gamma_multiply(total * opacity * selected), BlendMode::Normal);
+                        if painted != previous {
+                            changes.push((idx, previous));
+                            self.image_data.set(idx, painted);
                         }
                     }
                 }
             },
+            // Hard-edged on purpose, for pixel work
             Pencil::Pen => {
                 let radius = (brush_size / 2.0) as i32;
                 for dy in -radius..=radius {
@@ -113,33 +243,53 @@ impl LayerTexture {
                             let dist_sq = dx * dx + dy * dy;
                             if dist_sq <= radius * radius {
                                 let idx = py as usize * self.layer_size.x.floor() as usize + px as usize;
-                                self.image_data.pixels[idx] = color;
+                                let previous = self.image_data.get(idx);
+                                let selected = selection.coverage(idx);
+                                let painted = if selected >= 1. {
+                                    color
+                                } else {
+                                    // Partly selected pixels are blended from what they were before the stroke
+                                    let (original, _) = coverage.add(idx, previous, 0.);
+                                    blend_pixel(original, color.gamma_multiply(selected), BlendMode::Normal)
+                                };
+                                if previous != painted {
+                                    changes.push((idx, previous));
+                                    self.image_data.set(idx, painted);
+                                }
                             }
                         }
                     }
                 }
             },
+            // Same tip and hardness falloff as the brush, taking paint away instead of adding it
             Pencil::Eraser => {
-                let x = pos.x as usize;
-                let y = pos.y as usize;
-                let radius = (brush_size / 2.0) as i32;
-                for dy in -radius..=radius {
-                    for dx in -radius..=radius {
-                        let px = x as i32 + dx;
-                        let py = y as i32 + dy;
-                        
-                        if px >= 0 && py >= 0 && 
-                        (px as usize) < self.layer_size.x.floor() as usize && 
-                        (py as usize) < self.layer_size.y.floor() as usize {
-                            let idx = py as usize * self.layer_size.x.floor() as usize + px as usize;
-
-                            self.image_data.pixels[idx] = Color32::from_white_alpha(0);
+                let reach = if brush.tip.is_some() { std::f32::consts::SQRT_2 } else { 1. };
+                let (x_range, y_range) = self.dab_bounds(pos, brush_size / 2.0 * reach);
+                for py in y_range {
+                    for px in x_range.clone() {
+                        let amount = brush.tip_coverage(&dab, Pos2::new(px as f32 + 0.5, py as f32 + 0.5) - pos) * flow;
+                        let idx = py * width + px;
+                        let selected = selection.coverage(idx);
+                        if amount <= 0. || selected <= 0. {
+                            continue;
+                        }
+                        let previous = self.image_data.get(idx);
+                        let (original, total) = coverage.add(idx, previous, amount);
+                        let erased = original.gamma_multiply(1. - total * opacity * selected);
+                        if erased != previous {
+                            changes.push((idx, previous));
+                            self.image_data.set(idx, erased);
                         }
                     }
                 }
-            }
+            },
+            // Every other tool edits whole regions at once instead of dab by dab
+            _ => {}
         }
-        
+        for (idx, _) in changes.iter() {
+            self.mark_dirty(*idx);
+        }
+        changes
     }
     
 }
@@ -148,14 +298,47 @@ impl LayerTexture {
 #[derive(Clone, PartialEq)]
 pub struct PaintColor {
     pub color: Color32,
-    pub id: Id
+    pub id: Id,
+    // Optional label, kept when palettes are shared as files
+    pub name: String
 }
 
 impl Default for PaintColor {
     fn default() -> Self {
         Self {
             color: Color32::BLACK,
-            id: new_rand_id()
+            id: new_rand_id(),
+            name: String::new()
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn erase(hardness: f32) -> LayerTexture {
+        let mut texture = LayerTexture::new(32, 32);
+        for idx in 0..32 * 32 {
+            texture.image_data.set(idx, Color32::RED);
+        }
+        let brush = Brush { size: 20., hardness, flow: 1., opacity: 1., ..Default::default() };
+        let dab = Dab { pos: Pos2::new(16., 16.), size: 20., angle: 0. };
+        texture.paint_at(dab, Pencil::Eraser, &brush, Color32::BLACK, &mut StrokeCoverage::default(), &Selection::new([32, 32]));
+        texture
+    }
+
+    #[test]
+    fn eraser_follows_hardness() {
+        let hard = erase(1.);
+        let soft = erase(0.);
+        // Both all but clear the center and leave the corners of the dab's square alone
+        for texture in [&hard, &soft] {
+            assert!(texture.image_data.get(16 * 32 + 16).a() < 8);
+            assert_eq!(texture.image_data.get(7 * 32 + 7), Color32::RED);
+        }
+        // Halfway out a hard eraser still clears everything while a soft one fades
+        let halfway = 16 * 32 + 21;
+        assert_eq!(hard.image_data.get(halfway).a(), 0);
+        assert!(soft.image_data.get(halfway).a() > 0 && soft.image_data.get(halfway).a() < 255);
+    }
+}
@@ -5,6 +5,23 @@ pub mod layer;
 pub mod draw_tool;
 pub mod pencil_cursor;
 pub mod create_paint;
+pub mod history;
+pub mod project_file;
+pub mod open_raster;
+pub mod stroke;
+pub mod brush;
+pub mod tiled_image;
+pub mod composite_cache;
+pub mod export_image;
+pub mod flood_fill;
+pub mod shape;
+pub mod selection;
+pub mod transform;
+pub mod eyedropper;
+pub mod color_space;
+pub mod palette;
+pub mod quantize;
+pub mod indexed;
 
 pub fn new_rand_id()-> egui::Id {
     let rand = rand::rng().random::<u32>();
@@ -0,0 +1,261 @@
+use std::io::{Cursor, Read, Seek, Write};
+
+use egui::{Color32, Vec2};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use quick_xml::escape::{escape, unescape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::app::components::utils::create_paint::MAX_CANVAS_SIZE;
+use crate::app::components::utils::image_color::{composite_layers, to_rgba_image, BlendMode};
+use crate::app::components::utils::layer::{Layer, LayerTexture};
+use crate::app::components::utils::new_rand_id;
+
+pub const OPEN_RASTER_EXTENSION: &str = "ora";
+const OPEN_RASTER_MIMETYPE: &str = "image/openraster";
+const THUMBNAIL_MAX_SIZE: u32 = 256;
+
+/// Layers read from an OpenRaster file, top-most first like `LayersContainer.layers`.
+pub struct OpenRasterDocument {
+    pub layer_size: Vec2,
+    pub layers: Vec<Layer>
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn composite_op(mode: BlendMode) -> &'static str {
+    match mode {
+        BlendMode::Normal => "svg:src-over",
+        BlendMode::Multiply => "svg:multiply",
+        BlendMode::Screen => "svg:screen",
+        BlendMode::Overlay => "svg:overlay",
+        BlendMode::Add => "svg:plus",
+        BlendMode::Darken => "svg:darken",
+        BlendMode::Lighten => "svg:lighten",
+        BlendMode::ColorDodge => "svg:color-dodge",
+        BlendMode::ColorBurn => "svg:color-burn",
+        BlendMode::Difference => "svg:difference",
+        BlendMode::Hue => "svg:hue",
+        BlendMode::Saturation => "svg:saturation",
+        BlendMode::Color => "svg:color",
+        BlendMode::Luminosity => "svg:luminosity"
+    }
+}
+
+fn blend_mode_from_op(op: &str) -> BlendMode {
+    BlendMode::ALL.into_iter().find(|mode| composite_op(*mode) == op).unwrap_or_default()
+}
+
+fn encode_png(image: &DynamicImage) -> std::io::Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png).map_err(std::io::Error::other)?;
+    Ok(png.into_inner())
+}
+
+pub fn write_open_raster(writer: impl Write + Seek, layers: &[Layer], layer_size: Vec2) -> std::io::Result<()> {
+    let width = layer_size.x as usize;
+    let height = layer_size.y as usize;
+    let mut zip = ZipWriter::new(writer);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The spec requires an uncompressed `mimetype` entry first so the format can be sniffed.
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(OPEN_RASTER_MIMETYPE.as_bytes())?;
+
+    let mut stack_xml = format!("<?xml version='1.0' encoding='UTF-8'?>\n<image version=\"0.0.5\" w=\"{width}\" h=\"{height}\">\n  <stack>\n");
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        let src = format!("data/layer{layer_idx}.png");
+        let visibility = if layer.is_visible { "visible" } else { "hidden" };
+        stack_xml.push_str(&format!(
+            "    <layer name=\"{}\" src=\"{src}\" visibility=\"{visibility}\" opacity=\"{:.3}\" x=\"0\" y=\"0\" composite-op=\"{}\"/>\n",
+            escape(layer.name.as_str()),
+            layer.opacity.clamp(0., 1.),
+            composite_op(layer.blend_mode)
+        ));
+        zip.start_file(src, stored)?;
+        zip.write_all(&encode_png(&DynamicImage::ImageRgba8(to_rgba_image(&layer.texture.image_data.to_color_image())))?)?;
+    }
+    stack_xml.push_str("  </stack>\n</image>\n");
+    zip.start_file("stack.xml", deflated)?;
+    zip.write_all(stack_xml.as_bytes())?;
+
+    let visible_layers = layers.iter().rev().filter(|layer| layer.is_visible).collect::<Vec<&Layer>>();
+    let merged = composite_layers(&visible_layers, [width, height]);
+    let merged_image = DynamicImage::ImageRgba8(to_rgba_image(&merged.to_color_image()));
+    zip.start_file("mergedimage.png", stored)?;
+    zip.write_all(&encode_png(&merged_image)?)?;
+    zip.start_file("Thumbnails/thumbnail.png", stored)?;
+    zip.write_all(&encode_png(&merged_image.resize(THUMBNAIL_MAX_SIZE, THUMBNAIL_MAX_SIZE, FilterType::Triangle))?)?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+struct StackEntry {
+    name: String,
+    src: String,
+    is_visible: bool,
+    blend_mode: BlendMode,
+    opacity: f32,
+    x: i64,
+    y: i64
+}
+
+fn attribute_value(element: &BytesStart, key: &str) -> std::io::Result<Option<String>> {
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(std::io::Error::other)?;
+        if attribute.key.as_ref() == key {
+            let value = unescape(&attribute.value).map_err(std::io::Error::other)?;
+            return Ok(Some(value.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_stack(stack_xml: &str) -> std::io::Result<(usize, usize, Vec<StackEntry>)> {
+    let mut reader = Reader::from_str(stack_xml);
+    let mut size: Option<(usize, usize)> = None;
+    let mut entries: Vec<StackEntry> = Vec::new();
+    // Visibility of enclosing <stack> groups; a hidden group hides every layer inside it.
+    let mut hidden_stacks: Vec<bool> = Vec::new();
+    loop {
+        match reader.read_event().map_err(std::io::Error::other)? {
+            Event::Start(element) | Event::Empty(element) if element.name().as_ref() == "image" => {
+                let width = attribute_value(&element, "w")?.and_then(|w| w.parse::<usize>().ok());
+                let height = attribute_value(&element, "h")?.and_then(|h| h.parse::<usize>().ok());
+                if let (Some(width), Some(height)) = (width, height) {
+                    size = Some((width, height));
+                }
+            },
+            Event::Start(element) if element.name().as_ref() == "stack" => {
+                hidden_stacks.push(attribute_value(&element, "visibility")?.as_deref() == Some("hidden"));
+            },
+            Event::End(element) if element.name().as_ref() == "stack" => {
+                hidden_stacks.pop();
+            },
+            Event::Start(element) | Event::Empty(element) if element.name().as_ref() == "layer" => {
+                let Some(src) = attribute_value(&element, "src")? else {
+                    continue;
+                };
+                let is_hidden = attribute_value(&element, "visibility")?.as_deref() == Some("hidden");
+                entries.push(StackEntry {
+                    name: attribute_value(&element, "name")?.unwrap_or_else(|| format!("Layer {}", entries.len() + 1)),
+                    src,
+                    is_visible: !is_hidden && !hidden_stacks.iter().any(|hidden| *hidden),
+                    blend_mode: attribute_value(&element, "composite-op")?.map(|op| blend_mode_from_op(&op)).unwrap_or_default(),
+                    opacity: attribute_value(&element, "opacity")?.and_then(|o| o.parse::<f32>().ok()).filter(|o| o.is_finite()).unwrap_or(1.).clamp(0., 1.),
+                    x: attribute_value(&element, "x")?.and_then(|x| x.parse().ok()).unwrap_or(0),
+                    y: attribute_value(&element, "y")?.and_then(|y| y.parse().ok()).unwrap_or(0)
+                });
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let (width, height) = size.ok_or_else(|| invalid_data("stack.xml has no image size"))?;
+    if width == 0 || height == 0 {
+        return Err(invalid_data("OpenRaster image is empty"));
+    }
+    if width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+        return Err(invalid_data(&format!("OpenRaster image is larger than {MAX_CANVAS_SIZE}x{MAX_CANVAS_SIZE}")));
+    }
+    Ok((width, height, entries))
+}
+
+pub fn read_open_raster(reader: impl Read + Seek) -> std::io::Result<OpenRasterDocument> {
+    let mut archive = ZipArchive::new(reader).map_err(std::io::Error::other)?;
+    let mut stack_xml = String::new();
+    archive.by_name("stack.xml").map_err(std::io::Error::other)?.read_to_string(&mut stack_xml)?;
+    let (width, height, entries) = parse_stack(&stack_xml)?;
+
+    let mut layers: Vec<Layer> = Vec::new();
+    for entry in entries {
+        let mut png = Vec::new();
+        archive.by_name(&entry.src).map_err(std::io::Error::other)?.read_to_end(&mut png)?;
+        let source = image::load_from_memory(&png).map_err(std::io::Error::other)?.to_rgba8();
+
+        // Layers may be smaller than the canvas and offset, so copy them into a full-size layer.
+        let mut texture = LayerTexture::new(width, height);
+        for (src_x, src_y, pixel) in source.enumerate_pixels() {
+            let x = src_x as i64 + entry.x;
+            let y = src_y as i64 + entry.y;
+            if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                continue;
+            }
+            let [r, g, b, a] = pixel.0;
+            texture.image_data.set(y as usize * width + x as usize, Color32::from_rgba_unmultiplied(r, g, b, a));
+        }
+        layers.push(Layer {
+            id: new_rand_id(),
+            name: entry.name,
+            is_visible: entry.is_visible,
+            blend_mode: entry.blend_mode,
+            opacity: entry.opacity,
+            texture
+        });
+    }
+    if layers.is_empty() {
+        return Err(invalid_data("OpenRaster file has no layers"));
+    }
+
+    Ok(OpenRasterDocument {
+        layer_size: Vec2::new(width as f32, height as f32),
+        layers
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_layers_and_attributes() {
+        let stack_xml = r#"<?xml version='1.0' encoding='UTF-8'?>
+<image version="0.0.5" w="64" h="32">
+  <stack>
+    <layer name="Ink &amp; paint" src="data/ink.png" opacity="0.25" x="4" y="-2" composite-op="svg:multiply"/>
+    <stack visibility="hidden">
+      <layer name="Inside hidden group" src="data/group.png" visibility="visible" opacity="NaN"/>
+    </stack>
+    <layer src="data/plain.png" visibility="hidden" opacity="3" composite-op="krita:unknown"/>
+    <layer name="No source"/>
+  </stack>
+</image>"#;
+        let (width, height, entries) = parse_stack(stack_xml).unwrap();
+        assert_eq!((width, height), (64, 32));
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(entries[0].name, "Ink & paint");
+        assert_eq!(entries[0].src, "data/ink.png");
+        assert!(entries[0].is_visible);
+        assert_eq!(entries[0].blend_mode, BlendMode::Multiply);
+        assert_eq!(entries[0].opacity, 0.25);
+        assert_eq!((entries[0].x, entries[0].y), (4, -2));
+
+        assert_eq!(entries[1].name, "Inside hidden group");
+        assert!(!entries[1].is_visible);
+        assert_eq!(entries[1].opacity, 1.);
+
+        // Missing and unknown attributes fall back to defaults
+        assert_eq!(entries[2].name, "Layer 3");
+        assert!(!entries[2].is_visible);
+        assert_eq!(entries[2].blend_mode, BlendMode::Normal);
+        assert_eq!(entries[2].opacity, 1.);
+        assert_eq!((entries[2].x, entries[2].y), (0, 0));
+    }
+
+    #[test]
+    fn rejects_missing_empty_and_oversized_images() {
+        assert!(parse_stack("<image><stack/></image>").is_err());
+        assert!(parse_stack(r#"<image w="0" h="10"><stack/></image>"#).is_err());
+        let oversized = format!(r#"<image w="{}" h="10"><stack/></image>"#, MAX_CANVAS_SIZE + 1);
+        assert_eq!(parse_stack(&oversized).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+        assert!(parse_stack(&format!(r#"<image w="{MAX_CANVAS_SIZE}" h="10"><stack/></image>"#)).is_ok());
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt::Display;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use egui::{Color32, Id};
+
+use crate::app::components::utils::color_space::{from_linear, parse_color, to_hex};
+use crate::app::components::utils::layer::PaintColor;
+use crate::app::components::utils::new_rand_id;
+
+pub const DEFAULT_PALETTE_NAME: &str = "Default";
+const GPL_HEADER: &str = "GIMP Palette";
+const ASE_MAGIC: &[u8; 4] = b"ASEF";
+const ASE_GROUP_START: u16 = 0xC001;
+const ASE_GROUP_END: u16 = 0xC002;
+const ASE_COLOR: u16 = 0x0001;
+// Swatches written as plain global colors rather than spot or process colors
+const ASE_COLOR_TYPE_NORMAL: u16 = 2;
+
+/// Named set of swatches. Several can be kept side by side and shared as palette files.
+#[derive(Clone, PartialEq)]
+pub struct Palette {
+    pub id: Id,
+    pub name: String,
+    pub colors: Vec<PaintColor>
+}
+
+impl Palette {
+    pub fn new(name: &str, colors: Vec<PaintColor>) -> Self {
+        Self { id: new_rand_id(), name: name.to_string(), colors }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PaletteFormat {
+    #[default]
+    Gpl,
+    Ase,
+    Hex
+}
+
+impl PaletteFormat {
+    pub const ALL: [PaletteFormat; 3] = [PaletteFormat::Gpl, PaletteFormat::Ase, PaletteFormat::Hex];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "gpl",
+            PaletteFormat::Ase => "ase",
+            PaletteFormat::Hex => "hex"
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        Self::ALL.into_iter().find(|format| format.extension() == extension)
+    }
+}
+
+impl Display for PaletteFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PaletteFormat::Gpl => "GIMP palette",
+            PaletteFormat::Ase => "Adobe swatch exchange",
+            PaletteFormat::Hex => "Hex list"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn unknown_format() -> std::io::Error {
+    std::io::Error::other("Palettes can only be .gpl, .ase or .hex files")
+}
+
+fn swatch(color: Color32, name: &str) -> PaintColor {
+    PaintColor { color, name: name.to_string(), ..Default::default() }
+}
+
+/// Reads a palette file, picking the format from the extension. Palettes without a name of their own
+/// are named after the file.
+pub fn read_palette(path: &Path) -> std::io::Result<Palette> {
+    let format = PaletteFormat::from_path(path).ok_or_else(unknown_format)?;
+    let bytes = std::fs::read(path)?;
+    let mut palette = match format {
+        PaletteFormat::Gpl => decode_gpl(&String::from_utf8_lossy(&bytes))?,
+        PaletteFormat::Ase => decode_ase(&bytes)?,
+        PaletteFormat::Hex => decode_hex(&String::from_utf8_lossy(&bytes))?
+    };
+    if palette.name.trim().is_empty() {
+        palette.name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    }
+    Ok(palette)
+}
+
+/// Writes `palette` in the format matching the extension of `path`. GIMP and Adobe palettes have no
+/// alpha, so their swatches are saved opaque.
+pub fn write_palette(palette: &Palette, path: &Path) -> std::io::Result<()> {
+    let bytes = match PaletteFormat::from_path(path).ok_or_else(unknown_format)? {
+        PaletteFormat::Gpl => encode_gpl(palette).into_bytes(),
+        PaletteFormat::Ase => encode_ase(palette),
+        PaletteFormat::Hex => encode_hex(palette).into_bytes()
+    };
+    std::fs::write(path, bytes)
+}
+
+fn decode_gpl(text: &str) -> std::io::Result<Palette> {
+    let mut lines = text.trim_start_matches('\u{feff}').lines().enumerate();
+    if lines.next().is_none_or(|(_, line)| line.trim() != GPL_HEADER) {
+        return Err(invalid_data("Not a GIMP palette"));
+    }
+    let mut palette = Palette::new("", Vec::new());
+    for (number, line) in lines {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("Name:") {
+            palette.name = name.trim().to_string();
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Columns:") {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let mut channel = || fields.next().and_then(|field| field.parse::<u8>().ok());
+        let (Some(r), Some(g), Some(b)) = (channel(), channel(), channel()) else {
+            return Err(invalid_data(&format!("Line {} of the GIMP palette is not a color", number + 1)));
+        };
+        let name = fields.collect::<Vec<&str>>().join(" ");
+        palette.colors.push(swatch(Color32::from_rgb(r, g, b), &name));
+    }
+    Ok(palette)
+}
+
+fn encode_gpl(palette: &Palette) -> String {
+    let mut text = format!("{GPL_HEADER}\nName: {}\nColumns: 6\n#\n", palette.name);
+    for paint_color in palette.colors.iter() {
+        let [r, g, b, _] = paint_color.color.to_srgba_unmultiplied();
+        let line = format!("{r:3} {g:3} {b:3}\t{}", paint_color.name);
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+/// One color per line as `rrggbb`, or `rrggbbaa` for translucent swatches, with or without a `#`.
+fn decode_hex(text: &str) -> std::io::Result<Palette> {
+    let mut palette = Palette::new("", Vec::new());
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        // Some tools put comments above the colors
+        if line.is_empty() || line.starts_with(';') || line.starts_with("//") {
+            continue;
+        }
+        let color = parse_color(line).ok_or_else(|| invalid_data(&format!("Line {} of the hex list is not a color", number + 1)))?;
+        palette.colors.push(swatch(color, ""));
+    }
+    Ok(palette)
+}
+
+fn encode_hex(palette: &Palette) -> String {
+    palette.colors.iter()
+        .map(|paint_color| format!("{}\n", to_hex(paint_color.color).trim_start_matches('#')))
+        .collect()
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut buffer = [0u8; 2];
+    reader.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+fn read_f32(reader: &mut impl Read) -> std::io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+/// Names are UTF-16 with a length counted in code units, the closing null included.
+fn read_ase_name(reader: &mut impl Read) -> std::io::Result<String> {
+    let len = read_u16(reader)?;
+    let units = (0..len).map(|_| read_u16(reader)).collect::<std::io::Result<Vec<u16>>>()?;
+    let units = units.strip_suffix(&[0]).unwrap_or(&units);
+    Ok(String::from_utf16_lossy(units))
+}
+
+fn write_ase_name(bytes: &mut Vec<u8>, name: &str) {
+    let units = name.encode_utf16().chain([0]).collect::<Vec<u16>>();
+    bytes.extend((units.len() as u16).to_be_bytes());
+    bytes.extend(units.iter().flat_map(|unit| unit.to_be_bytes()));
+}
+
+/// sRGB from CIE L*a*b* against the D50 white Adobe applications use, clipped to the gamut.
+fn lab_to_rgb(lightness: f32, a: f32, b: f32) -> [f32; 3] {
+    let fy = (lightness + 16.) / 116.;
+    let inverse = |t: f32| if t > 6. / 29. { t.powi(3) } else { 3. * (6f32 / 29.).powi(2) * (t - 4. / 29.) };
+    let [x, y, z] = [0.9642 * inverse(fy + a / 500.), inverse(fy), 0.8249 * inverse(fy - b / 200.)];
+    [
+        3.133856 * x - 1.616867 * y - 0.4906146 * z,
+        -0.9787684 * x + 1.916142 * y + 0.033454 * z,
+        0.0719453 * x - 0.2289914 * y + 1.405243 * z
+    ].map(|channel| from_linear(channel.clamp(0., 1.)))
+}
+
+fn decode_ase(bytes: &[u8]) -> std::io::Result<Palette> {
+    let mut reader = Cursor::new(bytes);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != ASE_MAGIC {
+        return Err(invalid_data("Not an Adobe swatch exchange file"));
+    }
+    let major_version = read_u16(&mut reader)?;
+    let _minor_version = read_u16(&mut reader)?;
+    if major_version != 1 {
+        return Err(invalid_data("Unsupported Adobe swatch exchange version"));
+    }
+    let mut palette = Palette::new("", Vec::new());
+    for _ in 0..read_u32(&mut reader)? {
+        let block_type = read_u16(&mut reader)?;
+        let len = read_u32(&mut reader)? as u64;
+        let start = reader.position();
+        match block_type {
+            // The first group names the palette; groups themselves aren't kept
+            ASE_GROUP_START if palette.name.is_empty() => palette.name = read_ase_name(&mut reader)?,
+            ASE_COLOR => {
+                let name = read_ase_name(&mut reader)?;
+                let mut model = [0u8; 4];
+                reader.read_exact(&mut model)?;
+                let rgb = match &model {
+                    b"RGB " => [read_f32(&mut reader)?, read_f32(&mut reader)?, read_f32(&mut reader)?],
+                    b"CMYK" => {
+                        let [c, m, y, k] = [read_f32(&mut reader)?, read_f32(&mut reader)?, read_f32(&mut reader)?, read_f32(&mut reader)?];
+                        [c, m, y].map(|channel| (1. - channel) * (1. - k))
+                    },
+                    // Lightness is stored as a 0-1 fraction, a and b as they are
+                    b"LAB " => lab_to_rgb(read_f32(&mut reader)? * 100., read_f32(&mut reader)?, read_f32(&mut reader)?),
+                    b"Gray" => [read_f32(&mut reader)?; 3],
+                    _ => return Err(invalid_data("Unknown color model in Adobe swatch exchange file"))
+                };
+                let [r, g, b] = rgb.map(|channel| (channel.clamp(0., 1.) * 255.).round() as u8);
+                palette.colors.push(swatch(Color32::from_rgb(r, g, b), &name));
+            },
+            _ => {}
+        }
+        // Blocks say how long they are, so anything unread (like the color type) is skipped
+        reader.set_position(start + len);
+    }
+    Ok(palette)
+}
+
+fn encode_ase(palette: &Palette) -> Vec<u8> {
+    let mut blocks: Vec<(u16, Vec<u8>)> = Vec::new();
+    let mut group = Vec::new();
+    write_ase_name(&mut group, &palette.name);
+    blocks.push((ASE_GROUP_START, group));
+    for paint_color in palette.colors.iter() {
+        let mut block = Vec::new();
+        write_ase_name(&mut block, &paint_color.name);
+        block.extend(b"RGB ");
+        let [r, g, b, _] = paint_color.color.to_srgba_unmultiplied();
+        for channel in [r, g, b] {
+            block.extend((channel as f32 / 255.).to_be_bytes());
+        }
+        block.extend(ASE_COLOR_TYPE_NORMAL.to_be_bytes());
+        blocks.push((ASE_COLOR, block));
+    }
+    blocks.push((ASE_GROUP_END, Vec::new()));
+
+    let mut bytes = ASE_MAGIC.to_vec();
+    bytes.extend(1_u16.to_be_bytes());
+    bytes.extend(0_u16.to_be_bytes());
+    bytes.extend((blocks.len() as u32).to_be_bytes());
+    for (block_type, block) in blocks {
+        bytes.extend(block_type.to_be_bytes());
+        bytes.extend((block.len() as u32).to_be_bytes());
+        bytes.extend(block);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_palette() -> Palette {
+        Palette::new("Sunset tones", vec![
+            swatch(Color32::from_rgb(255, 94, 77), "Coral red"),
+            swatch(Color32::from_rgb(18, 52, 86), ""),
+            swatch(Color32::from_rgb(0, 0, 0), "Ink")
+        ])
+    }
+
+    fn colors_and_names(palette: &Palette) -> Vec<(Color32, String)> {
+        palette.colors.iter().map(|c| (c.color, c.name.clone())).collect()
+    }
+
+    #[test]
+    fn gpl_round_trip() {
+        let palette = sample_palette();
+        let decoded = decode_gpl(&encode_gpl(&palette)).unwrap();
+        assert_eq!(decoded.name, palette.name);
+        assert_eq!(colors_and_names(&decoded), colors_and_names(&palette));
+    }
+
+    #[test]
+    fn gpl_decodes_gimp_files() {
+        let text = "\u{feff}GIMP Palette\r\nName: Pastels\r\nColumns: 4\r\n# A comment\r\n\r\n255 200 200\tBlush pink\r\n  0   0 255 \r\n";
+        let palette = decode_gpl(text).unwrap();
+        assert_eq!(palette.name, "Pastels");
+        assert_eq!(colors_and_names(&palette), vec![
+            (Color32::from_rgb(255, 200, 200), "Blush pink".to_string()),
+            (Color32::from_rgb(0, 0, 255), String::new())
+        ]);
+        assert!(decode_gpl("Not a palette\n255 0 0").is_err());
+        assert_eq!(decode_gpl("GIMP Palette\n255 0\n").err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+        assert!(decode_gpl("GIMP Palette\n256 0 0\n").is_err());
+    }
+
+    #[test]
+    fn hex_round_trip_keeps_alpha() {
+        let mut palette = sample_palette();
+        palette.colors.push(swatch(Color32::from_rgba_unmultiplied(10, 20, 30, 128), ""));
+        let text = encode_hex(&palette);
+        assert!(text.starts_with("ff5e4d\n"));
+        let decoded = decode_hex(&text).unwrap();
+        assert_eq!(decoded.colors.iter().map(|c| c.color).collect::<Vec<_>>(), palette.colors.iter().map(|c| c.color).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn hex_decodes_comments_and_hashes() {
+        let palette = decode_hex("; exported palette\n// another comment\n#ff0000\n\n00ff00\n").unwrap();
+        assert_eq!(palette.colors.iter().map(|c| c.color).collect::<Vec<_>>(), vec![Color32::RED, Color32::GREEN]);
+        assert!(decode_hex("ff0000\nnot a color\n").is_err());
+    }
+
+    #[test]
+    fn ase_round_trip() {
+        let palette = sample_palette();
+        let bytes = encode_ase(&palette);
+        assert_eq!(&bytes[..4], ASE_MAGIC);
+        let decoded = decode_ase(&bytes).unwrap();
+        assert_eq!(decoded.name, palette.name);
+        assert_eq!(colors_and_names(&decoded), colors_and_names(&palette));
+    }
+
+    #[test]
+    fn ase_decodes_other_color_models() {
+        let block = |model: &[u8; 4], values: &[f32]| {
+            let mut block = Vec::new();
+            write_ase_name(&mut block, "");
+            block.extend(model);
+            for value in values {
+                block.extend(value.to_be_bytes());
+            }
+            block.extend(ASE_COLOR_TYPE_NORMAL.to_be_bytes());
+            block
+        };
+        let blocks = [
+            block(b"Gray", &[0.5]),
+            block(b"CMYK", &[0., 1., 1., 0.]),
+            block(b"LAB ", &[1., 0., 0.])
+        ];
+        let mut bytes = ASE_MAGIC.to_vec();
+        bytes.extend(1_u16.to_be_bytes());
+        bytes.extend(0_u16.to_be_bytes());
+        bytes.extend((blocks.len() as u32).to_be_bytes());
+        for block in blocks {
+            bytes.extend(ASE_COLOR.to_be_bytes());
+            bytes.extend((block.len() as u32).to_be_bytes());
+            bytes.extend(block);
+        }
+        let palette = decode_ase(&bytes).unwrap();
+        assert_eq!(palette.colors.iter().map(|c| c.color).collect::<Vec<_>>(), vec![Color32::from_gray(128), Color32::RED, Color32::WHITE]);
+
+        assert!(decode_ase(b"ASEF").is_err());
+        assert!(decode_ase(b"NOPE\0\x01\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn files_pick_the_format_from_the_extension() {
+        let dir = std::env::temp_dir().join(format!("palette_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let palette = sample_palette();
+        for format in PaletteFormat::ALL {
+            let path = dir.join(format!("Warm.{}", format.extension().to_uppercase()));
+            write_palette(&palette, &path).unwrap();
+            let read = read_palette(&path).unwrap();
+            // Hex lists have no name of their own, so they take the file's
+            let name = if format == PaletteFormat::Hex { "Warm" } else { "Sunset tones" };
+            assert_eq!(read.name, name, "{format}");
+            assert_eq!(read.colors.iter().map(|c| c.color).collect::<Vec<_>>(), palette.colors.iter().map(|c| c.color).collect::<Vec<_>>());
+        }
+        assert!(write_palette(&palette, &dir.join("Warm.txt")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -72,11 +72,13 @@ impl PencilCursor  {
         
         match self.pencil {
             
-            Pencil::Brush | Pencil::Pen => {
+            Pencil::Brush | Pencil::Pen | Pencil::Eraser => {
                 painter.circle_stroke(self.pos, self.radius / 2., Stroke::new(1., Color32::BLACK));
             },
-            Pencil::Eraser => {
-                painter.rect_stroke(egui::Rect::from_center_size(self.pos, Vec2::new(self.radius, self.radius)), 0.0, Stroke::new(1., Color32::BLACK), egui::StrokeKind::Middle);
+            // Every other tool starts from a single pixel, so the brush size doesn't apply
+            _ => {
+                painter.line_segment([self.pos - Vec2::new(6., 0.), self.pos + Vec2::new(6., 0.)], Stroke::new(1., Color32::BLACK));
+                painter.line_segment([self.pos - Vec2::new(0., 6.), self.pos + Vec2::new(0., 6.)], Stroke::new(1., Color32::BLACK));
             }
         }
     }
@@ -0,0 +1,449 @@
+use std::io::{Cursor, Read, Write};
+
+use egui::{Color32, ColorImage, Pos2, Vec2};
+use image::{ImageFormat, RgbaImage};
+
+use crate::app::components::utils::create_paint::MAX_CANVAS_SIZE;
+use crate::app::components::utils::draw_tool::Pencil;
+use crate::app::components::utils::image_color::BlendMode;
+use crate::app::components::utils::layer::{Layer, LayerTexture, PaintColor, Transform};
+use crate::app::components::utils::new_rand_id;
+use crate::app::components::utils::palette::{DEFAULT_PALETTE_NAME, Palette};
+
+pub const PROJECT_EXTENSION: &str = "paint";
+const PROJECT_MAGIC: &[u8; 8] = b"EGPAINT\0";
+const PROJECT_VERSION: u32 = 5;
+
+/// Everything needed to restore a painting session from disk.
+#[derive(Clone, PartialEq)]
+pub struct ProjectDocument {
+    pub layer_size: Vec2,
+    pub layers: Vec<Layer>,
+    pub transform: Transform,
+    pub current_layer: Option<usize>,
+    pub palettes: Vec<Palette>,
+    pub current_palette: Option<usize>,
+    // Index into the current palette
+    pub current_color: Option<usize>,
+    // Palette the layers are locked to in an indexed document
+    pub indexed_palette: Option<usize>,
+    pub current_stroke_width: f32,
+    pub current_pencil: Pencil
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_i32(writer: &mut impl Write, value: i32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32(writer: &mut impl Write, value: f32) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    write_u32(writer, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_i32(reader: &mut impl Read) -> std::io::Result<i32> {
+    Ok(i32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_f32(reader: &mut impl Read) -> std::io::Result<f32> {
+    Ok(f32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_bytes(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != len {
+        return Err(invalid_data("Unexpected end of project file"));
+    }
+    Ok(buffer)
+}
+
+fn read_string(reader: &mut impl Read) -> std::io::Result<String> {
+    String::from_utf8(read_bytes(reader)?).map_err(|_| invalid_data("Palette name is not valid UTF-8"))
+}
+
+fn pencil_to_u8(pencil: Pencil) -> u8 {
+    match pencil {
+        Pencil::Brush => 0,
+        Pencil::Pen => 1,
+        Pencil::Eraser => 2,
+        Pencil::Fill => 3,
+        Pencil::Line => 4,
+        Pencil::Rect => 5,
+        Pencil::Ellipse => 6,
+        Pencil::Polygon => 7,
+        Pencil::SelectRect => 8,
+        Pencil::SelectEllipse => 9,
+        Pencil::Lasso => 10,
+        Pencil::PolygonLasso => 11,
+        Pencil::MagicWand => 12,
+        Pencil::Move => 13,
+        Pencil::Eyedropper => 14
+    }
+}
+
+fn pencil_from_u8(value: u8) -> std::io::Result<Pencil> {
+    match value {
+        0 => Ok(Pencil::Brush),
+        1 => Ok(Pencil::Pen),
+        2 => Ok(Pencil::Eraser),
+        3 => Ok(Pencil::Fill),
+        4 => Ok(Pencil::Line),
+        5 => Ok(Pencil::Rect),
+        6 => Ok(Pencil::Ellipse),
+        7 => Ok(Pencil::Polygon),
+        8 => Ok(Pencil::SelectRect),
+        9 => Ok(Pencil::SelectEllipse),
+        10 => Ok(Pencil::Lasso),
+        11 => Ok(Pencil::PolygonLasso),
+        12 => Ok(Pencil::MagicWand),
+        13 => Ok(Pencil::Move),
+        14 => Ok(Pencil::Eyedropper),
+        _ => Err(invalid_data("Unknown draw tool in project file"))
+    }
+}
+
+fn blend_mode_to_u8(mode: BlendMode) -> u8 {
+    BlendMode::ALL.iter().position(|m| *m == mode).unwrap_or(0) as u8
+}
+
+fn blend_mode_from_u8(value: u8) -> std::io::Result<BlendMode> {
+    BlendMode::ALL.get(value as usize).copied().ok_or_else(|| invalid_data("Unknown blend mode in project file"))
+}
+
+fn optional_index(index: Option<usize>) -> i32 {
+    index.map(|i| i as i32).unwrap_or(-1)
+}
+
+fn read_optional_index(reader: &mut impl Read, len: usize) -> std::io::Result<Option<usize>> {
+    let index = read_i32(reader)?;
+    Ok(if index >= 0 && (index as usize) < len { Some(index as usize) } else { None })
+}
+
+// Layer pixels are stored premultiplied, exactly as `ColorImage` holds them, so a save/open round trip is lossless.
+fn encode_layer_png(image: &ColorImage) -> std::io::Result<Vec<u8>> {
+    let [width, height] = image.size;
+    let buffer = RgbaImage::from_raw(width as u32, height as u32, image.as_raw().to_vec())
+        .ok_or_else(|| invalid_data("Layer pixels do not match the layer size"))?;
+    let mut png = Cursor::new(Vec::new());
+    buffer.write_to(&mut png, ImageFormat::Png).map_err(std::io::Error::other)?;
+    Ok(png.into_inner())
+}
+
+fn decode_layer_png(bytes: &[u8], size: [usize; 2]) -> std::io::Result<ColorImage> {
+    let decoded = image::load_from_memory_with_format(bytes, ImageFormat::Png).map_err(std::io::Error::other)?.to_rgba8();
+    if decoded.width() as usize != size[0] || decoded.height() as usize != size[1] {
+        return Err(invalid_data("Layer size does not match the canvas size"));
+    }
+    Ok(ColorImage::from_rgba_premultiplied(size, decoded.as_raw()))
+}
+
+impl ProjectDocument {
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(PROJECT_MAGIC)?;
+        write_u32(writer, PROJECT_VERSION)?;
+
+        write_u32(writer, self.layer_size.x as u32)?;
+        write_u32(writer, self.layer_size.y as u32)?;
+        write_f32(writer, self.transform.position.x)?;
+        write_f32(writer, self.transform.position.y)?;
+        write_f32(writer, self.transform.scale)?;
+        write_f32(writer, self.current_stroke_width)?;
+        writer.write_all(&[pencil_to_u8(self.current_pencil)])?;
+
+        write_u32(writer, self.palettes.len() as u32)?;
+        for palette in self.palettes.iter() {
+            write_bytes(writer, palette.name.as_bytes())?;
+            write_u32(writer, palette.colors.len() as u32)?;
+            for paint_color in palette.colors.iter() {
+                writer.write_all(&paint_color.color.to_array())?;
+                write_bytes(writer, paint_color.name.as_bytes())?;
+            }
+        }
+        write_i32(writer, optional_index(self.current_palette))?;
+        write_i32(writer, optional_index(self.current_color))?;
+        write_i32(writer, optional_index(self.indexed_palette))?;
+
+        write_u32(writer, self.layers.len() as u32)?;
+        write_i32(writer, optional_index(self.current_layer))?;
+        for layer in self.layers.iter() {
+            write_bytes(writer, layer.name.as_bytes())?;
+            writer.write_all(&[layer.is_visible as u8])?;
+            writer.write_all(&[blend_mode_to_u8(layer.blend_mode)])?;
+            write_f32(writer, layer.opacity)?;
+            write_bytes(writer, &encode_layer_png(&layer.texture.image_data.to_color_image())?)?;
+        }
+        writer.flush()
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> std::io::Result<Self> {
+        let magic: [u8; 8] = read_array(reader)?;
+        if &magic != PROJECT_MAGIC {
+            return Err(invalid_data("Not a painting project file"));
+        }
+        let version = read_u32(reader)?;
+        if version == 0 || version > PROJECT_VERSION {
+            return Err(invalid_data("Unsupported project file version"));
+        }
+
+        let width = read_u32(reader)? as usize;
+        let height = read_u32(reader)? as usize;
+        if width == 0 || height == 0 {
+            return Err(invalid_data("Project canvas is empty"));
+        }
+        if width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+            return Err(invalid_data(&format!("Project canvas is larger than {MAX_CANVAS_SIZE}x{MAX_CANVAS_SIZE}")));
+        }
+        let transform = Transform {
+            position: Pos2::new(read_f32(reader)?, read_f32(reader)?),
+            scale: read_f32(reader)?
+        };
+        let current_stroke_width = read_f32(reader)?;
+        if !transform.position.x.is_finite() || !transform.position.y.is_finite() {
+            return Err(invalid_data("Project view position is not a number"));
+        }
+        if !transform.scale.is_finite() || transform.scale <= 0. {
+            return Err(invalid_data("Project view scale must be positive"));
+        }
+        if !current_stroke_width.is_finite() || current_stroke_width <= 0. {
+            return Err(invalid_data("Project stroke width must be positive"));
+        }
+        let [pencil] = read_array(reader)?;
+        let current_pencil = pencil_from_u8(pencil)?;
+
+        let (palettes, current_palette) = if version >= 4 {
+            let palettes_len = read_u32(reader)? as usize;
+            let mut palettes: Vec<Palette> = Vec::new();
+            for _ in 0..palettes_len {
+                let name = read_string(reader)?;
+                let colors_len = read_u32(reader)? as usize;
+                let mut colors: Vec<PaintColor> = Vec::new();
+                for _ in 0..colors_len {
+                    let [r, g, b, a] = read_array(reader)?;
+                    colors.push(PaintColor { color: Color32::from_rgba_premultiplied(r, g, b, a), id: new_rand_id(), name: read_string(reader)? });
+                }
+                palettes.push(Palette::new(&name, colors));
+            }
+            let current_palette = read_optional_index(reader, palettes.len())?;
+            (palettes, current_palette)
+        } else {
+            // Older files hold a single palette of unnamed swatches
+            let colors_len = read_u32(reader)? as usize;
+            let mut colors: Vec<PaintColor> = Vec::new();
+            for _ in 0..colors_len {
+                let [r, g, b, a] = read_array(reader)?;
+                colors.push(PaintColor { color: Color32::from_rgba_premultiplied(r, g, b, a), ..Default::default() });
+            }
+            (vec![Palette::new(DEFAULT_PALETTE_NAME, colors)], Some(0))
+        };
+        if palettes.is_empty() {
+            return Err(invalid_data("Project has no palettes"));
+        }
+        let current_color = read_optional_index(reader, current_palette.map_or(0, |i| palettes[i].colors.len()))?;
+        let indexed_palette = if version >= 5 { read_optional_index(reader, palettes.len())? } else { None };
+
+        let layers_len = read_u32(reader)? as usize;
+        let current_layer = read_optional_index(reader, layers_len)?;
+        let mut layers: Vec<Layer> = Vec::new();
+        for _ in 0..layers_len {
+            let name = String::from_utf8(read_bytes(reader)?).map_err(|_| invalid_data("Layer name is not valid UTF-8"))?;
+            let [is_visible] = read_array(reader)?;
+            // Version 1 files predate blend modes
+            let blend_mode = if version >= 2 { blend_mode_from_u8(read_array::<1>(reader)?[0])? } else { BlendMode::Normal };
+            let opacity = if version >= 3 { read_f32(reader)?.clamp(0., 1.) } else { 1. };
+            let image_data = decode_layer_png(&read_bytes(reader)?, [width, height])?;
+            layers.push(Layer {
+                id: new_rand_id(),
+                name,
+                is_visible: is_visible != 0,
+                blend_mode,
+                opacity,
+                texture: LayerTexture::from_image(image_data)
+            });
+        }
+        if layers.is_empty() {
+            return Err(invalid_data("Project has no layers"));
+        }
+
+        Ok(Self {
+            layer_size: Vec2::new(width as f32, height as f32),
+            layers,
+            transform,
+            current_layer,
+            palettes,
+            current_palette,
+            current_color,
+            indexed_palette,
+            current_stroke_width,
+            current_pencil
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::components::utils::tiled_image::TiledImage;
+
+    fn layer(name: &str, size: [usize; 2], pixel: Color32) -> Layer {
+        let mut image = TiledImage::new(size);
+        image.set(1, pixel);
+        Layer { id: new_rand_id(), name: name.to_string(), is_visible: true, blend_mode: BlendMode::Normal, opacity: 1., texture: LayerTexture::from_tiled(image) }
+    }
+
+    fn document() -> ProjectDocument {
+        let mut hidden = layer("Sketch", [4, 3], Color32::from_rgba_premultiplied(10, 20, 30, 40));
+        hidden.is_visible = false;
+        hidden.blend_mode = BlendMode::Multiply;
+        hidden.opacity = 0.5;
+        let swatches = vec![
+            PaintColor { color: Color32::RED, name: "Red".to_string(), ..Default::default() },
+            PaintColor { color: Color32::from_rgba_premultiplied(0, 0, 50, 128), ..Default::default() }
+        ];
+        ProjectDocument {
+            layer_size: Vec2::new(4., 3.),
+            layers: vec![layer("Ink", [4, 3], Color32::BLUE), hidden],
+            transform: Transform { position: Pos2::new(12., -3.), scale: 2. },
+            current_layer: Some(1),
+            palettes: vec![Palette::new("Default", Vec::new()), Palette::new("Skin tones", swatches)],
+            current_palette: Some(1),
+            current_color: Some(1),
+            indexed_palette: Some(1),
+            current_stroke_width: 7.,
+            current_pencil: Pencil::Eraser
+        }
+    }
+
+    fn header(version: u32, width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = PROJECT_MAGIC.to_vec();
+        for value in [version, width, height] {
+            write_u32(&mut bytes, value).unwrap();
+        }
+        for value in [0., 0., 1., 5.] {
+            write_f32(&mut bytes, value).unwrap();
+        }
+        bytes.push(pencil_to_u8(Pencil::Brush));
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let document = document();
+        let mut bytes = Vec::new();
+        document.write_to(&mut bytes).unwrap();
+        let read = ProjectDocument::read_from(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(read.layer_size, document.layer_size);
+        assert_eq!(read.transform, document.transform);
+        assert_eq!(read.current_layer, Some(1));
+        assert_eq!(read.current_stroke_width, 7.);
+        assert_eq!(read.current_pencil, Pencil::Eraser);
+        assert_eq!(read.current_palette, Some(1));
+        assert_eq!(read.current_color, Some(1));
+        assert_eq!(read.indexed_palette, Some(1));
+        for (read, written) in read.palettes.iter().zip(document.palettes.iter()) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.colors.iter().map(|c| (c.color, c.name.clone())).collect::<Vec<_>>(), written.colors.iter().map(|c| (c.color, c.name.clone())).collect::<Vec<_>>());
+        }
+        assert_eq!(read.layers.len(), 2);
+        for (read, written) in read.layers.iter().zip(document.layers.iter()) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.is_visible, written.is_visible);
+            assert_eq!(read.blend_mode, written.blend_mode);
+            assert_eq!(read.opacity, written.opacity);
+            // Premultiplied pixels survive unchanged
+            assert_eq!(read.texture.image_data.to_color_image().pixels, written.texture.image_data.to_color_image().pixels);
+        }
+    }
+
+    #[test]
+    fn reads_version_one() {
+        let mut bytes = header(1, 4, 3);
+        write_u32(&mut bytes, 2).unwrap();
+        bytes.extend(Color32::RED.to_array());
+        bytes.extend(Color32::GREEN.to_array());
+        write_i32(&mut bytes, 1).unwrap();
+        write_u32(&mut bytes, 1).unwrap();
+        write_i32(&mut bytes, 0).unwrap();
+        write_bytes(&mut bytes, b"Layer 1").unwrap();
+        bytes.push(1);
+        let pixels = layer("", [4, 3], Color32::BLUE).texture.image_data.to_color_image();
+        write_bytes(&mut bytes, &encode_layer_png(&pixels).unwrap()).unwrap();
+
+        let read = ProjectDocument::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.palettes.len(), 1);
+        assert_eq!(read.palettes[0].name, DEFAULT_PALETTE_NAME);
+        assert_eq!(read.palettes[0].colors.iter().map(|c| c.color).collect::<Vec<_>>(), vec![Color32::RED, Color32::GREEN]);
+        assert_eq!(read.current_palette, Some(0));
+        assert_eq!(read.current_color, Some(1));
+        assert_eq!(read.indexed_palette, None);
+        assert_eq!(read.layers[0].name, "Layer 1");
+        assert_eq!(read.layers[0].blend_mode, BlendMode::Normal);
+        assert_eq!(read.layers[0].opacity, 1.);
+        assert_eq!(read.layers[0].texture.image_data.get(1), Color32::BLUE);
+    }
+
+    #[test]
+    fn reads_version_four_without_indexed_palette() {
+        let document = document();
+        // Version 5 added the indexed palette right before the layer count and current layer
+        let mut before_layers = Vec::new();
+        ProjectDocument { layers: Vec::new(), ..document.clone() }.write_to(&mut before_layers).unwrap();
+        let indexed_at = before_layers.len() - 12;
+        let mut bytes = Vec::new();
+        document.write_to(&mut bytes).unwrap();
+        bytes.drain(indexed_at..indexed_at + 4);
+        bytes[8..12].copy_from_slice(&4_u32.to_le_bytes());
+
+        let read = ProjectDocument::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.indexed_palette, None);
+        assert_eq!(read.palettes[1].name, "Skin tones");
+        assert_eq!(read.layers.len(), 2);
+    }
+
+    #[test]
+    fn rejects_newer_versions_and_oversized_canvases() {
+        let newer = header(PROJECT_VERSION + 1, 4, 3);
+        assert!(ProjectDocument::read_from(&mut newer.as_slice()).is_err());
+        let oversized = header(PROJECT_VERSION, MAX_CANVAS_SIZE as u32 + 1, 3);
+        let error = ProjectDocument::read_from(&mut oversized.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let empty = header(PROJECT_VERSION, 0, 3);
+        assert!(ProjectDocument::read_from(&mut empty.as_slice()).is_err());
+
+        // The view scale and stroke width follow the magic, version, canvas size and view position
+        let mut bytes = Vec::new();
+        document().write_to(&mut bytes).unwrap();
+        for offset in [28, 32] {
+            for value in [f32::NAN, f32::INFINITY, 0., -2.] {
+                let mut corrupt = bytes.clone();
+                corrupt[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                let error = ProjectDocument::read_from(&mut corrupt.as_slice()).err().unwrap();
+                assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{value} at {offset}");
+            }
+        }
+        assert!(ProjectDocument::read_from(&mut bytes.as_slice()).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt::Display;
+
+use egui::Color32;
+use image::DynamicImage;
+
+use crate::app::components::utils::tiled_image::TiledImage;
+
+// Mostly transparent pixels are background, not colors worth a swatch
+const MIN_ALPHA: u8 = 128;
+// Larger images are sampled evenly down to about this many pixels
+const MAX_SAMPLES: usize = 1 << 16;
+const KMEANS_ITERATIONS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum QuantizeMethod {
+    #[default]
+    MedianCut,
+    KMeans
+}
+
+impl QuantizeMethod {
+    pub const ALL: [QuantizeMethod; 2] = [QuantizeMethod::MedianCut, QuantizeMethod::KMeans];
+}
+
+impl Display for QuantizeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            QuantizeMethod::MedianCut => "Median cut",
+            QuantizeMethod::KMeans => "K-means"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct QuantizeSettings {
+    pub method: QuantizeMethod,
+    // How many colors to extract
+    pub colors: usize
+}
+
+impl Default for QuantizeSettings {
+    fn default() -> Self {
+        Self { method: QuantizeMethod::default(), colors: 8 }
+    }
+}
+
+/// Opaque RGB of the pixels of an imported image, for `dominant_colors`.
+pub fn image_samples(image: &DynamicImage) -> Vec<[u8; 3]> {
+    let rgba = image.to_rgba8();
+    let pixels = rgba.pixels().map(|pixel| pixel.0);
+    samples(pixels, rgba.width() as usize * rgba.height() as usize)
+}
+
+/// Opaque RGB of the pixels of a layer or composite, for `dominant_colors`.
+pub fn tiled_image_samples(image: &TiledImage) -> Vec<[u8; 3]> {
+    let [width, height] = image.size;
+    let pixels = image.allocated_tiles().flat_map(|(_, _, tile)| tile.iter().map(|pixel| pixel.to_srgba_unmultiplied()));
+    samples(pixels, width * height)
+}
+
+fn samples(pixels: impl Iterator<Item = [u8; 4]>, len: usize) -> Vec<[u8; 3]> {
+    pixels.step_by(len.div_ceil(MAX_SAMPLES).max(1))
+        .filter(|[_, _, _, a]| *a >= MIN_ALPHA)
+        .map(|[r, g, b, _]| [r, g, b])
+        .collect()
+}
+
+/// Up to `settings.colors` colors that best represent `samples`, the most common first.
+pub fn dominant_colors(samples: &[[u8; 3]], settings: &QuantizeSettings) -> Vec<Color32> {
+    if samples.is_empty() || settings.colors == 0 {
+        return Vec::new();
+    }
+    let mut clusters = median_cut(samples, settings.colors);
+    if settings.method == QuantizeMethod::KMeans {
+        // Median cut makes a good deterministic start, which k-means then refines
+        clusters = kmeans(samples, clusters.into_iter().map(|(center, _)| center).collect());
+    }
+    clusters.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let mut colors: Vec<Color32> = Vec::new();
+    for (center, _) in clusters {
+        let [r, g, b] = center.map(|channel| channel.round().clamp(0., 255.) as u8);
+        let color = Color32::from_rgb(r, g, b);
+        if !colors.contains(&color) {
+            colors.push(color);
+        }
+    }
+    colors
+}
+
+fn mean(samples: &[[u8; 3]]) -> [f32; 3] {
+    let mut sum = [0_u64; 3];
+    for sample in samples {
+        for (total, channel) in sum.iter_mut().zip(sample) {
+            *total += *channel as u64;
+        }
+    }
+    sum.map(|total| total as f32 / samples.len().max(1) as f32)
+}
+
+/// Channel with the widest spread in `samples` and that spread.
+fn widest_channel(samples: &[[u8; 3]]) -> (usize, u8) {
+    (0..3).map(|channel| {
+        let (min, max) = samples.iter().fold((u8::MAX, u8::MIN), |(min, max), sample| (min.min(sample[channel]), max.max(sample[channel])));
+        (channel, max.saturating_sub(min))
+    }).max_by_key(|(_, range)| *range).unwrap_or((0, 0))
+}
+
+/// Splits the box with the widest channel at its median until there are `count` boxes. Returns the
+/// mean color and pixel count of each box.
+fn median_cut(samples: &[[u8; 3]], count: usize) -> Vec<([f32; 3], usize)> {
+    let mut boxes: Vec<Vec<[u8; 3]>> = vec![samples.to_vec()];
+    while boxes.len() < count {
+        let Some((index, channel)) = boxes.iter().enumerate()
+            .filter(|(_, samples)| samples.len() > 1)
+            .map(|(index, samples)| (index, widest_channel(samples)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel)) else {
+            // Every box holds a single color
+            break;
+        };
+        let mut split = boxes.swap_remove(index);
+        split.sort_unstable_by_key(|sample| sample[channel]);
+        // Cut at the value change nearest the median so one color never ends up in two boxes
+        let median = split.len() / 2;
+        let is_boundary = |i: usize| split[i - 1][channel] != split[i][channel];
+        let below = (1..=median).rev().find(|i| is_boundary(*i));
+        let above = (median.max(1)..split.len()).find(|i| is_boundary(*i));
+        let cut = match (below, above) {
+            (Some(below), Some(above)) => if median - below <= above - median { below } else { above },
+            (below, above) => below.or(above).unwrap_or(median)
+        };
+        let upper = split.split_off(cut);
+        boxes.push(split);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|samples| (mean(samples), samples.len())).collect()
+}
+
+fn distance(sample: &[u8; 3], center: &[f32; 3]) -> f32 {
+    sample.iter().zip(center).map(|(channel, center)| (*channel as f32 - center).powi(2)).sum()
+}
+
+/// Lloyd's algorithm from `centers`. Returns every center with the number of samples nearest to it.
+fn kmeans(samples: &[[u8; 3]], mut centers: Vec<[f32; 3]>) -> Vec<([f32; 3], usize)> {
+    let mut assignments = vec![0_usize; samples.len()];
+    for iteration in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(assignments.iter_mut()) {
+            let nearest = centers.iter().enumerate()
+                .min_by(|(_, a), (_, b)| distance(sample, a).total_cmp(&distance(sample, b)))
+                .map(|(index, _)| index)
+                .unwrap_or(0);
+            changed |= nearest != *assignment;
+            *assignment = nearest;
+        }
+        if iteration > 0 && !changed {
+            break;
+        }
+        let mut sums = vec![([0_u64; 3], 0_usize); centers.len()];
+        for (sample, assignment) in samples.iter().zip(assignments.iter()) {
+            let (sum, count) = &mut sums[*assignment];
+            for (total, channel) in sum.iter_mut().zip(sample) {
+                *total += *channel as u64;
+            }
+            *count += 1;
+        }
+        // A center nothing is nearest to stays put
+        for (center, (sum, count)) in centers.iter_mut().zip(sums.iter()) {
+            if *count > 0 {
+                *center = sum.map(|total| total as f32 / *count as f32);
+            }
+        }
+    }
+    let mut counts = vec![0_usize; centers.len()];
+    for assignment in assignments {
+        counts[assignment] += 1;
+    }
+    centers.into_iter().zip(counts).filter(|(_, count)| *count > 0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(method: QuantizeMethod, colors: usize) -> QuantizeSettings {
+        QuantizeSettings { method, colors }
+    }
+
+    // 60 red, 30 blue and 10 green pixels, each with a little noise
+    fn three_color_samples() -> Vec<[u8; 3]> {
+        let mut samples = Vec::new();
+        for i in 0..60 { samples.push([250 + (i % 3) as u8, 0, 0]); }
+        for i in 0..30 { samples.push([0, 0, 250 + (i % 3) as u8]); }
+        for i in 0..10 { samples.push([0, 250 + (i % 3) as u8, 0]); }
+        samples
+    }
+
+    #[test]
+    fn finds_the_dominant_colors_most_common_first() {
+        for method in QuantizeMethod::ALL {
+            let colors = dominant_colors(&three_color_samples(), &settings(method, 3));
+            assert_eq!(colors.len(), 3, "{method}");
+            assert!(colors[0].r() >= 250 && colors[0].g() == 0 && colors[0].b() == 0, "{method}: {:?}", colors);
+            assert!(colors[1].b() >= 250 && colors[1].r() == 0, "{method}: {:?}", colors);
+            assert!(colors[2].g() >= 250 && colors[2].r() == 0, "{method}: {:?}", colors);
+        }
+    }
+
+    #[test]
+    fn never_returns_more_colors_than_the_image_has() {
+        let samples = vec![[10, 20, 30]; 50].into_iter().chain(vec![[200, 100, 0]; 5]).collect::<Vec<_>>();
+        for method in QuantizeMethod::ALL {
+            assert_eq!(dominant_colors(&samples, &settings(method, 8)), vec![Color32::from_rgb(10, 20, 30), Color32::from_rgb(200, 100, 0)], "{method}");
+        }
+    }
+
+    #[test]
+    fn empty_input_gives_no_colors() {
+        assert!(dominant_colors(&[], &QuantizeSettings::default()).is_empty());
+        assert!(dominant_colors(&three_color_samples(), &settings(QuantizeMethod::MedianCut, 0)).is_empty());
+    }
+
+    #[test]
+    fn median_cut_keeps_each_color_in_one_box() {
+        let samples = [[0, 0, 0], [0, 0, 0], [0, 0, 0], [100, 0, 0], [200, 0, 0]];
+        let boxes = median_cut(&samples, 2);
+        assert_eq!(boxes.iter().map(|(_, count)| *count).sum::<usize>(), samples.len());
+        assert!(boxes.contains(&([0., 0., 0.], 3)), "{:?}", boxes);
+    }
+
+    #[test]
+    fn kmeans_moves_centers_to_their_clusters() {
+        let samples = [[0, 0, 0], [10, 10, 10], [240, 240, 240], [250, 250, 250]];
+        let clusters = kmeans(&samples, vec![[100., 100., 100.], [150., 150., 150.]]);
+        assert_eq!(clusters, vec![([5., 5., 5.], 2), ([245., 245., 245.], 2)]);
+    }
+
+    #[test]
+    fn samples_skip_transparent_pixels() {
+        let pixels = [[1, 2, 3, 255], [4, 5, 6, 0], [7, 8, 9, 128]].into_iter();
+        assert_eq!(samples(pixels, 3), vec![[1, 2, 3], [7, 8, 9]]);
+    }
+}
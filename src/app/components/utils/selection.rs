@@ -0,0 +1,324 @@
+use std::fmt::Display;
+use std::sync::Arc;
+
+use egui::Pos2;
+
+use crate::app::components::utils::export_image::ExportRegion;
+use crate::app::components::utils::flood_fill::FillSettings;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SelectionMode {
+    #[default]
+    Replace,
+    Add,
+    Subtract,
+    Intersect
+}
+
+impl SelectionMode {
+    pub const ALL: [SelectionMode; 4] = [
+        SelectionMode::Replace,
+        SelectionMode::Add,
+        SelectionMode::Subtract,
+        SelectionMode::Intersect
+    ];
+
+    /// Shift adds, Alt subtracts and both intersect, whatever mode is picked in the tool bar.
+    pub fn from_modifiers(modifiers: egui::Modifiers, fallback: SelectionMode) -> SelectionMode {
+        match (modifiers.shift, modifiers.alt) {
+            (true, true) => SelectionMode::Intersect,
+            (true, false) => SelectionMode::Add,
+            (false, true) => SelectionMode::Subtract,
+            (false, false) => fallback
+        }
+    }
+}
+
+impl Display for SelectionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct SelectionSettings {
+    pub mode: SelectionMode,
+    // Edge softening in pixels applied to every new selection
+    pub feather: usize,
+    // Tolerance, contiguity and sampling of the magic wand and color range
+    pub wand: FillSettings
+}
+
+/// Pixels edits are allowed to touch, 0-255 per pixel in `y * width + x` order. Without a mask
+/// nothing is selected and the whole document can be edited.
+#[derive(Clone, PartialEq, Default)]
+pub struct Selection {
+    size: [usize; 2],
+    mask: Option<Arc<Vec<u8>>>,
+    // Border between selected and unselected pixels in layer coordinates, kept for the marching ants
+    outline: Vec<[Pos2; 2]>
+}
+
+impl Selection {
+    pub fn new(size: [usize; 2]) -> Self {
+        Self {
+            size,
+            mask: None,
+            outline: Vec::new()
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mask.is_some()
+    }
+
+    /// How much an edit may change the pixel at `idx`, from 0.0 to 1.0.
+    pub fn coverage(&self, idx: usize) -> f32 {
+        match &self.mask {
+            Some(mask) => mask[idx] as f32 / 255.,
+            None => 1.
+        }
+    }
+
+    pub fn mask(&self) -> Option<&[u8]> {
+        self.mask.as_deref().map(Vec::as_slice)
+    }
+
+    pub fn outline(&self) -> &[[Pos2; 2]] {
+        &self.outline
+    }
+
+    /// Merges a shape's coverage (as from `shape_coverage`) into the selection.
+    pub fn combine(&mut self, shape: Vec<u8>, mode: SelectionMode) {
+        let mask = match (&self.mask, mode) {
+            (None, SelectionMode::Subtract) => return,
+            (None, _) | (_, SelectionMode::Replace) => shape,
+            (Some(mask), SelectionMode::Add) => mask.iter().zip(shape).map(|(a, b)| (*a).max(b)).collect(),
+            (Some(mask), SelectionMode::Subtract) => mask.iter().zip(shape).map(|(a, b)| ((*a as u32 * (255 - b as u32)) / 255) as u8).collect(),
+            (Some(mask), SelectionMode::Intersect) => mask.iter().zip(shape).map(|(a, b)| (*a).min(b)).collect()
+        };
+        self.set_mask(mask);
+    }
+
+    pub fn select_all(&mut self) {
+        self.set_mask(vec![255; self.size[0] * self.size[1]]);
+    }
+
+    pub fn deselect(&mut self) {
+        self.mask = None;
+        self.outline.clear();
+    }
+
+    pub fn invert(&mut self) {
+        if let Some(mask) = &self.mask {
+            let inverted = mask.iter().map(|amount| 255 - amount).collect();
+            self.set_mask(inverted);
+        }
+    }
+
+    /// Smallest rect holding every selected pixel; `None` without a selection or when it is empty.
+    pub fn bounds(&self) -> Option<ExportRegion> {
+        let mask = self.mask.as_ref()?;
+        let width = self.size[0];
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for (idx, _) in mask.iter().enumerate().filter(|(_, amount)| **amount > 0) {
+            let (x, y) = (idx % width, idx / width);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        (min_x <= max_x).then_some(ExportRegion { x: min_x, y: min_y, width: max_x - min_x + 1, height: max_y - min_y + 1 })
+    }
+
+    fn set_mask(&mut self, mask: Vec<u8>) {
+        // An empty selection is no selection, so edits don't silently do nothing
+        if mask.iter().all(|amount| *amount == 0) {
+            self.deselect();
+            return;
+        }
+        self.outline = outline(&mask, self.size);
+        self.mask = Some(Arc::new(mask));
+    }
+}
+
+/// Pixel edges between selected (at least half covered) and unselected pixels, joined into runs.
+fn outline(mask: &[u8], size: [usize; 2]) -> Vec<[Pos2; 2]> {
+    let [width, height] = size;
+    let is_selected = |x: usize, y: usize| x < width && y < height && mask[y * width + x] >= 128;
+    let mut segments = Vec::new();
+    for y in 0..=height {
+        let mut run_start = None;
+        for x in 0..=width {
+            let is_edge = x < width && is_selected(x, y) != (y > 0 && is_selected(x, y - 1));
+            match (is_edge, run_start) {
+                (true, None) => run_start = Some(x),
+                (false, Some(start)) => {
+                    segments.push([Pos2::new(start as f32, y as f32), Pos2::new(x as f32, y as f32)]);
+                    run_start = None;
+                },
+                _ => {}
+            }
+        }
+    }
+    for x in 0..=width {
+        let mut run_start = None;
+        for y in 0..=height {
+            let is_edge = y < height && is_selected(x, y) != (x > 0 && is_selected(x - 1, y));
+            match (is_edge, run_start) {
+                (true, None) => run_start = Some(y),
+                (false, Some(start)) => {
+                    segments.push([Pos2::new(x as f32, start as f32), Pos2::new(x as f32, y as f32)]);
+                    run_start = None;
+                },
+                _ => {}
+            }
+        }
+    }
+    segments
+}
+
+/// Softens the edge of a selection mask by `radius` pixels.
+pub fn feather(mask: Vec<u8>, size: [usize; 2], radius: usize) -> Vec<u8> {
+    if radius == 0 {
+        return mask;
+    }
+    // Two box blurs come close enough to a gaussian for a selection edge
+    let half = radius.div_ceil(2);
+    let blurred = box_blur(&mask, size, half);
+    box_blur(&blurred, size, half)
+}
+
+fn box_blur(mask: &[u8], size: [usize; 2], radius: usize) -> Vec<u8> {
+    let [width, height] = size;
+    let mut line = Vec::with_capacity(width.max(height));
+    let mut horizontal = vec![0_u8; mask.len()];
+    for y in 0..height {
+        blur_line((0..width).map(|x| mask[y * width + x]), radius, &mut line);
+        horizontal[y * width..(y + 1) * width].copy_from_slice(&line);
+    }
+    let mut blurred = vec![0_u8; mask.len()];
+    for x in 0..width {
+        blur_line((0..height).map(|y| horizontal[y * width + x]), radius, &mut line);
+        for (y, value) in line.iter().enumerate() {
+            blurred[y * width + x] = *value;
+        }
+    }
+    blurred
+}
+
+/// Averages every value with its neighbours up to `radius` away into `blurred`.
+fn blur_line(values: impl Iterator<Item = u8>, radius: usize, blurred: &mut Vec<u8>) {
+    let mut sums = vec![0_u32];
+    for value in values {
+        sums.push(sums[sums.len() - 1] + value as u32);
+    }
+    let len = sums.len() - 1;
+    blurred.clear();
+    blurred.extend((0..len).map(|i| {
+        let (start, end) = (i.saturating_sub(radius), (i + radius + 1).min(len));
+        ((sums[end] - sums[start]) / (end - start) as u32) as u8
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::Color32;
+    use crate::app::components::utils::brush::{Brush, Dab, StrokeCoverage};
+    use crate::app::components::utils::draw_tool::Pencil;
+    use crate::app::components::utils::layer::LayerTexture;
+
+    const SIZE: [usize; 2] = [10, 10];
+
+    fn rect(x: std::ops::Range<usize>, y: std::ops::Range<usize>) -> Vec<u8> {
+        (0..SIZE[0] * SIZE[1]).map(|idx| if x.contains(&(idx % SIZE[0])) && y.contains(&(idx / SIZE[0])) { 255 } else { 0 }).collect()
+    }
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> Option<ExportRegion> {
+        Some(ExportRegion { x, y, width, height })
+    }
+
+    fn combined(mode: SelectionMode) -> Selection {
+        let mut selection = Selection::new(SIZE);
+        selection.combine(rect(1..5, 1..5), SelectionMode::Replace);
+        selection.combine(rect(3..8, 3..6), mode);
+        selection
+    }
+
+    #[test]
+    fn modes_combine_overlapping_rects() {
+        assert_eq!(combined(SelectionMode::Replace).mask(), Some(rect(3..8, 3..6).as_slice()));
+        assert_eq!(combined(SelectionMode::Replace).bounds(), region(3, 3, 5, 3));
+
+        let added = combined(SelectionMode::Add);
+        assert_eq!(added.bounds(), region(1, 1, 7, 5));
+        assert_eq!(added.mask().unwrap().iter().filter(|amount| **amount == 255).count(), 16 + 15 - 4);
+
+        let subtracted = combined(SelectionMode::Subtract);
+        assert_eq!(subtracted.bounds(), region(1, 1, 4, 4));
+        assert_eq!(subtracted.coverage(3 * 10 + 3), 0.);
+        assert_eq!(subtracted.coverage(10 + 1), 1.);
+
+        assert_eq!(combined(SelectionMode::Intersect).mask(), Some(rect(3..5, 3..5).as_slice()));
+
+        // Subtracting from nothing still selects nothing
+        let mut selection = Selection::new(SIZE);
+        selection.combine(rect(1..5, 1..5), SelectionMode::Subtract);
+        assert!(!selection.is_active());
+    }
+
+    #[test]
+    fn invert_then_bounds() {
+        let mut selection = Selection::new(SIZE);
+        selection.combine(rect(0..10, 0..4), SelectionMode::Replace);
+        selection.invert();
+        assert_eq!(selection.bounds(), region(0, 4, 10, 6));
+        // Inverting everything leaves nothing, which is no selection at all
+        selection.select_all();
+        selection.invert();
+        assert!(!selection.is_active());
+        assert_eq!(selection.bounds(), None);
+    }
+
+    #[test]
+    fn feather_zero_keeps_the_mask() {
+        let mask = rect(2..7, 3..8);
+        assert_eq!(feather(mask.clone(), SIZE, 0), mask);
+    }
+
+    #[test]
+    fn feather_softens_the_edge() {
+        let feathered = feather(rect(3..9, 3..9), SIZE, 2);
+        let at = |x: usize, y: usize| feathered[y * SIZE[0] + x];
+        assert_eq!(at(6, 6), 255);
+        assert!(at(3, 6) > 0 && at(3, 6) < 255, "{}", at(3, 6));
+        assert!(at(2, 6) > 0 && at(2, 6) < at(3, 6), "{}", at(2, 6));
+        assert_eq!(at(0, 0), 0);
+    }
+
+    #[test]
+    fn painting_stays_inside_the_selection() {
+        let mut selection = Selection::new(SIZE);
+        selection.combine(rect(0..5, 0..10), SelectionMode::Replace);
+        let brush = Brush { size: 8., hardness: 1., flow: 1., opacity: 1., ..Default::default() };
+        for tool in [Pencil::Brush, Pencil::Pen, Pencil::Eraser] {
+            let mut texture = LayerTexture::new(SIZE[0], SIZE[1]);
+            if tool == Pencil::Eraser {
+                for idx in 0..SIZE[0] * SIZE[1] {
+                    texture.image_data.set(idx, Color32::BLUE);
+                }
+            }
+            let dab = Dab { pos: Pos2::new(5., 5.), size: 8., angle: 0. };
+            let changes = texture.paint_at(dab, tool, &brush, Color32::RED, &mut StrokeCoverage::default(), &selection);
+            assert!(!changes.is_empty(), "{tool:?}");
+            assert!(changes.iter().all(|(idx, _)| idx % SIZE[0] < 5), "{tool:?}");
+            let untouched = if tool == Pencil::Eraser { Color32::BLUE } else { Color32::TRANSPARENT };
+            for y in 0..SIZE[1] {
+                for x in 5..SIZE[0] {
+                    assert_eq!(texture.image_data.get(y * SIZE[0] + x), untouched, "{tool:?} at {x}, {y}");
+                }
+            }
+        }
+    }
+}
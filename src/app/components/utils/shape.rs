@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::f32::consts::{PI, TAU};
+
+use egui::{Pos2, Vec2};
+
+use crate::app::components::utils::draw_tool::Pencil;
+
+const ANGLE_STEP: f32 = PI / 12.;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ShapeSettings {
+    // Lines are always stroked, whatever this says
+    pub filled: bool,
+    pub width: f32,
+    pub anti_alias: bool
+}
+
+impl Default for ShapeSettings {
+    fn default() -> Self {
+        Self {
+            filled: false,
+            width: 4.,
+            anti_alias: true
+        }
+    }
+}
+
+/// Moves `end` so the segment from `start` points at a multiple of 15°.
+pub fn snap_angle(start: Pos2, end: Pos2) -> Pos2 {
+    let delta = end - start;
+    let angle = (delta.y.atan2(delta.x) / ANGLE_STEP).round() * ANGLE_STEP;
+    start + Vec2::angled(angle) * delta.length()
+}
+
+/// Moves `end` so the box it spans with `start` is a square.
+pub fn snap_square(start: Pos2, end: Pos2) -> Pos2 {
+    let delta = end - start;
+    let side = delta.x.abs().max(delta.y.abs());
+    start + Vec2::new(side.copysign(delta.x), side.copysign(delta.y))
+}
+
+/// Where the pointer at `end` puts the next point of a shape, Shift-constrained when `constrain` is set.
+pub fn constrain_end(pencil: Pencil, anchors: &[Pos2], end: Pos2, constrain: bool) -> Pos2 {
+    match anchors.last() {
+        Some(last) if constrain => match pencil {
+            Pencil::Rect | Pencil::Ellipse => snap_square(anchors[0], end),
+            _ => snap_angle(*last, end)
+        },
+        _ => end
+    }
+}
+
+/// Outline of a shape started at `anchors` and ending at `end`, as a path in layer coordinates and
+/// whether that path is closed.
+pub fn shape_path(pencil: Pencil, anchors: &[Pos2], end: Pos2) -> (Vec<Pos2>, bool) {
+    let Some(start) = anchors.first().copied() else {
+        return (Vec::new(), false);
+    };
+    match pencil {
+        Pencil::Rect => (vec![start, Pos2::new(end.x, start.y), end, Pos2::new(start.x, end.y)], true),
+        Pencil::Ellipse => {
+            let center = start.lerp(end, 0.5);
+            let radius = (end - start).abs() / 2.;
+            // Enough segments that no edge is noticeably straight
+            let segments = ((radius.x + radius.y) * PI / 4.).clamp(16., 512.) as usize;
+            let points = (0..segments)
+                .map(|segment| {
+                    let angle = segment as f32 / segments as f32 * TAU;
+                    center + Vec2::new(angle.cos() * radius.x, angle.sin() * radius.y)
+                })
+                .collect();
+            (points, true)
+        },
+        Pencil::Polygon => (anchors.iter().copied().chain(std::iter::once(end)).collect(), true),
+        _ => (vec![start, end], false)
+    }
+}
+
+fn edges(path: &[Pos2], closed: bool) -> impl Iterator<Item = (Pos2, Pos2)> + '_ {
+    let closing = if closed && path.len() > 2 { path.last().copied().zip(path.first().copied()) } else { None };
+    path.windows(2).map(|pair| (pair[0], pair[1])).chain(closing)
+}
+
+fn segment_distance(point: Pos2, a: Pos2, b: Pos2) -> f32 {
+    let ab = b - a;
+    let t = if ab.length_sq() > 0. { ((point - a).dot(ab) / ab.length_sq()).clamp(0., 1.) } else { 0. };
+    point.distance(a + ab * t)
+}
+
+/// Distance from every pixel center within `reach` of the path to its nearest edge.
+fn near_edges(path: &[Pos2], closed: bool, reach: f32, size: [usize; 2]) -> HashMap<usize, f32> {
+    let [width, height] = size;
+    let mut distances: HashMap<usize, f32> = HashMap::new();
+    for (a, b) in edges(path, closed) {
+        let min_y = (a.y.min(b.y) - reach).floor().max(0.) as usize;
+        let max_y = ((a.y.max(b.y) + reach).ceil().max(0.) as usize).min(height);
+        for y in min_y..max_y {
+            let center_y = y as f32 + 0.5;
+            // Only the part of the edge inside this row's band can be within reach
+            let (low, high) = if (b.y - a.y).abs() < f32::EPSILON {
+                (a.x.min(b.x), a.x.max(b.x))
+            } else {
+                let x_at = |edge_y: f32| a.x + (b.x - a.x) * ((edge_y - a.y) / (b.y - a.y)).clamp(0., 1.);
+                let (x0, x1) = (x_at(center_y - reach), x_at(center_y + reach));
+                (x0.min(x1), x0.max(x1))
+            };
+            let min_x = (low - reach).floor().max(0.) as usize;
+            let max_x = ((high + reach).ceil().max(0.) as usize).min(width);
+            for x in min_x..max_x {
+                let distance = segment_distance(Pos2::new(x as f32 + 0.5, center_y), a, b);
+                if distance <= reach {
+                    let nearest = distances.entry(y * width + x).or_insert(distance);
+                    *nearest = nearest.min(distance);
+                }
+            }
+        }
+    }
+    distances
+}
+
+/// Even-odd test of a pixel center against a closed path.
+fn is_inside(path: &[Pos2], point: Pos2) -> bool {
+    edges(path, true)
+        .filter(|(a, b)| (a.y <= point.y) != (b.y <= point.y) && point.x < a.x + (point.y - a.y) * (b.x - a.x) / (b.y - a.y))
+        .count() % 2 == 1
+}
+
+/// How much of each pixel of a canvas of `size` the shape covers, 0-255 in `y * width + x` order.
+pub fn shape_coverage(path: &[Pos2], closed: bool, settings: &ShapeSettings, size: [usize; 2]) -> Vec<u8> {
+    let [width, height] = size;
+    let mut coverage = vec![0_u8; width * height];
+    let mut cover = |idx: usize, amount: f32| {
+        let amount = if settings.anti_alias { amount.clamp(0., 1.) } else if amount >= 0.5 { 1. } else { 0. };
+        coverage[idx] = coverage[idx].max((amount * 255.).round() as u8);
+    };
+
+    if settings.filled && closed && path.len() > 2 {
+        let edge_distances = near_edges(path, true, 1., size);
+        let min_y = path.iter().map(|p| p.y).fold(f32::INFINITY, f32::min).floor().max(0.) as usize;
+        let max_y = (path.iter().map(|p| p.y).fold(f32::NEG_INFINITY, f32::max).ceil().max(0.) as usize).min(height);
+        for y in min_y..max_y {
+            let center_y = y as f32 + 0.5;
+            let mut crossings = edges(path, true)
+                .filter(|(a, b)| (a.y <= center_y) != (b.y <= center_y))
+                .map(|(a, b)| a.x + (center_y - a.y) * (b.x - a.x) / (b.y - a.y))
+                .collect::<Vec<f32>>();
+            crossings.sort_by(f32::total_cmp);
+            for span in crossings.chunks_exact(2) {
+                let start = ((span[0] - 0.5).ceil().max(0.) as usize).min(width);
+                let end = ((span[1] - 0.5).ceil().max(0.) as usize).min(width);
+                for x in start..end {
+                    let idx = y * width + x;
+                    cover(idx, edge_distances.get(&idx).map(|distance| 0.5 + distance).unwrap_or(1.));
+                }
+            }
+        }
+        // Pixels just outside the edge get the part of them the shape overlaps
+        for (idx, distance) in edge_distances {
+            let center = Pos2::new((idx % width) as f32 + 0.5, (idx / width) as f32 + 0.5);
+            if !is_inside(path, center) {
+                cover(idx, 0.5 - distance);
+            }
+        }
+    } else {
+        let half_width = settings.width.max(1.) / 2.;
+        for (idx, distance) in near_edges(path, closed, half_width + 1., size) {
+            cover(idx, half_width - distance + 0.5);
+        }
+    }
+    coverage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: [usize; 2] = [16, 16];
+
+    fn close(a: Pos2, b: Pos2) -> bool {
+        a.distance(b) < 1e-3
+    }
+
+    fn rect_coverage(start: Pos2, end: Pos2, filled: bool, anti_alias: bool) -> Vec<u8> {
+        let (path, closed) = shape_path(Pencil::Rect, &[start], end);
+        shape_coverage(&path, closed, &ShapeSettings { filled, width: 2., anti_alias }, SIZE)
+    }
+
+    #[test]
+    fn snap_angle_uses_15_degree_steps() {
+        let start = Pos2::new(10., 10.);
+        assert!(close(snap_angle(start, Pos2::new(20., 11.)), Pos2::new(20.05, 10.)));
+        let snapped = snap_angle(start, Pos2::new(18., 14.));
+        let angle = (snapped - start).angle().to_degrees();
+        assert!((angle - 30.).abs() < 1e-3, "{angle}");
+        assert!(((snapped - start).length() - Vec2::new(8., 4.).length()).abs() < 1e-3);
+        assert!(close(snap_angle(start, Pos2::new(6., 5.9)), start + Vec2::angled(-135_f32.to_radians()) * Vec2::new(4., 4.1).length()));
+    }
+
+    #[test]
+    fn snap_square_in_every_quadrant() {
+        let start = Pos2::new(10., 10.);
+        for (end, expected) in [
+            (Pos2::new(14., 12.), Pos2::new(14., 14.)),
+            (Pos2::new(7., 15.), Pos2::new(5., 15.)),
+            (Pos2::new(4., 8.), Pos2::new(4., 4.)),
+            (Pos2::new(12., 3.), Pos2::new(17., 3.))
+        ] {
+            let snapped = snap_square(start, end);
+            assert_eq!(snapped, expected);
+            assert_eq!((snapped.x - start.x).abs(), (snapped.y - start.y).abs());
+        }
+    }
+
+    #[test]
+    fn filled_rects_cover_their_inside_and_stroked_ones_only_the_edge() {
+        let (start, end) = (Pos2::new(2., 2.), Pos2::new(12., 12.));
+        let filled = rect_coverage(start, end, true, true);
+        let stroked = rect_coverage(start, end, false, true);
+        let inside = 7 * SIZE[0] + 7;
+        let on_edge = 7 * SIZE[0] + 2;
+        assert_eq!(filled[inside], 255);
+        assert_eq!(stroked[inside], 0);
+        assert_eq!(filled[on_edge], 255);
+        assert!(stroked[on_edge] > 0);
+        assert_eq!(filled[0], 0);
+        assert_eq!(stroked[0], 0);
+    }
+
+    #[test]
+    fn only_anti_aliased_edges_are_fractional() {
+        let (start, end) = (Pos2::new(2.25, 2.25), Pos2::new(11.75, 11.75));
+        for filled in [true, false] {
+            let smooth = rect_coverage(start, end, filled, true);
+            let hard = rect_coverage(start, end, filled, false);
+            assert!(smooth.iter().any(|amount| *amount > 0 && *amount < 255), "filled: {filled}");
+            assert!(hard.iter().all(|amount| *amount == 0 || *amount == 255), "filled: {filled}");
+            assert!(hard.iter().any(|amount| *amount == 255), "filled: {filled}");
+        }
+    }
+}
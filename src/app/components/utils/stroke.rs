@@ -0,0 +1,141 @@
+use egui::Pos2;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct StrokeSettings {
+    pub smoothing: bool
+}
+
+fn catmull_rom(p0: Pos2, p1: Pos2, p2: Pos2, p3: Pos2, t: f32) -> Pos2 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let point = p1.to_vec2() * 2.
+        + (p2 - p0) * t
+        + (p0.to_vec2() * 2. - p1.to_vec2() * 5. + p2.to_vec2() * 4. - p3.to_vec2()) * t2
+        + (p1.to_vec2() * 3. - p0.to_vec2() - p2.to_vec2() * 3. + p3.to_vec2()) * t3;
+    (point * 0.5).to_pos2()
+}
+
+/// Walks `polyline` and drops a dab every `spacing` pixels.
+/// `carry` is the distance travelled since the last dab and is updated for the next call.
+fn place_dabs(polyline: &[Pos2], spacing: f32, carry: &mut f32) -> Vec<Pos2> {
+    let mut dabs: Vec<Pos2> = Vec::new();
+    for segment in polyline.windows(2) {
+        let (start, end) = (segment[0], segment[1]);
+        let len = start.distance(end);
+        if len <= f32::EPSILON {
+            continue;
+        }
+        let direction = (end - start) / len;
+        let mut next = spacing - *carry;
+        while next <= len {
+            dabs.push(start + direction * next);
+            next += spacing;
+        }
+        *carry = len - (next - spacing);
+    }
+    dabs
+}
+
+fn smoothed_segment(p0: Pos2, p1: Pos2, p2: Pos2, p3: Pos2) -> Vec<Pos2> {
+    // Roughly one sample every two pixels of chord length is enough for dab placement
+    let steps = ((p1.distance(p2) / 2.).ceil() as usize).max(1);
+    (0..=steps).map(|step| catmull_rom(p0, p1, p2, p3, step as f32 / steps as f32)).collect()
+}
+
+/// Dabs for the newest sample in `poses`, which already holds every sample of the stroke.
+/// With smoothing the curve lags one sample behind, since each Catmull-Rom segment needs the next point.
+pub fn next_dabs(poses: &[Pos2], settings: &StrokeSettings, spacing: f32, carry: &mut f32) -> Vec<Pos2> {
+    let n = poses.len();
+    match n {
+        0 => Vec::new(),
+        1 => {
+            *carry = 0.;
+            vec![poses[0]]
+        },
+        _ if !settings.smoothing => place_dabs(&poses[n - 2..], spacing, carry),
+        2 => Vec::new(),
+        _ => {
+            let p0 = if n >= 4 { poses[n - 4] } else { poses[n - 3] };
+            place_dabs(&smoothed_segment(p0, poses[n - 3], poses[n - 2], poses[n - 1]), spacing, carry)
+        }
+    }
+}
+
+/// Dabs for the last segment of a smoothed stroke once the pointer is released.
+pub fn final_dabs(poses: &[Pos2], settings: &StrokeSettings, spacing: f32, carry: &mut f32) -> Vec<Pos2> {
+    let n = poses.len();
+    if !settings.smoothing || n < 2 {
+        return Vec::new();
+    }
+    let p0 = if n >= 3 { poses[n - 3] } else { poses[n - 2] };
+    place_dabs(&smoothed_segment(p0, poses[n - 2], poses[n - 1], poses[n - 1]), spacing, carry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_spaced(dabs: &[Pos2], spacing: f32) {
+        for pair in dabs.windows(2) {
+            assert!((pair[0].distance(pair[1]) - spacing).abs() < 1e-3, "{:?}", dabs);
+        }
+    }
+
+    /// Feeds `poses` one sample at a time, as the canvas does while the pointer moves, then releases.
+    fn stroke(poses: &[Pos2], settings: &StrokeSettings, spacing: f32) -> Vec<Pos2> {
+        let mut carry = 0.;
+        let mut dabs = Vec::new();
+        for n in 1..=poses.len() {
+            dabs.extend(next_dabs(&poses[..n], settings, spacing, &mut carry));
+        }
+        dabs.extend(final_dabs(poses, settings, spacing, &mut carry));
+        dabs
+    }
+
+    #[test]
+    fn straight_lines_get_evenly_spaced_dabs() {
+        let mut carry = 0.;
+        let dabs = place_dabs(&[Pos2::new(0., 0.), Pos2::new(10., 0.)], 2.5, &mut carry);
+        assert_eq!(dabs, vec![Pos2::new(2.5, 0.), Pos2::new(5., 0.), Pos2::new(7.5, 0.), Pos2::new(10., 0.)]);
+        assert_eq!(carry, 0.);
+    }
+
+    #[test]
+    fn carry_keeps_spacing_across_segment_joins() {
+        let mut carry = 0.;
+        let first = place_dabs(&[Pos2::new(0., 0.), Pos2::new(7., 0.)], 3., &mut carry);
+        assert_eq!(first, vec![Pos2::new(3., 0.), Pos2::new(6., 0.)]);
+        assert!((carry - 1.).abs() < 1e-5);
+        let second = place_dabs(&[Pos2::new(7., 0.), Pos2::new(7., 8.)], 3., &mut carry);
+        assert_eq!(second, vec![Pos2::new(7., 2.), Pos2::new(7., 5.), Pos2::new(7., 8.)]);
+
+        // A fast stroke sampled far apart is as dense as a slow one sampled close together
+        let settings = StrokeSettings::default();
+        let fast = stroke(&[Pos2::new(0., 0.), Pos2::new(50., 0.), Pos2::new(100., 0.)], &settings, 4.);
+        let slow = stroke(&(0..=20).map(|i| Pos2::new(i as f32 * 5., 0.)).collect::<Vec<_>>(), &settings, 4.);
+        assert_eq!(fast.len(), slow.len());
+        assert_spaced(&fast[1..], 4.);
+        assert_spaced(&slow[1..], 4.);
+    }
+
+    #[test]
+    fn smoothing_lags_one_sample_and_flushes_on_release() {
+        let settings = StrokeSettings { smoothing: true };
+        let poses = [Pos2::new(0., 0.), Pos2::new(10., 0.), Pos2::new(20., 0.)];
+        let mut carry = 0.;
+        assert_eq!(next_dabs(&poses[..1], &settings, 2., &mut carry), vec![poses[0]]);
+        assert!(next_dabs(&poses[..2], &settings, 2., &mut carry).is_empty());
+        // The third sample draws the segment up to the second one
+        let middle = next_dabs(&poses, &settings, 2., &mut carry);
+        assert_eq!(middle.last().copied(), Some(poses[1]));
+        assert!(middle.iter().all(|dab| dab.x <= 10. + 1e-3));
+
+        let last = final_dabs(&poses, &settings, 2., &mut carry);
+        assert_eq!(last.len(), 5);
+        assert!((last[4].x - 20.).abs() < 1e-3);
+        assert_spaced(&[&middle[..], &last[..]].concat(), 2.);
+
+        // Without smoothing there is nothing left to flush
+        assert!(final_dabs(&poses, &StrokeSettings::default(), 2., &mut carry).is_empty());
+    }
+}
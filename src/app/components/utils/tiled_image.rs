@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use egui::{Color32, ColorImage};
+
+pub const TILE_SIZE: usize = 256;
+
+/// Premultiplied pixels split into `TILE_SIZE` square tiles. Fully transparent tiles are never allocated,
+/// so mostly empty layers on large canvases stay cheap. Pixels are addressed by the same `y * width + x`
+/// index a dense `ColorImage` would use.
+#[derive(Clone, PartialEq)]
+pub struct TiledImage {
+    pub size: [usize; 2],
+    // Row-major; tiles on the right and bottom edges are padded with transparent pixels.
+    // `Arc` lets duplicated layers and history entries share tiles until one of them is painted on.
+    tiles: Vec<Option<Arc<Vec<Color32>>>>
+}
+
+impl TiledImage {
+    pub fn new(size: [usize; 2]) -> Self {
+        let tiles_len = size[0].div_ceil(TILE_SIZE) * size[1].div_ceil(TILE_SIZE);
+        Self {
+            size,
+            tiles: vec![None; tiles_len]
+        }
+    }
+
+    pub fn from_color_image(image: &ColorImage) -> Self {
+        let mut tiled = Self::new(image.size);
+        for (idx, pixel) in image.pixels.iter().enumerate() {
+            tiled.set(idx, *pixel);
+        }
+        tiled
+    }
+
+    pub fn to_color_image(&self) -> ColorImage {
+        let [width, height] = self.size;
+        let mut image = ColorImage::new(self.size, vec![Color32::TRANSPARENT; width * height]);
+        for (tile_x, tile_y, tile) in self.allocated_tiles() {
+            let min_x = tile_x * TILE_SIZE;
+            let min_y = tile_y * TILE_SIZE;
+            let tile_width = TILE_SIZE.min(width - min_x);
+            for local_y in 0..TILE_SIZE.min(height - min_y) {
+                let row = (min_y + local_y) * width + min_x;
+                image.pixels[row..row + tile_width].copy_from_slice(&tile[local_y * TILE_SIZE..local_y * TILE_SIZE + tile_width]);
+            }
+        }
+        image
+    }
+
+    pub fn width(&self) -> usize {
+        self.size[0]
+    }
+
+    pub fn tiles_x(&self) -> usize {
+        self.size[0].div_ceil(TILE_SIZE)
+    }
+
+    pub fn tiles_y(&self) -> usize {
+        self.size[1].div_ceil(TILE_SIZE)
+    }
+
+    /// Tile number and offset inside that tile for a pixel index.
+    fn locate(&self, idx: usize) -> (usize, usize) {
+        let (x, y) = (idx % self.size[0], idx / self.size[0]);
+        let tile = (y / TILE_SIZE) * self.tiles_x() + x / TILE_SIZE;
+        (tile, (y % TILE_SIZE) * TILE_SIZE + x % TILE_SIZE)
+    }
+
+    pub fn get(&self, idx: usize) -> Color32 {
+        let (tile, offset) = self.locate(idx);
+        self.tiles[tile].as_ref().map(|pixels| pixels[offset]).unwrap_or(Color32::TRANSPARENT)
+    }
+
+    pub fn set(&mut self, idx: usize, color: Color32) {
+        let (tile, offset) = self.locate(idx);
+        match &mut self.tiles[tile] {
+            Some(pixels) => Arc::make_mut(pixels)[offset] = color,
+            // Writing transparency into a missing tile changes nothing
+            None if color == Color32::TRANSPARENT => {},
+            None => {
+                let mut pixels = vec![Color32::TRANSPARENT; TILE_SIZE * TILE_SIZE];
+                pixels[offset] = color;
+                self.tiles[tile] = Some(Arc::new(pixels));
+            }
+        }
+    }
+
+    pub fn tile(&self, tile_x: usize, tile_y: usize) -> Option<&[Color32]> {
+        self.tiles[tile_y * self.tiles_x() + tile_x].as_deref().map(|pixels| pixels.as_slice())
+    }
+
+    /// Replaces a whole tile; a fully transparent one is dropped instead of stored.
+    pub fn set_tile(&mut self, tile_x: usize, tile_y: usize, pixels: Vec<Color32>) {
+        let tile = tile_y * self.tiles_x() + tile_x;
+        self.tiles[tile] = pixels.iter().any(|pixel| *pixel != Color32::TRANSPARENT).then(|| Arc::new(pixels));
+    }
+
+    /// Tiles whose pixels differ from the same tile of `other`, which must have the same size.
+    pub fn changed_tiles(&self, other: &TiledImage) -> impl Iterator<Item = (usize, usize)> {
+        let tiles_x = self.tiles_x();
+        self.tiles.iter().zip(other.tiles.iter()).enumerate()
+            .filter(|(_, (tile, other_tile))| tile != other_tile)
+            .map(move |(tile, _)| (tile % tiles_x, tile / tiles_x))
+    }
+
+    /// Allocated tiles as `(tile_x, tile_y, pixels)`.
+    pub fn allocated_tiles(&self) -> impl Iterator<Item = (usize, usize, &[Color32])> {
+        let tiles_x = self.tiles_x();
+        self.tiles.iter().enumerate()
+            .filter_map(move |(tile, pixels)| pixels.as_ref().map(|pixels| (tile % tiles_x, tile / tiles_x, pixels.as_slice())))
+    }
+
+    /// Indices of every pixel that may be non-transparent, in no particular order.
+    pub fn allocated_indices(&self) -> impl Iterator<Item = usize> {
+        let [width, height] = self.size;
+        self.allocated_tiles().flat_map(move |(tile_x, tile_y, _)| {
+            let min_x = tile_x * TILE_SIZE;
+            let min_y = tile_y * TILE_SIZE;
+            (min_y..(min_y + TILE_SIZE).min(height)).flat_map(move |y| (min_x..(min_x + TILE_SIZE).min(width)).map(move |x| y * width + x))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_on_both_sides_of_a_tile_boundary() {
+        let width = 300;
+        let mut image = TiledImage::new([width, 300]);
+        let (left, right, below) = (255 * width + 255, 255 * width + 256, 256 * width + 255);
+        image.set(left, Color32::RED);
+        image.set(right, Color32::GREEN);
+        image.set(below, Color32::BLUE);
+        assert_eq!([image.get(left), image.get(right), image.get(below)], [Color32::RED, Color32::GREEN, Color32::BLUE]);
+        assert_eq!(image.get(256 * width + 256), Color32::TRANSPARENT);
+        assert_eq!(image.allocated_tiles().count(), 3);
+    }
+
+    #[test]
+    fn round_trip_through_color_image() {
+        let size = [300, 300];
+        let pixels = (0..size[0] * size[1]).map(|idx| if idx % 7 == 0 { Color32::TRANSPARENT } else { Color32::from_rgb(idx as u8, (idx / 300) as u8, 9) }).collect();
+        let image = ColorImage::new(size, pixels);
+        let tiled = TiledImage::from_color_image(&image);
+        assert_eq!([tiled.tiles_x(), tiled.tiles_y()], [2, 2]);
+        assert_eq!(tiled.to_color_image(), image);
+    }
+
+    #[test]
+    fn transparent_images_allocate_no_tiles() {
+        let image = ColorImage::new([300, 200], vec![Color32::TRANSPARENT; 300 * 200]);
+        let mut tiled = TiledImage::from_color_image(&image);
+        assert_eq!(tiled.allocated_tiles().count(), 0);
+        tiled.set(0, Color32::TRANSPARENT);
+        assert_eq!(tiled.allocated_tiles().count(), 0);
+        tiled.set_tile(1, 0, vec![Color32::TRANSPARENT; TILE_SIZE * TILE_SIZE]);
+        assert_eq!(tiled.allocated_tiles().count(), 0);
+    }
+
+    #[test]
+    fn changed_tiles_reports_only_the_written_tile() {
+        let mut image = TiledImage::new([600, 300]);
+        image.set(0, Color32::RED);
+        let before = image.clone();
+        image.set(280 * 600 + 520, Color32::GREEN);
+        assert_eq!(image.changed_tiles(&before).collect::<Vec<_>>(), vec![(2, 1)]);
+        // Writing the same color back leaves the shared tile equal
+        image.set(0, Color32::RED);
+        assert_eq!(image.changed_tiles(&before).count(), 1);
+    }
+}
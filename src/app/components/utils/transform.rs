@@ -0,0 +1,595 @@
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+use egui::{Color32, ColorImage, Id, Pos2, TextureHandle, TextureId, TextureOptions, Vec2};
+
+use crate::app::components::utils::export_image::ExportRegion;
+use crate::app::components::utils::image_color::{blend_pixel, BlendMode};
+use crate::app::components::utils::layer::LayerTexture;
+use crate::app::components::utils::selection::Selection;
+use crate::app::components::utils::tiled_image::{TiledImage, TILE_SIZE};
+
+const ANGLE_STEP: f32 = PI / 12.;
+/// Screen pixels around a handle that still grab it.
+pub const HANDLE_RADIUS: f32 = 6.;
+/// Screen pixels between the top edge and the rotation handle.
+pub const ROTATE_HANDLE_OFFSET: f32 = 24.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    #[default]
+    Bicubic
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 3] = [Interpolation::Nearest, Interpolation::Bilinear, Interpolation::Bicubic];
+
+    /// First source pixel a sample at fraction `t` past a pixel center reads, relative to that pixel,
+    /// and the weights of it and the ones after it.
+    fn taps(&self, t: f32) -> (i64, [f32; 4]) {
+        match self {
+            Interpolation::Nearest => (if t < 0.5 { 0 } else { 1 }, [1., 0., 0., 0.]),
+            Interpolation::Bilinear => (0, [1. - t, t, 0., 0.]),
+            Interpolation::Bicubic => {
+                // Catmull-Rom, sharp without ringing much
+                let (t2, t3) = (t * t, t * t * t);
+                (-1, [
+                    (-t3 + 2. * t2 - t) / 2.,
+                    (3. * t3 - 5. * t2 + 2.) / 2.,
+                    (-3. * t3 + 4. * t2 + t) / 2.,
+                    (t3 - t2) / 2.
+                ])
+            }
+        }
+    }
+}
+
+impl Display for Interpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TransformTarget {
+    // The selected pixels of the current layer, or all of them without a selection
+    #[default]
+    Pixels,
+    // Only the selection outline, leaving the pixels where they are
+    Selection
+}
+
+impl TransformTarget {
+    pub const ALL: [TransformTarget; 2] = [TransformTarget::Pixels, TransformTarget::Selection];
+}
+
+impl Display for TransformTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TransformTarget::Pixels => "Layer pixels",
+            TransformTarget::Selection => "Selection only"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct TransformSettings {
+    pub target: TransformTarget,
+    // How the transformed pixels are resampled when the transform is applied
+    pub interpolation: Interpolation
+}
+
+/// Where the lifted content goes: scaled (negative to flip), skewed, rotated in radians and then moved
+/// so its center lands on `center`, in layer coordinates.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TransformParams {
+    pub center: Pos2,
+    pub scale: Vec2,
+    pub rotation: f32,
+    // Horizontal shift per unit of height, and vertical shift per unit of width
+    pub skew: Vec2
+}
+
+impl TransformParams {
+    fn rotate(&self, v: Vec2) -> Vec2 {
+        let (sin, cos) = self.rotation.sin_cos();
+        Vec2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+    }
+
+    fn unrotate(&self, v: Vec2) -> Vec2 {
+        let (sin, cos) = self.rotation.sin_cos();
+        Vec2::new(v.x * cos + v.y * sin, -v.x * sin + v.y * cos)
+    }
+
+    fn apply_skew(&self, v: Vec2) -> Vec2 {
+        Vec2::new(v.x + self.skew.x * v.y, v.y + self.skew.y * v.x)
+    }
+
+    fn unskew(&self, v: Vec2) -> Option<Vec2> {
+        let det = 1. - self.skew.x * self.skew.y;
+        (det.abs() > f32::EPSILON).then(|| Vec2::new(v.x - self.skew.x * v.y, v.y - self.skew.y * v.x) / det)
+    }
+
+    /// Layer position of a point given relative to the center of the untransformed content.
+    pub fn layer_pos(&self, local: Vec2) -> Pos2 {
+        self.center + self.rotate(self.apply_skew(local * self.scale))
+    }
+
+    /// Inverse of `layer_pos`; `None` when the content is squashed flat.
+    pub fn local_pos(&self, pos: Pos2) -> Option<Vec2> {
+        let unscaled = self.unskew(self.unrotate(pos - self.center))?;
+        (self.scale.x != 0. && self.scale.y != 0.).then(|| unscaled / self.scale)
+    }
+}
+
+/// Part of the transform box a drag grabs. Sides are -1, 0 or 1 per axis of the untransformed box,
+/// so `[1, 0]` is the middle of the right edge.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransformHandle {
+    Move,
+    Scale([i8; 2]),
+    Skew([i8; 2]),
+    Rotate
+}
+
+/// Layer content (or just the selection) lifted off the layer while it is being moved or transformed.
+/// The layer shows the hole it left until the transform is applied or cancelled.
+#[derive(Clone, PartialEq)]
+pub struct FloatingTransform {
+    pub layer_id: Id,
+    // Layer pixels from before anything was lifted, put back on cancel; `None` when only the selection moves
+    original: Option<TiledImage>,
+    // Lifted pixels cropped to `region`
+    pixels: ColorImage,
+    // Selection cropped to `region`, transformed along with the pixels
+    mask: Option<Vec<u8>>,
+    region: ExportRegion,
+    pub params: TransformParams,
+    texture: Option<TextureHandle>,
+    // Handle being dragged, with the params and pointer position from when the drag began
+    drag: Option<(TransformHandle, TransformParams, Pos2)>
+}
+
+/// Smallest rect holding every non-transparent pixel of `image`.
+fn content_bounds(image: &TiledImage) -> Option<ExportRegion> {
+    let width = image.width();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for idx in image.allocated_indices().filter(|idx| image.get(*idx) != Color32::TRANSPARENT) {
+        let (x, y) = (idx % width, idx / width);
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    (min_x <= max_x).then_some(ExportRegion { x: min_x, y: min_y, width: max_x - min_x + 1, height: max_y - min_y + 1 })
+}
+
+/// Weighted sum of the source pixels around `(x, y)`, given in source pixel coordinates where pixel
+/// centers sit on whole numbers. `fetch` returns zeroes outside the source.
+fn sample<const N: usize>(fetch: &impl Fn(i64, i64) -> [f32; N], x: f32, y: f32, interpolation: Interpolation) -> [f32; N] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (x_start, x_weights) = interpolation.taps(x - x0);
+    let (y_start, y_weights) = interpolation.taps(y - y0);
+    let mut sum = [0.; N];
+    for (dy, y_weight) in y_weights.iter().enumerate().filter(|(_, weight)| **weight != 0.) {
+        for (dx, x_weight) in x_weights.iter().enumerate().filter(|(_, weight)| **weight != 0.) {
+            let value = fetch(x0 as i64 + x_start + dx as i64, y0 as i64 + y_start + dy as i64);
+            for (total, channel) in sum.iter_mut().zip(value) {
+                *total += channel * x_weight * y_weight;
+            }
+        }
+    }
+    sum
+}
+
+impl FloatingTransform {
+    /// Lifts the selected pixels of `texture` (every pixel without a selection) off the layer, or only
+    /// the selection with `TransformTarget::Selection`. `None` when there is nothing to transform.
+    pub fn lift(layer_id: Id, texture: &mut LayerTexture, selection: &Selection, target: TransformTarget) -> Option<Self> {
+        let width = texture.image_data.width();
+        let region = match target {
+            TransformTarget::Pixels if !selection.is_active() => content_bounds(&texture.image_data)?,
+            _ => selection.bounds()?
+        };
+        let region_indices = move || (region.y..region.y + region.height).flat_map(move |y| (region.x..region.x + region.width).map(move |x| y * width + x));
+        let mask = selection.mask().map(|mask| region_indices().map(|idx| mask[idx]).collect());
+
+        let (original, pixels) = match target {
+            TransformTarget::Pixels => {
+                let original = texture.image_data.clone();
+                let mut pixels = Vec::with_capacity(region.width * region.height);
+                for idx in region_indices() {
+                    let (color, amount) = (original.get(idx), selection.coverage(idx));
+                    pixels.push(color.gamma_multiply(amount));
+                    if amount > 0. && color != Color32::TRANSPARENT {
+                        texture.image_data.set(idx, color.gamma_multiply(1. - amount));
+                        texture.mark_dirty(idx);
+                    }
+                }
+                (Some(original), ColorImage::new([region.width, region.height], pixels))
+            },
+            TransformTarget::Selection => (None, ColorImage::new([0, 0], Vec::new()))
+        };
+        Some(Self {
+            layer_id,
+            original,
+            pixels,
+            mask,
+            region,
+            params: TransformParams {
+                center: Pos2::new(region.x as f32 + region.width as f32 / 2., region.y as f32 + region.height as f32 / 2.),
+                scale: Vec2::splat(1.),
+                rotation: 0.,
+                skew: Vec2::ZERO
+            },
+            texture: None,
+            drag: None
+        })
+    }
+
+    pub fn is_selection_only(&self) -> bool {
+        self.original.is_none()
+    }
+
+    fn half_size(&self) -> Vec2 {
+        Vec2::new(self.region.width as f32, self.region.height as f32) / 2.
+    }
+
+    /// Corners of the transformed box in layer coordinates, clockwise from the original top left.
+    pub fn corners(&self) -> [Pos2; 4] {
+        let half = self.half_size();
+        [[-1., -1.], [1., -1.], [1., 1.], [-1., 1.]].map(|[x, y]| self.params.layer_pos(Vec2::new(x * half.x, y * half.y)))
+    }
+
+    /// Scale handles on the corners and edge midpoints in layer coordinates, with the side each one moves.
+    pub fn handles(&self) -> Vec<([i8; 2], Pos2)> {
+        let half = self.half_size();
+        let mut handles = Vec::with_capacity(8);
+        for y in -1..=1_i8 {
+            for x in -1..=1_i8 {
+                if x != 0 || y != 0 {
+                    handles.push(([x, y], self.params.layer_pos(Vec2::new(x as f32 * half.x, y as f32 * half.y))));
+                }
+            }
+        }
+        handles
+    }
+
+    /// Rotation handle, sticking `offset` layer pixels out of the middle of the top edge.
+    pub fn rotate_handle(&self, offset: f32) -> (Pos2, Pos2) {
+        let top = self.params.layer_pos(Vec2::new(0., -self.half_size().y));
+        let outwards = (top - self.params.center).normalized();
+        (top, top + outwards * offset)
+    }
+
+    /// What a press at `pos` grabs. `zoom` is the canvas scale, so handles keep their size on screen;
+    /// `skew` (Ctrl) turns edge handles into skew handles. Presses outside the box rotate it.
+    pub fn handle_at(&self, pos: Pos2, zoom: f32, skew: bool) -> TransformHandle {
+        let reach = HANDLE_RADIUS / zoom;
+        if self.rotate_handle(ROTATE_HANDLE_OFFSET / zoom).1.distance(pos) <= reach {
+            return TransformHandle::Rotate;
+        }
+        // Handles of small boxes overlap, so the closest one wins
+        let nearest = self.handles().into_iter().min_by(|(_, a), (_, b)| a.distance(pos).total_cmp(&b.distance(pos)));
+        if let Some((side, handle)) = nearest && handle.distance(pos) <= reach {
+            return if skew && (side[0] == 0 || side[1] == 0) { TransformHandle::Skew(side) } else { TransformHandle::Scale(side) };
+        }
+        let half = self.half_size();
+        let is_inside = self.params.local_pos(pos).is_some_and(|local| local.x.abs() <= half.x && local.y.abs() <= half.y);
+        if is_inside { TransformHandle::Move } else { TransformHandle::Rotate }
+    }
+
+    pub fn begin_drag(&mut self, handle: TransformHandle, pos: Pos2) {
+        self.drag = Some((handle, self.params, pos));
+    }
+
+    pub fn end_drag(&mut self) {
+        self.drag = None;
+    }
+
+    /// Follows the pointer at `pos` with the handle being dragged. `constrain` (Shift) keeps moves on
+    /// one axis, scaling proportional and rotation on 15° steps.
+    pub fn drag_to(&mut self, pos: Pos2, constrain: bool) {
+        let Some((handle, start, origin)) = self.drag else {
+            return;
+        };
+        let half = self.half_size();
+        let mut params = start;
+        match handle {
+            TransformHandle::Move => {
+                let mut delta = pos - origin;
+                if constrain {
+                    if delta.x.abs() > delta.y.abs() { delta.y = 0. } else { delta.x = 0. }
+                }
+                params.center = start.center + delta;
+            },
+            TransformHandle::Rotate => {
+                let angle = |point: Pos2| (point - start.center).angle();
+                params.rotation = start.rotation + angle(pos) - angle(origin);
+                if constrain {
+                    params.rotation = (params.rotation / ANGLE_STEP).round() * ANGLE_STEP;
+                }
+            },
+            TransformHandle::Scale(side) => {
+                let side = Vec2::new(side[0] as f32, side[1] as f32);
+                // The opposite side stays where it is
+                let anchor = start.layer_pos(-side * half);
+                let Some(reach) = start.unskew(start.unrotate(pos - anchor)) else {
+                    return;
+                };
+                let ratio = |axis: usize| if side[axis] == 0. { 1. } else { reach[axis] / (side[axis] * 2. * half[axis] * start.scale[axis]) };
+                let (mut x_ratio, mut y_ratio) = (ratio(0), ratio(1));
+                if constrain {
+                    let uniform = match (side.x == 0., side.y == 0.) {
+                        (true, _) => y_ratio,
+                        (_, true) => x_ratio,
+                        _ => if x_ratio.abs() > y_ratio.abs() { x_ratio } else { y_ratio }
+                    };
+                    (x_ratio, y_ratio) = (uniform, uniform);
+                }
+                // Never squash to nothing, so the transform stays invertible
+                let min_scale = 1. / half.x.max(half.y).max(1.);
+                let keep_size = |scale: f32| if scale.abs() < min_scale { min_scale.copysign(scale) } else { scale };
+                params.scale = Vec2::new(keep_size(start.scale.x * x_ratio), keep_size(start.scale.y * y_ratio));
+                params.center = anchor + params.rotate(params.apply_skew(side * half * params.scale));
+            },
+            TransformHandle::Skew(side) => {
+                let side = Vec2::new(side[0] as f32, side[1] as f32);
+                let anchor = start.layer_pos(-side * half);
+                let reach = start.unrotate(pos - anchor);
+                if side.x == 0. {
+                    params.skew.x = reach.x / (side.y * 2. * half.y * start.scale.y);
+                } else {
+                    params.skew.y = reach.y / (side.x * 2. * half.x * start.scale.x);
+                }
+                if params.unskew(Vec2::X).is_none() {
+                    return;
+                }
+                params.center = anchor + params.rotate(params.apply_skew(side * half * params.scale));
+            }
+        }
+        self.params = params;
+    }
+
+    /// Mirrors the content inside its box, horizontally or vertically.
+    pub fn flip(&mut self, horizontal: bool) {
+        if horizontal {
+            self.params.scale.x = -self.params.scale.x;
+        } else {
+            self.params.scale.y = -self.params.scale.y;
+        }
+    }
+
+    /// Texture of the lifted pixels for the live preview, uploaded the first time it is asked for.
+    pub fn texture(&mut self, ctx: &egui::Context) -> Option<TextureId> {
+        if self.is_selection_only() {
+            return None;
+        }
+        let pixels = &self.pixels;
+        Some(self.texture.get_or_insert_with(|| ctx.load_texture("floating_transform", pixels.clone(), TextureOptions::LINEAR)).id())
+    }
+
+    /// Calls `put` with every layer pixel of a canvas of `size` the transformed box may cover and the
+    /// source resampled there.
+    fn resample<const N: usize>(&self, size: [usize; 2], interpolation: Interpolation, fetch: impl Fn(i64, i64) -> [f32; N], mut put: impl FnMut(usize, [f32; N])) {
+        let [width, height] = size;
+        let corners = self.corners();
+        let min = corners.iter().fold(Pos2::new(f32::INFINITY, f32::INFINITY), |min, corner| min.min(*corner));
+        let max = corners.iter().fold(Pos2::new(f32::NEG_INFINITY, f32::NEG_INFINITY), |max, corner| max.max(*corner));
+        let (min_x, min_y) = ((min.x - 1.).floor().max(0.) as usize, (min.y - 1.).floor().max(0.) as usize);
+        let (max_x, max_y) = (((max.x + 1.).ceil().max(0.) as usize).min(width), ((max.y + 1.).ceil().max(0.) as usize).min(height));
+        let half = self.half_size();
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let Some(local) = self.params.local_pos(Pos2::new(x as f32 + 0.5, y as f32 + 0.5)) else {
+                    return;
+                };
+                if local.x.abs() > half.x + 2. || local.y.abs() > half.y + 2. {
+                    continue;
+                }
+                let source = local + half - Vec2::splat(0.5);
+                put(y * width + x, sample(&fetch, source.x, source.y, interpolation));
+            }
+        }
+    }
+
+    /// The selection moved along with the transform, as a mask of a canvas of `size`.
+    pub fn transformed_mask(&self, interpolation: Interpolation, size: [usize; 2]) -> Option<Vec<u8>> {
+        let mask = self.mask.as_ref()?;
+        let [region_width, region_height] = [self.region.width as i64, self.region.height as i64];
+        let fetch = |x: i64, y: i64| {
+            if x < 0 || y < 0 || x >= region_width || y >= region_height { [0.] } else { [mask[(y * region_width + x) as usize] as f32] }
+        };
+        let mut transformed = vec![0_u8; size[0] * size[1]];
+        self.resample(size, interpolation, fetch, |idx, [amount]| transformed[idx] = amount.round().clamp(0., 255.) as u8);
+        Some(transformed)
+    }
+
+    /// Resamples the lifted pixels into place on `texture`. Returns the pixels that differ from before
+    /// the lift, with their color back then, for the history.
+    pub fn apply(&self, texture: &mut LayerTexture, interpolation: Interpolation) -> Vec<(usize, Color32)> {
+        let Some(original) = &self.original else {
+            return Vec::new();
+        };
+        let pixels = &self.pixels;
+        let [region_width, region_height] = [self.region.width as i64, self.region.height as i64];
+        let fetch = |x: i64, y: i64| {
+            if x < 0 || y < 0 || x >= region_width || y >= region_height {
+                [0.; 4]
+            } else {
+                pixels.pixels[(y * region_width + x) as usize].to_array().map(|channel| channel as f32)
+            }
+        };
+        let size = texture.image_data.size;
+        let mut placed = Vec::new();
+        self.resample(size, interpolation, fetch, |idx, [r, g, b, a]| {
+            // Sharpening can overshoot a channel past its alpha, which premultiplied colors can't hold
+            let alpha = a.round().clamp(0., 255.) as u8;
+            if alpha > 0 {
+                let channel = |value: f32| (value.round().clamp(0., 255.) as u8).min(alpha);
+                placed.push((idx, Color32::from_rgba_premultiplied(channel(r), channel(g), channel(b), alpha)));
+            }
+        });
+        for (idx, color) in placed {
+            let blended = blend_pixel(texture.image_data.get(idx), color, BlendMode::Normal);
+            texture.image_data.set(idx, blended);
+            texture.mark_dirty(idx);
+        }
+
+        let [width, height] = size;
+        let mut changes = Vec::new();
+        for (tile_x, tile_y) in texture.image_data.changed_tiles(original) {
+            let (min_x, min_y) = (tile_x * TILE_SIZE, tile_y * TILE_SIZE);
+            for y in min_y..(min_y + TILE_SIZE).min(height) {
+                for x in min_x..(min_x + TILE_SIZE).min(width) {
+                    let idx = y * width + x;
+                    if texture.image_data.get(idx) != original.get(idx) {
+                        changes.push((idx, original.get(idx)));
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    /// Puts the lifted pixels back where they were.
+    pub fn cancel(&self, texture: &mut LayerTexture) {
+        if let Some(original) = &self.original {
+            texture.replace_image(original.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 8;
+
+    // 3x3 source with value 10 * (x + 1) + y at each pixel
+    fn fetch(x: i64, y: i64) -> [f32; 1] {
+        if (0..3).contains(&x) && (0..3).contains(&y) { [(10 * (x + 1) + y) as f32] } else { [0.] }
+    }
+
+    // 6x6 source that is 100 everywhere
+    fn flat(x: i64, y: i64) -> [f32; 1] {
+        if (0..6).contains(&x) && (0..6).contains(&y) { [100.] } else { [0.] }
+    }
+
+    fn assert_close(actual: [f32; 1], expected: f32) {
+        assert!((actual[0] - expected).abs() < 1e-3, "{} != {}", actual[0], expected);
+    }
+
+    fn texture_with(pixels: &[(usize, usize, Color32)]) -> LayerTexture {
+        let mut texture = LayerTexture::new(SIZE, SIZE);
+        for (x, y, color) in pixels {
+            texture.image_data.set(y * SIZE + x, *color);
+        }
+        texture
+    }
+
+    fn pixel(texture: &LayerTexture, x: usize, y: usize) -> Color32 {
+        texture.image_data.get(y * SIZE + x)
+    }
+
+    fn lift(texture: &mut LayerTexture) -> FloatingTransform {
+        FloatingTransform::lift(Id::new("layer"), texture, &Selection::new([SIZE, SIZE]), TransformTarget::Pixels).unwrap()
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_pixel() {
+        assert_close(sample(&fetch, 1., 1., Interpolation::Nearest), 21.);
+        assert_close(sample(&fetch, 1.4, 1., Interpolation::Nearest), 21.);
+        assert_close(sample(&fetch, 1.6, 1., Interpolation::Nearest), 31.);
+        assert_close(sample(&fetch, 2.4, 2., Interpolation::Nearest), 32.);
+        assert_close(sample(&fetch, 2.6, 2., Interpolation::Nearest), 0.);
+    }
+
+    #[test]
+    fn bilinear_blends_neighbours_and_fades_out_past_the_edge() {
+        assert_close(sample(&fetch, 0.5, 0., Interpolation::Bilinear), 15.);
+        assert_close(sample(&fetch, 1.25, 1.5, Interpolation::Bilinear), 24.);
+        assert_close(sample(&fetch, 2., 2., Interpolation::Bilinear), 32.);
+        assert_close(sample(&flat, -0.5, 0., Interpolation::Bilinear), 50.);
+        assert_close(sample(&flat, 5.5, 5.5, Interpolation::Bilinear), 25.);
+    }
+
+    #[test]
+    fn bicubic_keeps_pixel_centers_and_flat_areas() {
+        assert_close(sample(&fetch, 1., 1., Interpolation::Bicubic), 21.);
+        assert_close(sample(&fetch, 0., 2., Interpolation::Bicubic), 12.);
+        assert_close(sample(&flat, 2.3, 2.7, Interpolation::Bicubic), 100.);
+        // Halfway past the last pixel the weights are -1/16, 9/16, 9/16, -1/16
+        assert_close(sample(&flat, 5.5, 2., Interpolation::Bicubic), 50.);
+        assert_close(sample(&flat, -0.5, 2., Interpolation::Bicubic), 50.);
+    }
+
+    #[test]
+    fn cancel_restores_the_lifted_pixels() {
+        let mut texture = texture_with(&[(2, 3, Color32::RED), (4, 5, Color32::BLUE)]);
+        let before = texture.image_data.clone();
+        let transform = lift(&mut texture);
+        assert_eq!(pixel(&texture, 2, 3), Color32::TRANSPARENT);
+        assert_eq!(pixel(&texture, 4, 5), Color32::TRANSPARENT);
+        transform.cancel(&mut texture);
+        assert!(texture.image_data == before);
+    }
+
+    #[test]
+    fn translation_moves_pixels_and_undoes_exactly() {
+        let mut texture = texture_with(&[(2, 3, Color32::RED), (3, 3, Color32::BLUE)]);
+        let before = texture.image_data.clone();
+        let mut transform = lift(&mut texture);
+        transform.params.center += Vec2::new(2., 1.);
+        for interpolation in Interpolation::ALL {
+            let mut moved = texture.clone();
+            let changes = transform.apply(&mut moved, interpolation);
+            assert_eq!(pixel(&moved, 4, 4), Color32::RED, "{interpolation}");
+            assert_eq!(pixel(&moved, 5, 4), Color32::BLUE, "{interpolation}");
+            assert_eq!(pixel(&moved, 2, 3), Color32::TRANSPARENT, "{interpolation}");
+            assert_eq!(moved.image_data.allocated_indices().filter(|idx| moved.image_data.get(*idx) != Color32::TRANSPARENT).count(), 2);
+
+            for (idx, color) in changes {
+                moved.image_data.set(idx, color);
+            }
+            assert!(moved.image_data == before, "{interpolation}");
+        }
+    }
+
+    #[test]
+    fn flip_mirrors_the_content() {
+        let mut texture = texture_with(&[(2, 3, Color32::RED), (3, 3, Color32::BLUE), (2, 4, Color32::GREEN)]);
+        let mut transform = lift(&mut texture);
+        let mut flipped = texture.clone();
+        transform.flip(true);
+        transform.apply(&mut flipped, Interpolation::Nearest);
+        assert_eq!([pixel(&flipped, 2, 3), pixel(&flipped, 3, 3), pixel(&flipped, 3, 4)], [Color32::BLUE, Color32::RED, Color32::GREEN]);
+
+        let mut flipped = texture.clone();
+        transform.flip(true);
+        transform.flip(false);
+        transform.apply(&mut flipped, Interpolation::Nearest);
+        assert_eq!([pixel(&flipped, 2, 4), pixel(&flipped, 3, 4), pixel(&flipped, 2, 3)], [Color32::RED, Color32::BLUE, Color32::GREEN]);
+    }
+
+    #[test]
+    fn constrained_scaling_keeps_the_aspect_ratio() {
+        let mut texture = texture_with(&[(1, 1, Color32::RED), (4, 2, Color32::RED)]);
+        let mut transform = lift(&mut texture);
+        let corner = transform.handles().into_iter().find(|(side, _)| *side == [1, 1]).unwrap().1;
+        assert_eq!(transform.handle_at(corner, 1., false), TransformHandle::Scale([1, 1]));
+
+        transform.begin_drag(TransformHandle::Scale([1, 1]), corner);
+        transform.drag_to(corner + Vec2::new(4., 0.5), true);
+        assert_eq!(transform.params.scale, Vec2::splat(2.));
+        // The opposite corner stays put
+        assert_eq!(transform.corners()[0], Pos2::new(1., 1.));
+        assert_eq!(transform.corners()[2], Pos2::new(9., 5.));
+
+        transform.drag_to(corner + Vec2::new(4., 0.5), false);
+        assert_eq!(transform.params.scale, Vec2::new(2., 1.25));
+    }
+}
@@ -0,0 +1,178 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use egui::{Align2, Id, PointerButton, Vec2, Window};
+
+use crate::app::components::utils::export_image::{ExportFormat, ExportOptions, ExportRegion, ResampleFilter};
+use crate::app::components::AppComponentExt;
+use crate::app::App;
+
+const SCALE_PRESETS: [f32; 3] = [0.5, 1., 2.];
+
+#[derive(Clone, PartialEq)]
+pub struct ExportImageWidget {
+    pub is_open: bool,
+    pub file_name: String,
+    pub options: ExportOptions,
+    // Files about to be written, held while the user confirms replacing the ones that already exist
+    pub pending_targets: Vec<(PathBuf, Option<Id>)>
+}
+
+impl Default for ExportImageWidget {
+    fn default() -> Self {
+        Self {
+            is_open: false,
+            file_name: "final_output".to_string(),
+            options: ExportOptions::default(),
+            pending_targets: Vec::new()
+        }
+    }
+}
+
+impl AppComponentExt for ExportImageWidget {
+    type Context = App;
+    fn add(ctx: &mut Self::Context, ui: &mut eframe::egui::Ui) {
+        if !ctx.app_settings.export_image_widget.is_open {
+            return;
+        }
+        let canvas_size = [ctx.app_settings.layer_size.x as usize, ctx.app_settings.layer_size.y as usize];
+        let has_selection = ctx.app_state.selection.is_active();
+        let mut targets_to_write: Option<Vec<(PathBuf, Option<Id>)>> = None;
+        Window::new("Export image")
+            .anchor(Align2::CENTER_CENTER, Vec2::new(0., -300.))
+            .collapsible(false)
+            .resizable(false)
+            .show(ui.ctx(), |ui| {
+                let folder = ctx.export_directory();
+                ui.horizontal(|ui| {
+                    ui.label("Folder");
+                    ui.label(folder.display().to_string());
+                    if ui.button("Change").clicked_by(PointerButton::Primary) {
+                        ctx.set_base_directory();
+                    }
+                });
+                let widget = &mut ctx.app_settings.export_image_widget;
+                let options = &mut widget.options;
+                ui.horizontal(|ui| {
+                    ui.label("File name");
+                    ui.text_edit_singleline(&mut widget.file_name);
+                    ui.label(format!(".{}", options.format.extension()));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Format");
+                    egui::ComboBox::from_id_salt("export_format")
+                        .selected_text(options.format.to_string())
+                        .show_ui(ui, |ui| {
+                            for format in ExportFormat::ALL {
+                                ui.selectable_value(&mut options.format, format, format.to_string());
+                            }
+                        });
+                    if options.format == ExportFormat::Jpeg {
+                        ui.add(egui::Slider::new(&mut options.jpeg_quality, RangeInclusive::new(1, 100)).prefix("Quality: "));
+                    }
+                    if options.format == ExportFormat::Png {
+                        ui.checkbox(&mut options.indexed, "Indexed").on_hover_text("Write palette indices instead of RGBA, using the palette the document is locked to or else the current one");
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Scale");
+                    for scale in SCALE_PRESETS {
+                        if ui.selectable_label(options.scale == scale, format!("{}%", scale * 100.)).clicked_by(PointerButton::Primary) {
+                            options.scale = scale;
+                        }
+                    }
+                    let mut percent = options.scale * 100.;
+                    if ui.add(egui::DragValue::new(&mut percent).range(RangeInclusive::new(1., 800.)).suffix("%")).changed() {
+                        options.scale = percent / 100.;
+                    }
+                    ui.add_enabled_ui(options.scale != 1., |ui| {
+                        egui::ComboBox::from_id_salt("export_filter")
+                            .selected_text(options.filter.to_string())
+                            .show_ui(ui, |ui| {
+                                for filter in ResampleFilter::ALL {
+                                    ui.selectable_value(&mut options.filter, filter, filter.to_string());
+                                }
+                            });
+                    });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut options.keep_transparency, "Keep transparency");
+                    if !options.keep_transparency {
+                        ui.label("Background");
+                        ui.color_edit_button_srgba(&mut options.background);
+                    }
+                });
+
+                ui.add_enabled(has_selection, egui::Checkbox::new(&mut options.selection_only, "Selection only"));
+                let is_selection_only = options.selection_only && has_selection;
+                ui.add_enabled_ui(!is_selection_only, |ui| ui.horizontal(|ui| {
+                    let mut is_cropped = options.region.is_some();
+                    if ui.checkbox(&mut is_cropped, "Crop").changed() {
+                        options.region = is_cropped.then_some(ExportRegion { x: 0, y: 0, width: canvas_size[0], height: canvas_size[1] });
+                    }
+                    if let Some(region) = &mut options.region {
+                        ui.add(egui::DragValue::new(&mut region.x).range(RangeInclusive::new(0, canvas_size[0].saturating_sub(1))).prefix("x: "));
+                        ui.add(egui::DragValue::new(&mut region.y).range(RangeInclusive::new(0, canvas_size[1].saturating_sub(1))).prefix("y: "));
+                        ui.add(egui::DragValue::new(&mut region.width).range(RangeInclusive::new(1, canvas_size[0].saturating_sub(region.x).max(1))).prefix("w: "));
+                        ui.add(egui::DragValue::new(&mut region.height).range(RangeInclusive::new(1, canvas_size[1].saturating_sub(region.y).max(1))).prefix("h: "));
+                    }
+                }));
+                ui.checkbox(&mut options.per_layer, "Export each layer as a separate file");
+                ui.separator();
+
+                if !widget.pending_targets.is_empty() {
+                    let existing = widget.pending_targets.iter().filter(|(path, _)| path.exists()).count();
+                    if existing == 1 && widget.pending_targets.len() == 1 {
+                        ui.label(format!("{} already exists. Replace it?", widget.pending_targets[0].0.display()));
+                    } else {
+                        ui.label(format!("{existing} of the files already exist. Replace them?"));
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked_by(PointerButton::Primary) {
+                            widget.pending_targets.clear();
+                        }
+                        if ui.button("Replace").clicked_by(PointerButton::Primary) {
+                            targets_to_write = Some(std::mem::take(&mut widget.pending_targets));
+                        }
+                    });
+                } else {
+                    let mut is_export_clicked = false;
+                    ui.horizontal(|ui| {
+                        if ui.button("Close").clicked_by(PointerButton::Primary) {
+                            widget.is_open = false;
+                        }
+                        let has_name = !ExportFormat::file_stem(&widget.file_name).is_empty();
+                        is_export_clicked = ui.add_enabled(has_name, egui::Button::new("Export")).clicked_by(PointerButton::Primary);
+                    });
+                    if is_export_clicked {
+                        let widget = &ctx.app_settings.export_image_widget;
+                        let targets = ctx.export_targets(&folder, &widget.file_name, &widget.options);
+                        if targets.iter().any(|(path, _)| path.exists()) {
+                            ctx.app_settings.export_image_widget.pending_targets = targets;
+                        } else {
+                            targets_to_write = Some(targets);
+                        }
+                    }
+                }
+            });
+
+        if let Some(targets) = targets_to_write {
+            let options = ctx.app_settings.export_image_widget.options.clone();
+            match ctx.export_image(&targets, &options) {
+                Ok(()) => {
+                    let message = match targets.as_slice() {
+                        [(path, _)] => format!("Exported {}", path.display()),
+                        _ => format!("Exported {} files", targets.len())
+                    };
+                    ctx.app_settings.notification_widget.success(message);
+                    ctx.app_settings.export_image_widget.is_open = false;
+                },
+                Err(error) => {
+                    ctx.app_settings.notification_widget.error(format!("Failed to export: {error}"));
+                }
+            }
+        }
+    }
+}
@@ -5,6 +5,8 @@ use egui::{Align, Align2, Color32, ColorImage, CursorIcon, Frame, Layout, Pointe
 use image::imageops::FilterType;
 use image::DynamicImage;
 
+use crate::app::components::color_palette::quantize_controls;
+use crate::app::components::utils::image_color::BlendMode;
 use crate::app::components::utils::layer::{Layer, LayerTexture, Transform};
 use crate::app::components::utils::new_rand_id;
 use crate::app::components::AppComponentExt;
@@ -112,6 +114,7 @@ impl AppComponentExt for ImportImageWidget {
         let transform = &mut widget.transform;
         let crop = &mut widget.crop;
         let drag_modifier = &mut widget.drag_modifier;
+        let mut generate_palette = false;
         Window::new("Import image")
         .title_bar(false)
         .anchor(Align2::CENTER_CENTER, Vec2::ZERO)
@@ -263,6 +266,8 @@ impl AppComponentExt for ImportImageWidget {
                                         id: new_rand_id(), 
                                         name: "Image layer".to_string(), 
                                         is_visible: true,
+                                        blend_mode: BlendMode::Normal,
+                                        opacity: 1.,
                                         texture: LayerTexture::new(layer_size.clone().x.floor() as usize, layer_size.clone().y.floor() as usize)
                                     };
 
@@ -299,24 +304,23 @@ impl AppComponentExt for ImportImageWidget {
                                             // println!("point: x: {:#}, y: {:#}", x_cord, y_cord);
                                             let point_position = (y_cord * new_image_layer.texture.layer_size.clone().x).floor() as usize + x_cord.floor() as usize;
                                             let image_point_position = (img_y_cord  * scaled_color_image.size[0].clone() as f32).floor() as usize + img_x_cord.floor() as usize; 
-                                            new_image_layer.texture.image_data.pixels[point_position] = scaled_color_image.pixels[image_point_position];
+                                            new_image_layer.texture.image_data.set(point_position, scaled_color_image.pixels[image_point_position]);
                                             
                                            
                                         }
                                     }
                                     if let Some(active_layer) = ctx.app_state.current_layer  {
                                         if let Some(find_index) = ctx.app_state.layers_container.layers.iter().position(|l| l.id == active_layer) {
-                                            ctx.app_state.current_layer = Some(new_image_layer.id.clone());
                                             if find_index == 0 {
-                                                ctx.app_state.layers_container.layers.insert(0, new_image_layer);
+                                                ctx.app_state.add_layer(0, new_image_layer);
                                             } else {
-                                                ctx.app_state.layers_container.layers.insert(find_index - 1, new_image_layer);
+                                                ctx.app_state.add_layer(find_index - 1, new_image_layer);
 
                                             }
                                         }
                                         
                                     } else {
-                                        ctx.app_state.layers_container.layers.insert(0, new_image_layer);
+                                        ctx.app_state.add_layer(0, new_image_layer);
 
                                     }
                                 }
@@ -334,10 +338,18 @@ impl AppComponentExt for ImportImageWidget {
                             ui.add_space(5.);
                             ui.add(egui::DragValue::new(&mut transform.scale).speed(0.05).prefix("Image scale: ").range(RangeInclusive::new(0.1, 4.0)).clamp_existing_to_range(false));
                             ui.label(format!("Position: x: {:.1}, y: {:.1}", transform.position.x, transform.position.y));
+                            ui.separator();
+                            quantize_controls(ui, &mut ctx.app_settings.quantize_settings);
+                            if ui.button("Generate palette").on_hover_text("Add the dominant colors of the image to the current palette").clicked() {
+                                generate_palette = true;
+                            }
                         });
                     });
                 });
                 
             });
+        if generate_palette {
+            ctx.generate_palette_from_import();
+        }
     }
 }
\ No newline at end of file
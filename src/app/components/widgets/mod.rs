@@ -1 +1,3 @@
 pub mod import_image_widget;
+pub mod export_image_widget;
+pub mod notification_widget;
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use egui::{Align2, Color32, Frame, Id, RichText, Vec2};
+
+use crate::app::components::AppComponentExt;
+use crate::app::App;
+
+const NOTIFICATION_SECONDS: f64 = 4.;
+
+#[derive(Clone, PartialEq)]
+pub struct Notification {
+    pub message: String,
+    pub is_error: bool,
+    // Set the first frame the notification is drawn
+    shown_at: Option<f64>
+}
+
+/// Toast in the bottom right corner reporting how an action such as an export went.
+#[derive(Clone, PartialEq, Default)]
+pub struct NotificationWidget {
+    current: Option<Notification>
+}
+
+impl NotificationWidget {
+    pub fn success(&mut self, message: impl Into<String>) {
+        self.current = Some(Notification { message: message.into(), is_error: false, shown_at: None });
+    }
+
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.current = Some(Notification { message: message.into(), is_error: true, shown_at: None });
+    }
+}
+
+impl AppComponentExt for NotificationWidget {
+    type Context = App;
+    fn add(ctx: &mut Self::Context, ui: &mut eframe::egui::Ui) {
+        let widget = &mut ctx.app_settings.notification_widget;
+        let Some(notification) = &mut widget.current else {
+            return;
+        };
+        let now = ui.input(|i| i.time);
+        let shown_at = *notification.shown_at.get_or_insert(now);
+        // Errors stay until dismissed so they can't be missed
+        if !notification.is_error {
+            let remaining = NOTIFICATION_SECONDS - (now - shown_at);
+            if remaining <= 0. {
+                widget.current = None;
+                return;
+            }
+            ui.ctx().request_repaint_after(Duration::from_secs_f64(remaining));
+        }
+
+        let mut is_dismissed = false;
+        egui::Area::new(Id::new("notification"))
+            .anchor(Align2::RIGHT_BOTTOM, Vec2::new(-20., -20.))
+            .show(ui.ctx(), |ui| {
+                Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        let color = if notification.is_error { Color32::from_rgb(230, 90, 90) } else { Color32::from_rgb(100, 200, 120) };
+                        ui.label(RichText::new(notification.message.as_str()).color(color));
+                        if ui.button(egui_phosphor::regular::X).clicked() {
+                            is_dismissed = true;
+                        }
+                    });
+                });
+            });
+        if is_dismissed {
+            widget.current = None;
+        }
+    }
+}
@@ -1,29 +1,49 @@
 pub mod components;
 
 
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 
-use egui::{Color32, ColorImage, Id, Pos2, TextureOptions, Vec2};
+use egui::{Color32, ColorImage, Id, Key, KeyboardShortcut, Modifiers, Pos2, TextureOptions, Vec2};
 
 
 use components::{AppComponentExt, canvas::Canvas};
-use image::{ImageBuffer};
 use rfd::FileDialog;
 
+use crate::app::components::utils::brush::{Brush, BrushPresets, BrushTip, StrokeCoverage};
+use crate::app::components::utils::composite_cache::CompositeCache;
 use crate::app::components::utils::create_paint::NewPaintSetting;
-use crate::app::components::utils::image_color::composite_layers;
+use crate::app::components::utils::eyedropper::{sample_color, EyedropperSettings};
+use crate::app::components::utils::flood_fill::{color_range_coverage, fill_coverage, FillSample, FillSettings};
+use crate::app::components::utils::selection::{feather, Selection, SelectionMode, SelectionSettings};
+use crate::app::components::utils::tiled_image::TiledImage;
+use crate::app::components::utils::transform::{FloatingTransform, Interpolation, TransformSettings, TransformTarget};
+use crate::app::components::utils::shape::{shape_coverage, shape_path, ShapeSettings};
+use crate::app::components::utils::export_image::{layer_file_stem, write_image, ExportFormat, ExportOptions};
+use crate::app::components::utils::history::{History, HistoryAction, PixelDelta};
+use crate::app::components::utils::image_color::{blend_pixel, composite_layers, composite_pixel, BlendMode};
 use crate::app::components::utils::layer::LayersContainer;
+use crate::app::components::utils::palette::{read_palette, write_palette, Palette, PaletteFormat, DEFAULT_PALETTE_NAME};
+use crate::app::components::utils::indexed::{write_indexed_png, PaletteLock, PaletteSwap};
+use crate::app::components::utils::quantize::{dominant_colors, image_samples, tiled_image_samples, QuantizeSettings};
+use crate::app::components::utils::open_raster::{read_open_raster, write_open_raster, OPEN_RASTER_EXTENSION};
+use crate::app::components::utils::project_file::{ProjectDocument, PROJECT_EXTENSION};
+use crate::app::components::utils::stroke::{final_dabs, next_dabs, StrokeSettings};
+use crate::app::components::widgets::export_image_widget::ExportImageWidget;
 use crate::app::components::widgets::import_image_widget::{ImportImageWidget, Texture};
+use crate::app::components::widgets::notification_widget::NotificationWidget;
 use crate::app::components::{
-    color_palette::ColorPalette,
+    color_palette::{ColorPalette, PalettePanelState},
     color_picker::ColorPicker,
-    layers_display_container::LayersDisplayContainer,
-    tools_bar::ToolBar
+    layers_display_container::{LayersDisplayContainer, LayersPanelState},
+    tools_bar::{ToolBar, ToolBarState}
 };
 
 use crate::app::components::utils::{
-    draw_tool::{DrawTool, Tools},
-    layer::{Layer, LayerTexture, PaintColor},
+    draw_tool::{DrawTool, Pencil, Tools},
+    layer::{Layer, LayerTexture, PaintColor, Transform},
     new_rand_id,
     pencil_cursor::PencilCursor
 };
@@ -41,8 +61,23 @@ pub struct AppSettings {
     pencil_cursor: PencilCursor,
     draw_tools: Tools,
     base_dir: Option<PathBuf>,
+    project_path: Option<PathBuf>,
     new_paint_settings: NewPaintSetting,
-    import_image_widget: ImportImageWidget
+    stroke_settings: StrokeSettings,
+    fill_settings: FillSettings,
+    shape_settings: ShapeSettings,
+    selection_settings: SelectionSettings,
+    transform_settings: TransformSettings,
+    eyedropper_settings: EyedropperSettings,
+    quantize_settings: QuantizeSettings,
+    brush_presets: BrushPresets,
+    layer_composites: CompositeCache,
+    tool_bar: ToolBarState,
+    import_image_widget: ImportImageWidget,
+    export_image_widget: ExportImageWidget,
+    notification_widget: NotificationWidget,
+    layers_panel: LayersPanelState,
+    palette_panel: PalettePanelState
 }
 
 impl Default for AppSettings {
@@ -56,8 +91,23 @@ impl Default for AppSettings {
             draw_tools: Tools::default(),
             pencil_cursor: PencilCursor::default(),
             new_paint_settings: NewPaintSetting::default(),
+            stroke_settings: StrokeSettings::default(),
+            fill_settings: FillSettings::default(),
+            shape_settings: ShapeSettings::default(),
+            selection_settings: SelectionSettings::default(),
+            transform_settings: TransformSettings::default(),
+            eyedropper_settings: EyedropperSettings::default(),
+            quantize_settings: QuantizeSettings::default(),
+            brush_presets: BrushPresets::default(),
+            layer_composites: CompositeCache::default(),
+            tool_bar: ToolBarState::default(),
             base_dir: None,
-            import_image_widget: ImportImageWidget::default()
+            project_path: None,
+            import_image_widget: ImportImageWidget::default(),
+            export_image_widget: ExportImageWidget::default(),
+            notification_widget: NotificationWidget::default(),
+            layers_panel: LayersPanelState::default(),
+            palette_panel: PalettePanelState::default()
         }
     }
 }
@@ -65,15 +115,29 @@ impl Default for AppSettings {
 #[derive(Clone, PartialEq)]
 pub struct AppState {
     is_dragging: bool,
+    // Samples of the stroke in progress, in layer coordinates
     poses: Vec<Pos2>,
+    // Distance travelled since the last dab of the stroke in progress
+    stroke_carry: f32,
+    // Points placed so far of the shape or selection being drawn, in layer coordinates
+    shape_anchors: Vec<Pos2>,
+    selection: Selection,
+    // Content being moved or transformed, lifted off its layer until applied
+    transform: Option<FloatingTransform>,
     layers_container: LayersContainer,
     
     current_layer: Option<Id>,
   
-    color_palette: Vec<PaintColor>,
+    palettes: Vec<Palette>,
+    // Palette the swatch grid shows and new swatches go to
+    current_palette: Id,
+    // Palette every layer pixel is locked to in an indexed document, `None` for full color
+    indexed_palette: Option<Id>,
     current_color: Option<PaintColor>,
-    current_stroke_width: f32,
-    current_draw_tool: Option<DrawTool>
+    current_brush: Brush,
+    stroke_coverage: StrokeCoverage,
+    current_draw_tool: Option<DrawTool>,
+    history: History
 }
 
 
@@ -83,31 +147,620 @@ impl AppState {
             id: new_rand_id(), 
             name: "Layer 1".to_string(), 
             is_visible: true,
+            blend_mode: BlendMode::Normal,
+            opacity: 1.,
             texture: LayerTexture::new(settings.layer_size.x as usize, settings.layer_size.y as usize)
         };
         let mut palette: Vec<PaintColor> = Vec::new();
         let mut layers_container = LayersContainer::default();
         layers_container.layers.push(default_layer.clone());
-        palette.push(PaintColor{color: Color32::BLACK, id: new_rand_id()});
-        palette.push(PaintColor{color: Color32::WHITE, id: new_rand_id()});
+        palette.push(PaintColor{color: Color32::BLACK, ..Default::default()});
+        palette.push(PaintColor{color: Color32::WHITE, ..Default::default()});
 
-        palette.push(PaintColor{color: Color32::BLUE, id: new_rand_id()});
+        palette.push(PaintColor{color: Color32::BLUE, ..Default::default()});
         // palette.push(Color32::WHITE);
         // palette.push(Color32::GREEN);
+        let default_palette = Palette::new(DEFAULT_PALETTE_NAME, palette.clone());
         let default_tool = Tools::default().tools[0].clone();
         
         Self { 
             is_dragging: false,
             poses: Vec::new(),
+            stroke_carry: 0.,
+            shape_anchors: Vec::new(),
+            selection: Selection::new([settings.layer_size.x as usize, settings.layer_size.y as usize]),
+            transform: None,
             current_draw_tool: Some(default_tool),
             layers_container: layers_container,
 
-            color_palette: palette.clone(),
+            current_palette: default_palette.id,
+            palettes: vec![default_palette],
+            indexed_palette: None,
             current_color: Some(palette[0].clone()),
-            current_stroke_width: 10.,
+            current_brush: Brush::default(),
+            stroke_coverage: StrokeCoverage::default(),
            
             
-            current_layer: Some(default_layer.id)
+            current_layer: Some(default_layer.id),
+            history: History::default()
+        }
+    }
+
+    pub fn add_layer(&mut self, index: usize, mut layer: Layer) {
+        if let Some(lock) = self.palette_lock() {
+            lock.snap_image(&mut layer.texture.image_data);
+        }
+        let index = index.min(self.layers_container.layers.len());
+        let layer_id = layer.id;
+        self.history.push(HistoryAction::AddLayer {
+            index,
+            layer_id,
+            previous_layer: self.current_layer,
+            removed: None
+        });
+        self.layers_container.layers.insert(index, layer);
+        self.current_layer = Some(layer_id);
+    }
+
+    pub fn delete_layer(&mut self, layer_id: Id) {
+        // Painting always needs a target, so the last layer stays
+        if self.layers_container.layers.len() <= 1 {
+            return;
+        }
+        if let Some(index) = self.layers_container.layers.iter().position(|l| l.id == layer_id) {
+            let mut action = HistoryAction::RemoveLayer { index, layer_id, removed: None };
+            action.redo(self);
+            self.history.push(action);
+        }
+    }
+
+    pub fn duplicate_layer(&mut self, layer_id: Id) {
+        if let Some(index) = self.layers_container.layers.iter().position(|l| l.id == layer_id) {
+            let mut copy = self.layers_container.layers[index].clone();
+            copy.id = new_rand_id();
+            copy.name = format!("{} copy", copy.name);
+            copy.texture.reset_textures();
+            self.add_layer(index, copy);
+        }
+    }
+
+    pub fn move_layer(&mut self, from: usize, to: usize) {
+        let len = self.layers_container.layers.len();
+        if from == to || from >= len || to >= len {
+            return;
+        }
+        let mut action = HistoryAction::MoveLayer { from, to };
+        action.redo(self);
+        self.history.push(action);
+    }
+
+    pub fn rename_layer(&mut self, layer_id: Id, name: String) {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|l| l.id == layer_id) && layer.name != name {
+            self.history.push(HistoryAction::RenameLayer { layer_id, before: layer.name.clone(), after: name.clone() });
+            layer.name = name;
+        }
+    }
+
+    pub fn can_merge_down(&self, layer_id: Id) -> bool {
+        self.layers_container.layers.iter().position(|l| l.id == layer_id).is_some_and(|index| index + 1 < self.layers_container.layers.len())
+    }
+
+    /// Blends the layer into the one below it with its own blend mode and opacity, then removes it.
+    pub fn merge_down(&mut self, layer_id: Id) {
+        if !self.can_merge_down(layer_id) {
+            return;
+        }
+        self.commit_stroke();
+        let layers = &self.layers_container.layers;
+        let index = layers.iter().position(|l| l.id == layer_id).unwrap_or_default();
+        let upper = &layers[index];
+        let lower = &layers[index + 1];
+        let opacity = upper.opacity.clamp(0., 1.);
+        let lock = self.palette_lock();
+        // Empty tiles of the upper layer can't change anything below them
+        let mut deltas = upper.texture.image_data.allocated_indices()
+            .filter_map(|idx| {
+                let lower_pixel = lower.texture.image_data.get(idx);
+                let blended = blend_pixel(lower_pixel, upper.texture.image_data.get(idx).gamma_multiply(opacity), upper.blend_mode);
+                let after = lock.as_ref().map_or(blended, |lock| lock.snap(blended));
+                (after != lower_pixel).then_some(PixelDelta { idx, before: lower_pixel, after })
+            })
+            .collect::<Vec<PixelDelta>>();
+        deltas.sort_by_key(|delta| delta.idx);
+        let lower_id = lower.id;
+
+        let mut action = HistoryAction::Batch(vec![
+            HistoryAction::Stroke { layer_id: lower_id, deltas },
+            HistoryAction::RemoveLayer { index, layer_id, removed: None }
+        ]);
+        action.redo(self);
+        self.current_layer = Some(lower_id);
+        self.history.push(action);
+    }
+
+    /// Replaces every layer with a single layer holding the visible composite.
+    pub fn flatten(&mut self, layer_size: [usize; 2]) {
+        if self.layers_container.layers.len() < 2 {
+            return;
+        }
+        self.commit_stroke();
+        let mut composite = composite_layers(&self.layers_container.visible_layers_bottom_up(), layer_size);
+        if let Some(lock) = self.palette_lock() {
+            lock.snap_image(&mut composite);
+        }
+        let flattened = Layer {
+            id: new_rand_id(),
+            name: "Flattened".to_string(),
+            is_visible: true,
+            blend_mode: BlendMode::Normal,
+            opacity: 1.,
+            texture: LayerTexture::from_tiled(composite)
+        };
+        let mut actions = self.layers_container.layers.iter()
+            .map(|layer| HistoryAction::RemoveLayer { index: 0, layer_id: layer.id, removed: None })
+            .collect::<Vec<HistoryAction>>();
+        actions.push(HistoryAction::AddLayer { index: 0, layer_id: flattened.id, previous_layer: self.current_layer, removed: Some(flattened) });
+        let mut action = HistoryAction::Batch(actions);
+        action.redo(self);
+        self.history.push(action);
+    }
+
+    fn ensure_current_layer(&mut self) {
+        let is_valid = self.current_layer.is_some_and(|id| self.layers_container.layers.iter().any(|l| l.id == id));
+        if !is_valid {
+            self.current_layer = self.layers_container.layers.first().map(|l| l.id);
+        }
+    }
+
+    pub fn toggle_layer_visibility(&mut self, layer_id: Id) {
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|l| l.id == layer_id) {
+            layer.is_visible = !layer.is_visible;
+            self.history.push(HistoryAction::ToggleVisibility { layer_id });
+        }
+    }
+
+    pub fn set_layer_blend_mode(&mut self, layer_id: Id, blend_mode: BlendMode) {
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|l| l.id == layer_id) && layer.blend_mode != blend_mode {
+            self.history.push(HistoryAction::SetBlendMode { layer_id, before: layer.blend_mode, after: blend_mode });
+            layer.blend_mode = blend_mode;
+        }
+    }
+
+    /// Sets the opacity of a layer. Edits with `continues_drag` join the undo step of the edit before them.
+    pub fn set_layer_opacity(&mut self, layer_id: Id, opacity: f32, continues_drag: bool) {
+        let opacity = opacity.clamp(0., 1.);
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|l| l.id == layer_id) && layer.opacity != opacity {
+            self.history.push_or_merge_opacity(layer_id, layer.opacity, opacity, continues_drag);
+            layer.opacity = opacity;
+        }
+    }
+
+    /// Swatches of the current palette.
+    pub fn color_palette(&self) -> &[PaintColor] {
+        self.palettes.iter().find(|p| p.id == self.current_palette).map(|p| p.colors.as_slice()).unwrap_or_default()
+    }
+
+    /// Changes the swatches of the current palette as one undoable step.
+    pub fn edit_palette(&mut self, edit: impl FnOnce(&mut Vec<PaintColor>)) {
+        let Some(palette) = self.palettes.iter_mut().find(|p| p.id == self.current_palette) else {
+            return;
+        };
+        let before = palette.colors.clone();
+        edit(&mut palette.colors);
+        if before == palette.colors {
+            return;
+        }
+        // The layers of an indexed document would have no color left to snap to
+        if palette.colors.is_empty() && self.indexed_palette == Some(palette.id) {
+            palette.colors = before;
+            return;
+        }
+        let palette_id = palette.id;
+        let after = palette.colors.clone();
+        let mut action = HistoryAction::Palette { palette_id, before: before.clone(), after: after.clone() };
+        // Editing the palette of an indexed document recolors the pixels of every swatch that changed
+        if self.indexed_palette == Some(palette_id) {
+            self.commit_stroke();
+            let swap = PaletteSwap::new(&before, &after);
+            let mut recolors = self.recolor_layers(|color| swap.recolor(color));
+            for recolor in recolors.iter_mut() {
+                recolor.redo(self);
+            }
+            if !recolors.is_empty() {
+                recolors.insert(0, action);
+                action = HistoryAction::Batch(recolors);
+            }
+        }
+        self.history.push(action);
+        self.sync_current_color();
+    }
+
+    /// Stroke actions turning every pixel into `recolor(pixel)`, one for each layer that changes.
+    fn recolor_layers(&self, recolor: impl Fn(Color32) -> Color32) -> Vec<HistoryAction> {
+        self.layers_container.layers.iter().filter_map(|layer| {
+            let image = &layer.texture.image_data;
+            let mut deltas = image.allocated_indices()
+                .filter_map(|idx| {
+                    let before = image.get(idx);
+                    let after = recolor(before);
+                    (after != before).then_some(PixelDelta { idx, before, after })
+                })
+                .collect::<Vec<PixelDelta>>();
+            deltas.sort_by_key(|delta| delta.idx);
+            (!deltas.is_empty()).then_some(HistoryAction::Stroke { layer_id: layer.id, deltas })
+        }).collect()
+    }
+
+    /// Colors the layers may hold while the document is indexed.
+    fn palette_lock(&self) -> Option<PaletteLock> {
+        let palette_id = self.indexed_palette?;
+        self.palettes.iter().find(|p| p.id == palette_id).map(|p| PaletteLock::new(&p.colors))
+    }
+
+    /// Locks the document to the palette with `palette_id`, snapping every pixel of every layer to its
+    /// nearest swatch, or goes back to full color with `None`. Either way it is one undo step. A palette
+    /// without swatches can't hold the lock.
+    pub fn set_indexed(&mut self, palette_id: Option<Id>) {
+        if self.indexed_palette == palette_id {
+            return;
+        }
+        if let Some(palette_id) = palette_id && !self.palettes.iter().any(|p| p.id == palette_id && !p.colors.is_empty()) {
+            return;
+        }
+        self.commit_stroke();
+        let mut action = HistoryAction::SetIndexed { before: self.indexed_palette, after: palette_id };
+        action.redo(self);
+        if let Some(lock) = self.palette_lock() {
+            let mut snaps = self.recolor_layers(|color| lock.snap(color));
+            for snap in snaps.iter_mut() {
+                snap.redo(self);
+            }
+            snaps.push(action);
+            action = HistoryAction::Batch(snaps);
+        }
+        self.history.push(action);
+    }
+
+    /// Restores the swatches of a palette and shows it, so an undone edit is never out of sight.
+    pub fn set_palette(&mut self, palette_id: Id, colors: Vec<PaintColor>) {
+        if let Some(palette) = self.palettes.iter_mut().find(|p| p.id == palette_id) {
+            palette.colors = colors;
+            self.current_palette = palette_id;
+            self.sync_current_color();
+        }
+    }
+
+    /// Adds, removes or renames palettes as one undoable step.
+    pub fn edit_palettes(&mut self, edit: impl FnOnce(&mut Vec<Palette>)) {
+        let before = self.palettes.clone();
+        edit(&mut self.palettes);
+        if before != self.palettes {
+            self.history.push(HistoryAction::Palettes { before, after: self.palettes.clone() });
+            self.select_palette(self.current_palette);
+        }
+    }
+
+    pub fn set_palettes(&mut self, palettes: Vec<Palette>) {
+        self.palettes = palettes;
+        self.select_palette(self.current_palette);
+    }
+
+    /// Shows the palette with `palette_id`, or the first one when it is gone.
+    pub fn select_palette(&mut self, palette_id: Id) {
+        self.current_palette = self.palettes.iter().find(|p| p.id == palette_id).or(self.palettes.first()).map(|p| p.id).unwrap_or(palette_id);
+        self.sync_current_color();
+    }
+
+    pub fn add_palette(&mut self, palette: Palette) {
+        let palette_id = palette.id;
+        self.edit_palettes(|palettes| palettes.push(palette));
+        self.select_palette(palette_id);
+    }
+
+    pub fn delete_palette(&mut self, palette_id: Id) {
+        // Swatches always need somewhere to go, so the last palette stays, as does the one an indexed
+        // document is locked to
+        if self.palettes.len() > 1 && self.indexed_palette != Some(palette_id) {
+            self.edit_palettes(|palettes| palettes.retain(|p| p.id != palette_id));
+        }
+    }
+
+    pub fn rename_palette(&mut self, palette_id: Id, name: String) {
+        let name = name.trim();
+        if name.is_empty() {
+            return;
+        }
+        self.edit_palettes(|palettes| {
+            if let Some(palette) = palettes.iter_mut().find(|p| p.id == palette_id) {
+                palette.name = name.to_string();
+            }
+        });
+    }
+
+    /// Appends the colors `samples` mostly consist of to the current palette, skipping ones it already has.
+    pub fn add_dominant_colors(&mut self, samples: &[[u8; 3]], settings: &QuantizeSettings) {
+        let colors = dominant_colors(samples, settings);
+        self.edit_palette(|palette| {
+            for color in colors {
+                if !palette.iter().any(|c| c.color == color) {
+                    palette.push(PaintColor { color, ..Default::default() });
+                }
+            }
+        });
+    }
+
+    /// Keeps the current color in step with its swatch after the swatch was changed from elsewhere.
+    /// Colors that aren't swatches of any palette, like eyedropper picks, are left alone.
+    fn sync_current_color(&mut self) {
+        if let Some(current_color) = &mut self.current_color
+            && let Some(found) = self.palettes.iter().flat_map(|p| p.colors.iter()).find(|c| c.id == current_color.id) {
+            *current_color = found.clone();
+        }
+    }
+
+    pub fn stroke_to(&mut self, pos: Pos2, settings: &StrokeSettings) {
+        if self.poses.last() == Some(&pos) {
+            return;
+        }
+        self.poses.push(pos);
+        let dabs = next_dabs(&self.poses, settings, self.current_brush.spacing_px(), &mut self.stroke_carry);
+        self.paint_dabs(&dabs);
+    }
+
+    pub fn end_stroke(&mut self, settings: &StrokeSettings) {
+        let dabs = final_dabs(&self.poses, settings, self.current_brush.spacing_px(), &mut self.stroke_carry);
+        self.paint_dabs(&dabs);
+        self.poses.clear();
+        self.commit_stroke();
+    }
+
+    fn paint_dabs(&mut self, dabs: &[Pos2]) {
+        let Some(layer_id) = self.current_layer else {
+            return;
+        };
+        // Switching layers mid-stroke starts a new stroke so undo restores the right layer
+        if self.history.pending_layer().is_some_and(|pending_id| pending_id != layer_id) {
+            self.commit_stroke();
+        }
+        let Some(tool) = &self.current_draw_tool else {
+            return;
+        };
+        let color = self.current_color.clone().unwrap_or_default().color;
+        let lock = self.palette_lock();
+        // Scatter spreads dabs across the direction the pointer last moved in
+        let direction = match self.poses.as_slice() {
+            [.., previous, last] => *last - *previous,
+            _ => Vec2::ZERO
+        };
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == layer_id) {
+            for dab in dabs {
+                let dab = self.current_brush.dab(*dab, direction);
+                let mut changes = layer.texture.paint_at(dab, tool.pencil, &self.current_brush, color, &mut self.stroke_coverage, &self.selection);
+                if let Some(lock) = &lock {
+                    let snapped = lock.snap_pixels(&mut layer.texture, changes.iter().map(|(idx, _)| *idx));
+                    changes.extend(snapped);
+                }
+                self.history.record_pixels(layer_id, changes);
+            }
+        }
+    }
+
+    /// Pixels a fill or the magic wand looks at: the current layer alone or every visible layer merged.
+    fn sample_image(&self, sample: FillSample, layer_size: [usize; 2]) -> Option<TiledImage> {
+        match sample {
+            FillSample::CurrentLayer => {
+                let layer_id = self.current_layer?;
+                self.layers_container.layers.iter().find(|layer| layer.id == layer_id).map(|layer| layer.texture.image_data.clone())
+            },
+            FillSample::Merged => Some(composite_layers(&self.layers_container.visible_layers_bottom_up(), layer_size))
+        }
+    }
+
+    /// Selects the pixels matching the one at `pos`, as a flood fill from there would cover them.
+    pub fn magic_wand(&mut self, pos: Pos2, settings: &SelectionSettings, mode: SelectionMode, layer_size: [usize; 2]) {
+        if pos.x < 0. || pos.y < 0. || pos.x >= layer_size[0] as f32 || pos.y >= layer_size[1] as f32 {
+            return;
+        }
+        let Some(source) = self.sample_image(settings.wand.sample, layer_size) else {
+            return;
+        };
+        let coverage = fill_coverage(&source, [pos.x as usize, pos.y as usize], &settings.wand);
+        self.selection.combine(feather(coverage, layer_size, settings.feather), mode);
+    }
+
+    /// Selects every pixel close enough to `color`, connected or not.
+    pub fn select_color_range(&mut self, color: Color32, settings: &SelectionSettings, mode: SelectionMode, layer_size: [usize; 2]) {
+        let Some(source) = self.sample_image(settings.wand.sample, layer_size) else {
+            return;
+        };
+        let coverage = color_range_coverage(&source, color, &settings.wand);
+        self.selection.combine(feather(coverage, layer_size, settings.feather), mode);
+    }
+
+    /// Makes the color under `pos` the current color, picking the palette swatch that holds it if there is
+    /// one. With `add_to_palette` a color the palette lacks becomes a new swatch. Returns the color, or
+    /// `None` when there is only transparency to pick.
+    pub fn pick_color(&mut self, pos: Pos2, settings: &EyedropperSettings, add_to_palette: bool, layer_size: [usize; 2]) -> Option<Color32> {
+        if pos.x < 0. || pos.y < 0. || pos.x >= layer_size[0] as f32 || pos.y >= layer_size[1] as f32 {
+            return None;
+        }
+        let pos = [pos.x as usize, pos.y as usize];
+        let color = match settings.sample {
+            FillSample::CurrentLayer => {
+                let layer_id = self.current_layer?;
+                let image = &self.layers_container.layers.iter().find(|layer| layer.id == layer_id)?.texture.image_data;
+                sample_color(layer_size, pos, settings.size, |idx| image.get(idx))
+            },
+            // Dragging samples every frame, so only the pixels under the pointer are composited
+            FillSample::Merged => {
+                let layers = self.layers_container.visible_layers_bottom_up();
+                sample_color(layer_size, pos, settings.size, |idx| composite_pixel(&layers, idx))
+            }
+        };
+        if color.a() == 0 {
+            return None;
+        }
+        if let Some(swatch) = self.color_palette().iter().find(|swatch| swatch.color == color) {
+            self.current_color = Some(swatch.clone());
+        } else {
+            let paint_color = PaintColor { color, ..Default::default() };
+            if add_to_palette {
+                self.edit_palette(|palette| palette.push(paint_color.clone()));
+            }
+            self.current_color = Some(paint_color);
+        }
+        Some(color)
+    }
+
+    /// Flood fills the current layer with the current color, starting at `pos` in layer coordinates.
+    pub fn fill_at(&mut self, pos: Pos2, settings: &FillSettings, layer_size: [usize; 2]) {
+        let Some(layer_id) = self.current_layer else {
+            return;
+        };
+        if pos.x < 0. || pos.y < 0. || pos.x >= layer_size[0] as f32 || pos.y >= layer_size[1] as f32 {
+            return;
+        }
+        self.commit_stroke();
+        let Some(source) = self.sample_image(settings.sample, layer_size) else {
+            return;
+        };
+        let coverage = fill_coverage(&source, [pos.x as usize, pos.y as usize], settings);
+        let color = self.current_color.clone().unwrap_or_default().color;
+        let lock = self.palette_lock();
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == layer_id) {
+            let mut changes = layer.texture.fill(&coverage, color, &self.selection);
+            if let Some(lock) = &lock {
+                let snapped = lock.snap_pixels(&mut layer.texture, changes.iter().map(|(idx, _)| *idx));
+                changes.extend(snapped);
+            }
+            self.history.record_pixels(layer_id, changes);
+        }
+        self.commit_stroke();
+    }
+
+    /// Draws the shape started at `shape_anchors` and ending at `end` onto the current layer.
+    pub fn commit_shape(&mut self, pencil: Pencil, end: Pos2, settings: &ShapeSettings, layer_size: [usize; 2]) {
+        let anchors = std::mem::take(&mut self.shape_anchors);
+        let (Some(layer_id), false) = (self.current_layer, anchors.is_empty()) else {
+            return;
+        };
+        self.commit_stroke();
+        let (path, closed) = shape_path(pencil, &anchors, end);
+        let coverage = shape_coverage(&path, closed, settings, layer_size);
+        let color = self.current_color.clone().unwrap_or_default().color;
+        let lock = self.palette_lock();
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == layer_id) {
+            let mut changes = layer.texture.fill(&coverage, color, &self.selection);
+            if let Some(lock) = &lock {
+                let snapped = lock.snap_pixels(&mut layer.texture, changes.iter().map(|(idx, _)| *idx));
+                changes.extend(snapped);
+            }
+            self.history.record_pixels(layer_id, changes);
+        }
+        self.commit_stroke();
+    }
+
+    /// Commits the shape or selection outline being drawn with `pencil`.
+    pub fn commit_outline(&mut self, pencil: Pencil, end: Pos2, shape_settings: &ShapeSettings, selection_settings: &SelectionSettings, mode: SelectionMode, layer_size: [usize; 2]) {
+        if pencil.is_selection() {
+            self.commit_selection(pencil, end, selection_settings, mode, layer_size);
+        } else {
+            self.commit_shape(pencil, end, shape_settings, layer_size);
+        }
+    }
+
+    /// Merges the outline started at `shape_anchors` and ending at `end` into the selection.
+    pub fn commit_selection(&mut self, pencil: Pencil, end: Pos2, settings: &SelectionSettings, mode: SelectionMode, layer_size: [usize; 2]) {
+        let anchors = std::mem::take(&mut self.shape_anchors);
+        if anchors.is_empty() {
+            return;
+        }
+        let (path, _) = shape_path(pencil.outline_shape(), &anchors, end);
+        if path.len() < 3 {
+            return;
+        }
+        let shape_settings = ShapeSettings { filled: true, ..Default::default() };
+        let coverage = shape_coverage(&path, true, &shape_settings, layer_size);
+        self.selection.combine(feather(coverage, layer_size, settings.feather), mode);
+    }
+
+    /// Lifts the selected pixels of the current layer, or the selection alone, so they can be moved and
+    /// transformed. Returns whether a transform is now in progress.
+    pub fn begin_transform(&mut self, target: TransformTarget) -> bool {
+        if self.transform.is_some() {
+            return true;
+        }
+        let Some(layer_id) = self.current_layer else {
+            return false;
+        };
+        self.commit_stroke();
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == layer_id) {
+            self.transform = FloatingTransform::lift(layer_id, &mut layer.texture, &self.selection, target);
+        }
+        self.transform.is_some()
+    }
+
+    /// Resamples the transformed content into place as one undo step, taking the selection along.
+    pub fn apply_transform(&mut self, interpolation: Interpolation, layer_size: [usize; 2]) {
+        let Some(transform) = self.transform.take() else {
+            return;
+        };
+        self.commit_stroke();
+        if let Some(mask) = transform.transformed_mask(interpolation, layer_size) {
+            self.selection.combine(mask, SelectionMode::Replace);
+        }
+        let lock = self.palette_lock();
+        if let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == transform.layer_id) {
+            let mut changes = transform.apply(&mut layer.texture, interpolation);
+            if let Some(lock) = &lock {
+                let snapped = lock.snap_pixels(&mut layer.texture, changes.iter().map(|(idx, _)| *idx));
+                changes.extend(snapped);
+            }
+            self.history.record_pixels(transform.layer_id, changes);
+        }
+        self.commit_stroke();
+    }
+
+    pub fn cancel_transform(&mut self) {
+        if let Some(transform) = self.transform.take()
+            && let Some(layer) = self.layers_container.layers.iter_mut().find(|layer| layer.id == transform.layer_id) {
+            transform.cancel(&mut layer.texture);
+        }
+    }
+
+    pub fn commit_stroke(&mut self) {
+        self.stroke_coverage.clear();
+        if let Some(action) = self.history.finish_stroke(&self.layers_container.layers) {
+            self.history.push(action);
+        }
+    }
+
+    pub fn undo(&mut self) {
+        // The lifted pixels aren't in the history yet, so undo just drops the transform
+        if self.transform.is_some() {
+            self.cancel_transform();
+            return;
+        }
+        self.commit_stroke();
+        if let Some(mut action) = self.history.pop_undo() {
+            action.undo(self);
+            self.ensure_current_layer();
+            self.history.push_undone(action);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if self.history.has_pending_stroke() || self.transform.is_some() {
+            return;
+        }
+        if let Some(mut action) = self.history.pop_redo() {
+            action.redo(self);
+            self.ensure_current_layer();
+            self.history.push_redone(action);
         }
     }
         
@@ -116,6 +769,31 @@ impl AppState {
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // ctx.request_repaint();
+        let redo_shortcut = KeyboardShortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::Z);
+        let undo_shortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+        let select_all_shortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::A);
+        let deselect_shortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::D);
+        let invert_selection_shortcut = KeyboardShortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::I);
+        // Switching away from the move tool applies whatever it was transforming
+        if self.app_state.current_draw_tool.as_ref().is_none_or(|tool| tool.pencil != Pencil::Move) {
+            let layer_size = [self.app_settings.layer_size.x as usize, self.app_settings.layer_size.y as usize];
+            self.app_state.apply_transform(self.app_settings.transform_settings.interpolation, layer_size);
+        }
+        // Leave Ctrl+Z to text fields while one is being edited
+        if !ctx.wants_keyboard_input() {
+            if ctx.input_mut(|i| i.consume_shortcut(&redo_shortcut)) {
+                self.app_state.redo();
+            } else if ctx.input_mut(|i| i.consume_shortcut(&undo_shortcut)) {
+                self.app_state.undo();
+            }
+            if ctx.input_mut(|i| i.consume_shortcut(&invert_selection_shortcut)) {
+                self.app_state.selection.invert();
+            } else if ctx.input_mut(|i| i.consume_shortcut(&select_all_shortcut)) {
+                self.app_state.selection.select_all();
+            } else if ctx.input_mut(|i| i.consume_shortcut(&deselect_shortcut)) {
+                self.app_state.selection.deselect();
+            }
+        }
 
         egui::CentralPanel::default().show(ctx,  |ui| {
             ui.horizontal(|ui|{
@@ -138,6 +816,8 @@ impl eframe::App for App {
             if self.app_settings.import_image_widget.is_open {
                 ImportImageWidget::add(self, ui);
             }
+            ExportImageWidget::add(self, ui);
+            NotificationWidget::add(self, ui);
         });  
     }
 }
@@ -158,7 +838,12 @@ impl App {
             .or_default()
             .insert(0, "roboto".to_owned());
         cc.egui_ctx.set_fonts(fonts);
-        let app_settings = AppSettings::default();
+        let mut app_settings = AppSettings::default();
+        match app_settings.brush_presets.load_user_presets() {
+            Ok(tip_errors) if !tip_errors.is_empty() => app_settings.notification_widget.error(tip_errors.join("\n")),
+            Ok(_) => {},
+            Err(error) => app_settings.notification_widget.error(format!("Failed to load brush presets: {error}"))
+        }
         Self {
 
             app_state: AppState::from_settings(app_settings.clone()),
@@ -167,10 +852,18 @@ impl App {
     }
 
     pub fn re_new(&mut self) {
-        let mut new_settings = AppSettings::default();
-        new_settings.layer_size = Vec2::new(self.app_settings.new_paint_settings.width as f32, self.app_settings.new_paint_settings.height as f32);
+        let new_settings = AppSettings {
+            layer_size: Vec2::new(self.app_settings.new_paint_settings.width as f32, self.app_settings.new_paint_settings.height as f32),
+            brush_presets: self.app_settings.brush_presets.clone(),
+            ..Default::default()
+        };
         self.app_settings = new_settings.clone();
-        self.app_state = AppState::from_settings(new_settings);
+        let mut app_state = AppState::from_settings(new_settings);
+        // Palettes belong to the user rather than the painting, so a new painting keeps them
+        app_state.palettes = std::mem::take(&mut self.app_state.palettes);
+        app_state.current_palette = self.app_state.current_palette;
+        app_state.current_color = self.app_state.current_color.take();
+        self.app_state = app_state;
     
     }
 
@@ -182,29 +875,265 @@ impl App {
         
     }
 
-    pub fn save_to_image(&self) -> Result<(), std::io::Error> {
-        let path = if let Some(base_dir) = &self.app_settings.base_dir {
-            base_dir.join("final_output.png")
-        } else {
-            let c_drive = PathBuf::from(r"C:\");
-            c_drive.join("final_output.png")
-            
+    /// Folder exports are written to: the chosen base directory, or the working directory.
+    pub fn export_directory(&self) -> PathBuf {
+        self.app_settings.base_dir.clone()
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// Files an export named `file_name` would write into `folder`, each paired with the layer it holds,
+    /// or `None` for the flattened visible layers. Empty when there is no name to save under.
+    pub fn export_targets(&self, folder: &Path, file_name: &str, options: &ExportOptions) -> Vec<(PathBuf, Option<Id>)> {
+        let stem = ExportFormat::file_stem(file_name);
+        let extension = options.format.extension();
+        if stem.is_empty() {
+            return Vec::new();
+        }
+        if !options.per_layer {
+            return vec![(folder.join(format!("{stem}.{extension}")), None)];
+        }
+        // Layers may share a name, so later ones get a number instead of overwriting earlier ones
+        let mut used_stems = HashSet::new();
+        self.app_state.layers_container.layers.iter().rev().map(|layer| {
+            let layer_stem = layer_file_stem(stem, &layer.name);
+            let mut unique_stem = layer_stem.clone();
+            let mut number = 2;
+            while !used_stems.insert(unique_stem.to_lowercase()) {
+                unique_stem = format!("{layer_stem} {number}");
+                number += 1;
+            }
+            (folder.join(format!("{unique_stem}.{extension}")), Some(layer.id))
+        }).collect()
+    }
+
+    /// Writes every target from `export_targets`, stopping at the first failure.
+    pub fn export_image(&mut self, targets: &[(PathBuf, Option<Id>)], options: &ExportOptions) -> Result<(), std::io::Error> {
+        self.app_state.commit_stroke();
+        let layer_size = [self.app_settings.layer_size.x as usize, self.app_settings.layer_size.y as usize];
+        self.app_state.apply_transform(self.app_settings.transform_settings.interpolation, layer_size);
+        let selection = Some(&self.app_state.selection).filter(|selection| options.selection_only && selection.is_active());
+        let options = match selection.and_then(|selection| selection.bounds()) {
+            Some(bounds) => ExportOptions { region: Some(bounds), ..options.clone() },
+            None => options.clone()
+        };
+        let indexed_lock = (options.indexed && options.format == ExportFormat::Png)
+            .then(|| self.app_state.palette_lock().unwrap_or_else(|| PaletteLock::new(self.app_state.color_palette())));
+        for (path, layer_id) in targets {
+            let layers = match layer_id {
+                Some(id) => self.app_state.layers_container.layers.iter().filter(|layer| layer.id == *id).collect(),
+                None => self.app_state.layers_container.visible_layers_bottom_up()
+            };
+            let mut composite = composite_layers(&layers, layer_size).to_color_image();
+            if let Some(selection) = selection {
+                for (idx, pixel) in composite.pixels.iter_mut().enumerate() {
+                    *pixel = pixel.gamma_multiply(selection.coverage(idx));
+                }
+            }
+            let image = options.apply(&composite)?;
+            match &indexed_lock {
+                Some(lock) => write_indexed_png(&image, path, lock)?,
+                None => write_image(&image, path, options.format, options.jpeg_quality)?
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_project(&mut self) -> Result<(), std::io::Error> {
+        match self.app_settings.project_path.clone() {
+            Some(path) => self.write_project(&path),
+            None => self.save_project_as()
+        }
+    }
+
+    pub fn save_project_as(&mut self) -> Result<(), std::io::Error> {
+        let mut dialog = FileDialog::new().add_filter("Painting project", &[PROJECT_EXTENSION]);
+        if let Some(base_dir) = &self.app_settings.base_dir {
+            dialog = dialog.set_directory(base_dir);
+        }
+        if let Some(mut path) = dialog.save_file() {
+            if path.extension().is_none() {
+                path.set_extension(PROJECT_EXTENSION);
+            }
+            self.write_project(&path)?;
+            self.app_settings.project_path = Some(path);
+        }
+        Ok(())
+    }
+
+    pub fn open_project(&mut self) -> Result<(), std::io::Error> {
+        let file_path: Option<PathBuf> = FileDialog::new()
+            .add_filter("Painting project", &[PROJECT_EXTENSION])
+            .pick_file();
+        if let Some(path) = file_path {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let document = ProjectDocument::read_from(&mut reader)?;
+            self.apply_project(document);
+            self.app_settings.project_path = Some(path);
+        }
+        Ok(())
+    }
+
+    pub fn import_open_raster(&mut self) -> Result<(), std::io::Error> {
+        let file_path: Option<PathBuf> = FileDialog::new()
+            .add_filter("OpenRaster", &[OPEN_RASTER_EXTENSION])
+            .pick_file();
+        if let Some(path) = file_path {
+            let open_raster = read_open_raster(BufReader::new(File::open(&path)?))?;
+            let state = &self.app_state;
+            let document = ProjectDocument {
+                layer_size: open_raster.layer_size,
+                layers: open_raster.layers,
+                transform: Transform::default(),
+                current_layer: Some(0),
+                palettes: state.palettes.clone(),
+                current_palette: state.palettes.iter().position(|p| p.id == state.current_palette),
+                current_color: state.current_color.as_ref().and_then(|c| state.color_palette().iter().position(|p| p.id == c.id)),
+                // A new document from another program starts in full color
+                indexed_palette: None,
+                current_stroke_width: state.current_brush.size,
+                current_pencil: state.current_draw_tool.as_ref().map(|tool| tool.pencil).unwrap_or(DrawTool::default().pencil)
+            };
+            self.apply_project(document);
+        }
+        Ok(())
+    }
+
+    pub fn export_open_raster(&mut self) -> Result<(), std::io::Error> {
+        let mut dialog = FileDialog::new().add_filter("OpenRaster", &[OPEN_RASTER_EXTENSION]);
+        if let Some(base_dir) = &self.app_settings.base_dir {
+            dialog = dialog.set_directory(base_dir);
+        }
+        if let Some(mut path) = dialog.save_file() {
+            if path.extension().is_none() {
+                path.set_extension(OPEN_RASTER_EXTENSION);
+            }
+            self.app_state.commit_stroke();
+            let layer_size = [self.app_settings.layer_size.x as usize, self.app_settings.layer_size.y as usize];
+            self.app_state.apply_transform(self.app_settings.transform_settings.interpolation, layer_size);
+            let writer = BufWriter::new(File::create(&path)?);
+            write_open_raster(writer, &self.app_state.layers_container.layers, self.app_settings.layer_size)?;
+        }
+        Ok(())
+    }
+
+    fn write_project(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        self.app_state.commit_stroke();
+        let layer_size = [self.app_settings.layer_size.x as usize, self.app_settings.layer_size.y as usize];
+        self.app_state.apply_transform(self.app_settings.transform_settings.interpolation, layer_size);
+        let state = &self.app_state;
+        let document = ProjectDocument {
+            layer_size: self.app_settings.layer_size,
+            layers: state.layers_container.layers.clone(),
+            transform: state.layers_container.transform.clone(),
+            current_layer: state.current_layer.and_then(|id| state.layers_container.layers.iter().position(|l| l.id == id)),
+            palettes: state.palettes.clone(),
+            current_palette: state.palettes.iter().position(|p| p.id == state.current_palette),
+            current_color: state.current_color.as_ref().and_then(|c| state.color_palette().iter().position(|p| p.id == c.id)),
+            indexed_palette: state.indexed_palette.and_then(|id| state.palettes.iter().position(|p| p.id == id)),
+            current_stroke_width: state.current_brush.size,
+            current_pencil: state.current_draw_tool.as_ref().map(|tool| tool.pencil).unwrap_or(DrawTool::default().pencil)
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        document.write_to(&mut writer)
+    }
+
+    fn apply_project(&mut self, document: ProjectDocument) {
+        let mut new_settings = AppSettings {
+            layer_size: document.layer_size,
+            layer_rect: egui::Rect::from_center_size(Pos2::ZERO, document.layer_size),
+            base_dir: self.app_settings.base_dir.clone(),
+            brush_presets: self.app_settings.brush_presets.clone(),
+            new_paint_settings: NewPaintSetting {
+                width: document.layer_size.x as usize,
+                height: document.layer_size.y as usize,
+                is_open: false
+            },
+            ..Default::default()
         };
-        // let path = base_dir.join("final_output.png");
-        let color_vec = &self.app_state.layers_container.layers.clone().iter().rev().map(|layer| layer.texture.image_data.clone()).collect::<Vec<ColorImage>>();
-        let composites = composite_layers(&color_vec);
-        let [width, height] = composites.size;
 
-        let mut buffer  = ImageBuffer::<image::Rgba<u8>, Vec<u8>>::new(width as u32, height as u32);
+        let mut app_state = AppState::from_settings(new_settings.clone());
+        let current_tool = new_settings.draw_tools.tools.iter().find(|tool| tool.pencil == document.current_pencil).cloned();
+        if let Some(tool) = &current_tool {
+            new_settings.pencil_cursor.set_pencil(tool.pencil);
+        }
+        new_settings.pencil_cursor.set_radius(document.current_stroke_width);
+        app_state.current_draw_tool = current_tool;
+        app_state.current_brush = Brush { size: document.current_stroke_width, ..self.app_state.current_brush.clone() };
+        app_state.current_layer = document.current_layer.or(Some(0)).map(|i| document.layers[i].id);
+        let current_palette = &document.palettes[document.current_palette.unwrap_or(0)];
+        app_state.current_palette = current_palette.id;
+        app_state.current_color = document.current_color.map(|i| current_palette.colors[i].clone());
+        // A file locked to a palette without swatches opens in full color
+        app_state.indexed_palette = document.indexed_palette.filter(|i| !document.palettes[*i].colors.is_empty()).map(|i| document.palettes[i].id);
+        app_state.palettes = document.palettes;
+        app_state.layers_container = LayersContainer {
+            layers: document.layers,
+            transform: document.transform,
+            ..Default::default()
+        };
+        self.app_settings = new_settings;
+        self.app_state = app_state;
+    }
 
-        for (i, pixel) in composites.pixels.iter().enumerate() {
-            let x = (i % width) as u32;
-            let y = (i / width) as u32;
-            let rgba = pixel.to_array(); // [r, g, b, a]
-            buffer.put_pixel(x, y, image::Rgba::from(rgba));
+    pub fn import_palette(&mut self) -> Result<(), std::io::Error> {
+        let mut dialog = FileDialog::new();
+        for format in PaletteFormat::ALL {
+            dialog = dialog.add_filter(format.to_string(), &[format.extension()]);
         }
-        buffer.save(path).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        if let Some(path) = dialog.pick_file() {
+            let palette = read_palette(&path)?;
+            self.app_state.add_palette(palette);
+        }
+        Ok(())
     }
+
+    pub fn export_palette(&mut self) -> Result<(), std::io::Error> {
+        let Some(palette) = self.app_state.palettes.iter().find(|p| p.id == self.app_state.current_palette) else {
+            return Ok(());
+        };
+        let mut dialog = FileDialog::new().set_file_name(format!("{}.{}", palette.name, PaletteFormat::default().extension()));
+        for format in PaletteFormat::ALL {
+            dialog = dialog.add_filter(format.to_string(), &[format.extension()]);
+        }
+        if let Some(base_dir) = &self.app_settings.base_dir {
+            dialog = dialog.set_directory(base_dir);
+        }
+        if let Some(mut path) = dialog.save_file() {
+            if PaletteFormat::from_path(&path).is_none() {
+                path.set_extension(PaletteFormat::default().extension());
+            }
+            write_palette(palette, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the dominant colors of the image waiting in the import dialog to the current palette.
+    pub fn generate_palette_from_import(&mut self) {
+        if let Some(texture) = &self.app_settings.import_image_widget.texture {
+            let samples = image_samples(&texture.dyn_image);
+            self.app_state.add_dominant_colors(&samples, &self.app_settings.quantize_settings);
+        }
+    }
+
+    /// Adds the dominant colors of the visible layers to the current palette.
+    pub fn generate_palette_from_canvas(&mut self) {
+        self.app_state.commit_stroke();
+        let layer_size = [self.app_settings.layer_size.x as usize, self.app_settings.layer_size.y as usize];
+        let composite = composite_layers(&self.app_state.layers_container.visible_layers_bottom_up(), layer_size);
+        self.app_state.add_dominant_colors(&tiled_image_samples(&composite), &self.app_settings.quantize_settings);
+    }
+
+    pub fn load_brush_tip(&mut self) -> Result<(), std::io::Error> {
+        let file_path: Option<PathBuf> = FileDialog::new()
+            .add_filter("Image", &["png", "jpeg", "jpg"])
+            .pick_file();
+        if let Some(path) = file_path {
+            self.app_state.current_brush.tip = Some(BrushTip::load(&path)?);
+        }
+        Ok(())
+    }
+
     pub fn load_image(&mut self, ctx: &egui::Context) {
         let file_path: Option<PathBuf> = FileDialog::new()
             .add_filter("Image", &["png", "jpeg", "jpg"])
@@ -244,4 +1173,57 @@ impl App {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::components::utils::layer::{Layer, LayerTexture};
+    use crate::app::components::utils::new_rand_id;
+
+    fn app_with_layers(names: &[&str]) -> App {
+        let app_settings = AppSettings::default();
+        let mut app_state = AppState::from_settings(app_settings.clone());
+        // Top first, as the layers panel lists them
+        app_state.layers_container.layers = names.iter().map(|name| Layer {
+            id: new_rand_id(),
+            name: name.to_string(),
+            is_visible: true,
+            blend_mode: BlendMode::Normal,
+            opacity: 1.,
+            texture: LayerTexture::new(4, 4)
+        }).collect();
+        App { app_settings, app_state }
+    }
+
+    fn file_names(targets: &[(PathBuf, Option<Id>)]) -> Vec<String> {
+        targets.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+    }
 
+    #[test]
+    fn export_targets_drop_a_typed_extension() {
+        let app = app_with_layers(&["Layer 1"]);
+        let options = ExportOptions { format: ExportFormat::Jpeg, ..Default::default() };
+        let targets = app.export_targets(Path::new("out"), "Poster.JPEG ", &options);
+        assert_eq!(targets, vec![(Path::new("out").join("Poster.jpg"), None)]);
+        // Extensions of other kinds are part of the name
+        assert_eq!(file_names(&app.export_targets(Path::new("out"), "v1.2", &options)), vec!["v1.2.jpg"]);
+    }
+
+    #[test]
+    fn export_targets_need_a_name() {
+        let app = app_with_layers(&["Layer 1"]);
+        for name in ["", "   ", ".png"] {
+            assert!(app.export_targets(Path::new("out"), name, &ExportOptions { per_layer: true, ..Default::default() }).is_empty(), "{name:?}");
+            assert!(app.export_targets(Path::new("out"), name, &ExportOptions::default()).is_empty(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn export_targets_number_layers_that_share_a_name() {
+        let app = app_with_layers(&["sky", "Ink", "Sky", "SKY"]);
+        let targets = app.export_targets(Path::new("out"), "art", &ExportOptions { per_layer: true, ..Default::default() });
+        // Bottom layer first, so numbering follows the stack upwards
+        assert_eq!(file_names(&targets), vec!["art_SKY.png", "art_Sky 2.png", "art_Ink.png", "art_sky 3.png"]);
+        let layer_ids = app.app_state.layers_container.layers.iter().rev().map(|layer| Some(layer.id)).collect::<Vec<_>>();
+        assert_eq!(targets.iter().map(|(_, id)| *id).collect::<Vec<_>>(), layer_ids);
+    }
+}